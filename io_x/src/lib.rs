@@ -1,7 +1,16 @@
-use std::{fmt::Debug, pin::Pin, task::Context, task::Poll};
+use std::{
+    fmt::Debug,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    task::Context,
+    task::Poll,
+};
 
 use pin_project_lite::pin_project;
-use prometheus_client::metrics::{counter::Counter, family::Family};
+use prometheus_client::metrics::counter::Counter;
 use std::io;
 use std::time::Duration;
 use tokio::io::AsyncRead;
@@ -10,7 +19,7 @@ use tokio::io::AsyncWrite;
 use futures_util::Future;
 use tokio::time::{sleep, Instant, Sleep};
 
-use prom_label::Label;
+use prom_label::{BoundedFamily, Label};
 
 pin_project! {
     /// enhance inner tcp stream with prometheus counter
@@ -23,7 +32,7 @@ pin_project! {
     {
         #[pin]
         inner: T,
-        traffic_counter: Family<R, Counter>,
+        traffic_counter: BoundedFamily<R, Counter>,
         label: R,
     }
 }
@@ -33,7 +42,7 @@ where
     T: AsyncWrite + AsyncRead,
     R: Label,
 {
-    pub fn new(inner: T, traffic_counter: Family<R, Counter>, label: R) -> Self {
+    pub fn new(inner: T, traffic_counter: BoundedFamily<R, Counter>, label: R) -> Self {
         Self {
             inner,
             traffic_counter,
@@ -55,7 +64,7 @@ where
         let label = pro.label;
         match pro.inner.poll_read(cx, buf) {
             Poll::Ready(Ok(_)) => {
-                traffic_counter.get_or_create(label).inc_by(buf.filled().len() as u64);
+                traffic_counter.with_series(label, |counter| counter.inc_by(buf.filled().len() as u64));
                 Poll::Ready(Ok(()))
             }
             other => other,
@@ -75,7 +84,7 @@ where
         match pro.inner.poll_write(cx, buf) {
             Poll::Ready(result) => {
                 if let Ok(size) = result {
-                    traffic_counter.get_or_create(label).inc_by(size as u64);
+                    traffic_counter.with_series(label, |counter| counter.inc_by(size as u64));
                 }
                 Poll::Ready(result)
             }
@@ -100,7 +109,8 @@ where
     ) -> Poll<Result<usize, std::io::Error>> {
         let pro = self.project();
         let count = bufs.iter().map(|buf| buf.len()).sum::<usize>() as u64;
-        pro.traffic_counter.get_or_create(pro.label).inc_by(count);
+        pro.traffic_counter
+            .with_series(pro.label, |counter| counter.inc_by(count));
         pro.inner.poll_write_vectored(cx, bufs)
     }
 }
@@ -223,3 +233,128 @@ where
         write_poll
     }
 }
+
+/// 连接的读写统计，可以在连接关闭后读取
+#[derive(Debug)]
+pub struct IoStats {
+    created: Instant,
+    read: AtomicU64,
+    written: AtomicU64,
+    first_read: OnceLock<Instant>,
+}
+
+impl IoStats {
+    /// 从inner读到的字节数
+    pub fn read_bytes(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    /// 写入inner的字节数
+    pub fn written_bytes(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// 从创建到第一次读到数据的耗时
+    pub fn time_to_first_read(&self) -> Option<Duration> {
+        self.first_read.get().map(|first_read| *first_read - self.created)
+    }
+
+    /// 从创建到现在的耗时
+    pub fn elapsed(&self) -> Duration {
+        self.created.elapsed()
+    }
+}
+
+pin_project! {
+    /// enhance inner stream with byte counters and time to first byte
+    #[derive(Debug)]
+    pub struct StatsIO<T>
+    where
+    T: AsyncWrite,
+    T: AsyncRead,
+    {
+        #[pin]
+        inner: T,
+        stats: Arc<IoStats>,
+    }
+}
+
+impl<T> StatsIO<T>
+where
+    T: AsyncWrite + AsyncRead,
+{
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            stats: Arc::new(IoStats {
+                created: Instant::now(),
+                read: AtomicU64::new(0),
+                written: AtomicU64::new(0),
+                first_read: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// 统计数据的句柄，inner被move走之后仍然可以读取
+    pub fn stats(&self) -> Arc<IoStats> {
+        self.stats.clone()
+    }
+}
+
+impl<T> AsyncRead for StatsIO<T>
+where
+    T: AsyncWrite + AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let pro = self.project();
+        let before = buf.filled().len();
+        let read_poll = pro.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(_)) = read_poll {
+            let size = (buf.filled().len() - before) as u64;
+            if size > 0 {
+                pro.stats.first_read.get_or_init(Instant::now);
+                pro.stats.read.fetch_add(size, Ordering::Relaxed);
+            }
+        }
+        read_poll
+    }
+}
+
+impl<T> AsyncWrite for StatsIO<T>
+where
+    T: AsyncWrite + AsyncRead,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        let pro = self.project();
+        let write_poll = pro.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = write_poll {
+            pro.stats.written.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        write_poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let pro = self.project();
+        let write_poll = pro.inner.poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(size)) = write_poll {
+            pro.stats.written.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        write_poll
+    }
+}
@@ -1,11 +1,16 @@
 use std::{fs, io, path};
 
 use flexi_logger::{
-    Cleanup, Criterion, DeferredNow, Duplicate, FileSpec, FlexiLoggerError, Logger, LoggerHandle, Naming,
+    writers::FileLogWriter, Cleanup, Criterion, DeferredNow, Duplicate, FileSpec, FlexiLoggerError, Logger,
+    LoggerHandle, Naming,
 };
 use log::{info, Record};
 
-pub fn init_log(log_dir: &str, log_file: &str) -> Result<LoggerHandle, FlexiLoggerError> {
+/// 访问日志的target，使用 `info!(target: ACCESS_LOG_TARGET, ...)` 输出的日志只会写入access log文件
+pub const ACCESS_LOG_TARGET: &str = "{access}";
+const ACCESS_LOG_WRITER: &str = "access";
+
+pub fn init_log(log_dir: &str, log_file: &str, access_log_file: &str) -> Result<LoggerHandle, FlexiLoggerError> {
     // 转换成绝对路径
     let log_dir_path = path::absolute(log_dir)?;
     if !log_dir_path.exists() {
@@ -20,6 +25,21 @@ pub fn init_log(log_dir: &str, log_file: &str) -> Result<LoggerHandle, FlexiLogg
     } else {
         Logger::try_with_env_or_str("info,rustls=error")?
     };
+    // 访问日志单独输出到一个文件，每行是一条已经格式化好的记录（json或combined）
+    let access_log_writer = FileLogWriter::builder(
+        FileSpec::default()
+            .directory(log_dir)
+            .basename(access_log_file)
+            .suffix(""),
+    )
+    .format(access_format)
+    .rotate(
+        Criterion::Size(100_000_000), // 每 100MB 切割
+        Naming::Timestamps,
+        Cleanup::KeepLogFiles(3),
+    )
+    .append()
+    .try_build()?;
     let log = logger
         .log_to_file(FileSpec::default().directory(log_dir).basename(log_file).suffix(""))
         .duplicate_to_stdout(Duplicate::All)
@@ -31,12 +51,14 @@ pub fn init_log(log_dir: &str, log_file: &str) -> Result<LoggerHandle, FlexiLogg
         .append()
         .format(my_format)
         .create_symlink(format!("{}/{}", log_dir, log_file))
+        .add_writer(ACCESS_LOG_WRITER, Box::new(access_log_writer))
         .start();
     let symlink_path = log_dir_path.join(log_file);
     let symlink_path = symlink_path
         .to_str()
         .ok_or(io::Error::new(io::ErrorKind::InvalidData, "cannot parse"))?;
     info!("log is output to {}", symlink_path);
+    info!("access log is output to {}", log_dir_path.join(access_log_file).display());
     log
 }
 
@@ -52,3 +74,8 @@ fn my_format(w: &mut dyn std::io::Write, now: &mut DeferredNow, record: &Record)
         &record.args()
     )
 }
+
+/// 访问日志的记录在输出前已经格式化完毕，这里原样输出
+fn access_format(w: &mut dyn std::io::Write, _now: &mut DeferredNow, record: &Record) -> Result<(), std::io::Error> {
+    write!(w, "{}", &record.args())
+}
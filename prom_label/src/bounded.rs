@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use prometheus_client::{
+    encoding::{EncodeMetric, MetricEncoder},
+    metrics::{family::Family, MetricType, TypedMetric},
+};
+
+use crate::Label;
+
+/// 限制label组合数量的Family
+///
+/// - 每个series记录最后一次使用的时间，`evict_idle`会逐个删除空闲超过ttl的series，而不是清空整个Family
+/// - 非固定的series数量达到`max_series`后，新的label组合计入`overflow`这个series（例如各个label都为"other"）
+/// - `pin`的series（例如汇总用的"all"）不会被淘汰，也不占用`max_series`的名额
+#[derive(Debug)]
+pub struct BoundedFamily<S: Label, M> {
+    family: Family<S, M>,
+    series: Arc<RwLock<HashMap<S, Series>>>,
+    /// 非固定的series数量，只在持有series的写锁时修改，读锁下用来判断是否已经超过max_series
+    unpinned: Arc<AtomicUsize>,
+    overflow: S,
+    max_series: usize,
+    ttl: Duration,
+    created: Instant,
+}
+
+#[derive(Debug)]
+struct Series {
+    /// 相对于created的毫秒数
+    last_seen: AtomicU64,
+    pinned: bool,
+}
+
+impl<S: Label, M> Clone for BoundedFamily<S, M> {
+    fn clone(&self) -> Self {
+        Self {
+            family: self.family.clone(),
+            series: self.series.clone(),
+            unpinned: self.unpinned.clone(),
+            overflow: self.overflow.clone(),
+            max_series: self.max_series,
+            ttl: self.ttl,
+            created: self.created,
+        }
+    }
+}
+
+impl<S: Label, M: Default> BoundedFamily<S, M> {
+    pub fn new(overflow: S, max_series: usize, ttl: Duration) -> Self {
+        let bounded = Self {
+            family: Family::default(),
+            series: Arc::new(RwLock::new(HashMap::new())),
+            unpinned: Arc::new(AtomicUsize::new(0)),
+            overflow: overflow.clone(),
+            max_series,
+            ttl,
+            created: Instant::now(),
+        };
+        bounded.pin(overflow);
+        bounded
+    }
+}
+
+impl<S: Label, M> BoundedFamily<S, M> {
+    /// 固定一个series，不会被淘汰，也不占用max_series的名额
+    pub fn pin(&self, label: S) {
+        let now = self.now();
+        let mut series = self.series.write().unwrap_or_else(|e| e.into_inner());
+        let previous = series.insert(
+            label,
+            Series {
+                last_seen: AtomicU64::new(now),
+                pinned: true,
+            },
+        );
+        if previous.is_some_and(|entry| !entry.pinned) {
+            self.unpinned.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 获取label对应的metric并执行f。超过max_series时使用overflow series
+    pub fn with_series<R>(&self, label: &S, f: impl FnOnce(&M) -> R) -> R {
+        let now = self.now();
+        // 持有series的锁期间访问family，避免与evict_idle交错时在family中留下不受管理的series
+        {
+            let series = self.series.read().unwrap_or_else(|e| e.into_inner());
+            // 已经超过max_series时新的label直接计入固定的overflow series，只需要读锁
+            let label = match series.contains_key(label) {
+                true => Some(label),
+                false if self.unpinned.load(Ordering::Relaxed) >= self.max_series => Some(&self.overflow),
+                false => None,
+            };
+            if let Some((label, entry)) = label.and_then(|label| Some((label, series.get(label)?))) {
+                entry.last_seen.store(now, Ordering::Relaxed);
+                return f(&self.family.get_or_create(label));
+            }
+        }
+        let mut series = self.series.write().unwrap_or_else(|e| e.into_inner());
+        let label = if series.contains_key(label) || self.unpinned.load(Ordering::Relaxed) < self.max_series {
+            label
+        } else {
+            &self.overflow
+        };
+        series
+            .entry(label.clone())
+            .or_insert_with(|| {
+                self.unpinned.fetch_add(1, Ordering::Relaxed);
+                Series {
+                    last_seen: AtomicU64::new(now),
+                    pinned: false,
+                }
+            })
+            .last_seen
+            .store(now, Ordering::Relaxed);
+        f(&self.family.get_or_create(label))
+    }
+
+    /// 删除空闲超过ttl的series，返回删除的数量
+    pub fn evict_idle(&self) -> usize {
+        let deadline = self.now().saturating_sub(self.ttl.as_millis() as u64);
+        let mut series = self.series.write().unwrap_or_else(|e| e.into_inner());
+        let before = series.len();
+        series.retain(|label, entry| {
+            let keep = entry.pinned || entry.last_seen.load(Ordering::Relaxed) >= deadline;
+            if !keep {
+                self.family.remove(label);
+                self.unpinned.fetch_sub(1, Ordering::Relaxed);
+            }
+            keep
+        });
+        before - series.len()
+    }
+
+    /// 当前series的数量，包含固定的series
+    pub fn len(&self) -> usize {
+        self.series.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+}
+
+impl<S: Label, M: TypedMetric> TypedMetric for BoundedFamily<S, M> {
+    const TYPE: MetricType = <M as TypedMetric>::TYPE;
+}
+
+impl<S, M> EncodeMetric for BoundedFamily<S, M>
+where
+    S: Label,
+    M: EncodeMetric + TypedMetric,
+{
+    fn encode(&self, encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        self.family.encode(encoder)
+    }
+
+    fn metric_type(&self) -> MetricType {
+        M::TYPE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_client::{encoding::EncodeLabelSet, metrics::counter::Counter};
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+    struct ClientLabel {
+        client: String,
+    }
+
+    impl Label for ClientLabel {}
+
+    fn label(client: &str) -> ClientLabel {
+        ClientLabel {
+            client: client.to_owned(),
+        }
+    }
+
+    fn value(family: &BoundedFamily<ClientLabel, Counter>, client: &str) -> u64 {
+        family.family.get_or_create(&label(client)).get()
+    }
+
+    #[test]
+    fn test_overflow() {
+        let family = BoundedFamily::<ClientLabel, Counter>::new(label("other"), 2, Duration::from_secs(3600));
+        family.pin(label("all"));
+        for client in ["1.1.1.1", "2.2.2.2", "3.3.3.3", "4.4.4.4", "1.1.1.1", "all"] {
+            family.with_series(&label(client), |counter| counter.inc());
+        }
+        assert_eq!(value(&family, "1.1.1.1"), 2);
+        assert_eq!(value(&family, "2.2.2.2"), 1);
+        assert_eq!(value(&family, "other"), 2);
+        assert_eq!(value(&family, "all"), 1);
+        // 1.1.1.1 2.2.2.2 other all
+        assert_eq!(family.len(), 4);
+
+        // 固定已有的series后空出名额
+        family.pin(label("1.1.1.1"));
+        family.with_series(&label("5.5.5.5"), |counter| counter.inc());
+        assert_eq!(value(&family, "5.5.5.5"), 1);
+        family.with_series(&label("6.6.6.6"), |counter| counter.inc());
+        assert_eq!(value(&family, "other"), 3);
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let family = BoundedFamily::<ClientLabel, Counter>::new(label("other"), 1, Duration::from_millis(50));
+        family.pin(label("all"));
+        family.with_series(&label("1.1.1.1"), |counter| counter.inc());
+        family.with_series(&label("2.2.2.2"), |counter| counter.inc());
+        family.with_series(&label("all"), |counter| counter.inc());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(family.evict_idle(), 1);
+        assert_eq!(family.len(), 2);
+        // 淘汰后空出名额，新的label不再计入other
+        family.with_series(&label("2.2.2.2"), |counter| counter.inc());
+        assert_eq!(value(&family, "2.2.2.2"), 1);
+        assert_eq!(value(&family, "other"), 1);
+        assert_eq!(value(&family, "all"), 1);
+    }
+}
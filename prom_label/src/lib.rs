@@ -1,3 +1,7 @@
+mod bounded;
+
+pub use bounded::BoundedFamily;
+
 use core::fmt::Debug;
 use core::hash::Hash;
 use core::ops::Deref;
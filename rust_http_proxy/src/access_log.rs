@@ -0,0 +1,442 @@
+//! 访问日志：每个请求或隧道输出一条结构化记录到独立的access log文件
+
+use std::{
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use chrono::{DateTime, Local};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    header, Request, Response,
+};
+use log::info;
+use pin_project_lite::pin_project;
+use serde::Serialize;
+
+use crate::tunnel::TunnelStats;
+
+/// 访问日志格式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum AccessLogFormat {
+    /// 每行一个json对象，方便投递到Loki、Elasticsearch
+    Json,
+    /// 类似nginx的combined格式，并在末尾追加上下行字节数、耗时、上游、tls信息
+    Combined,
+}
+
+/// 请求的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccessKind {
+    /// 正向代理普通请求
+    Proxy,
+    /// 正向代理CONNECT隧道
+    Tunnel,
+    /// 正向代理普通请求使用的上游连接，在连接关闭时记录
+    ProxyConn,
+    /// 反向代理
+    Reverse,
+    /// 静态资源等web服务
+    Web,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AccessRecord {
+    #[serde(serialize_with = "serialize_time")]
+    timestamp: DateTime<Local>,
+    kind: AccessKind,
+    client: String,
+    user: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    status: Option<u16>,
+    bytes_up: u64,
+    bytes_down: u64,
+    duration_ms: u64,
+    upstream: Option<String>,
+    tls: bool,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    close_reason: Option<&'static str>,
+    ttfb_ms: Option<u64>,
+    /// 开启otel feature并配置了collector时，反向代理请求的trace id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_id: Option<String>,
+}
+
+fn serialize_time<S: serde::Serializer>(time: &DateTime<Local>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+impl AccessRecord {
+    fn emit(&self, format: AccessLogFormat) {
+        info!(target: log_x::ACCESS_LOG_TARGET, "{}", self.format(format));
+    }
+
+    pub(crate) fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+            AccessLogFormat::Combined => self.format_combined(),
+        }
+    }
+
+    // $remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" $bytes_up $duration_ms $upstream $tls $close_reason [$trace_id]
+    fn format_combined(&self) -> String {
+        let mut line = String::with_capacity(256);
+        let _ = write!(
+            line,
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {}ms {} {} {}",
+            self.client,
+            self.user.as_deref().unwrap_or("-"),
+            self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.target,
+            self.version,
+            self.status.map_or("-".to_owned(), |status| status.to_string()),
+            self.bytes_down,
+            escape_quoted(self.referer.as_deref().unwrap_or("-")),
+            escape_quoted(self.user_agent.as_deref().unwrap_or("-")),
+            self.bytes_up,
+            self.duration_ms,
+            self.upstream.as_deref().unwrap_or("-"),
+            if self.tls { "tls" } else { "-" },
+            self.close_reason.unwrap_or("-"),
+        );
+        if let Some(trace_id) = &self.trace_id {
+            let _ = write!(line, " {}", trace_id);
+        }
+        line
+    }
+}
+
+fn escape_quoted(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// 访问日志的全局配置
+#[derive(Clone)]
+pub(crate) struct AccessLog {
+    format: AccessLogFormat,
+    over_tls: bool,
+}
+
+impl AccessLog {
+    pub(crate) fn new(format: AccessLogFormat, over_tls: bool) -> Self {
+        Self { format, over_tls }
+    }
+
+    /// 在请求到达时创建一条待完成的访问记录
+    pub(crate) fn begin(&self, req: &Request<impl Body>, client_socket_addr: SocketAddr) -> AccessEntry {
+        let header_str = |name: header::HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let mut record = self.new_record(
+            AccessKind::Web,
+            client_socket_addr.ip().to_canonical().to_string(),
+            req.method().to_string(),
+            req.uri().to_string(),
+        );
+        record.version = format!("{:?}", req.version());
+        record.referer = header_str(header::REFERER);
+        record.user_agent = header_str(header::USER_AGENT);
+        AccessEntry {
+            record,
+            format: self.format,
+            start: Instant::now(),
+            bytes_up: Arc::new(AtomicU64::new(0)),
+            discarded: false,
+            #[cfg(feature = "otel")]
+            trace: None,
+        }
+    }
+
+    /// 正向代理的上游连接关闭时输出一条记录
+    pub(crate) fn log_proxy_conn(&self, client: &str, user: &str, target: &str, stats: &TunnelStats) {
+        let mut record = self.new_record(AccessKind::ProxyConn, client.to_owned(), "-".to_owned(), target.to_owned());
+        record.user = Some(user.to_owned());
+        record.upstream = Some(target.to_owned());
+        record.tls = false;
+        record.bytes_up = stats.from_client;
+        record.bytes_down = stats.from_server;
+        record.duration_ms = stats.duration.as_millis() as u64;
+        record.ttfb_ms = stats.ttfb.map(|ttfb| ttfb.as_millis() as u64);
+        record.close_reason = Some(stats.close_reason.as_str());
+        record.emit(self.format);
+    }
+
+    fn new_record(&self, kind: AccessKind, client: String, method: String, target: String) -> AccessRecord {
+        AccessRecord {
+            timestamp: Local::now(),
+            kind,
+            client,
+            user: None,
+            method,
+            target,
+            version: "-".to_owned(),
+            status: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration_ms: 0,
+            upstream: None,
+            tls: self.over_tls,
+            referer: None,
+            user_agent: None,
+            close_reason: None,
+            ttfb_ms: None,
+            trace_id: None,
+        }
+    }
+}
+
+/// 一条进行中的访问记录，drop时输出到access log
+///
+/// 对于普通请求，AccessEntry会随响应body一起传递，在body发送完毕（或客户端断开）时输出；
+/// 对于隧道，AccessEntry在隧道关闭时输出。
+pub(crate) struct AccessEntry {
+    record: AccessRecord,
+    format: AccessLogFormat,
+    start: Instant,
+    bytes_up: Arc<AtomicU64>,
+    discarded: bool,
+    #[cfg(feature = "otel")]
+    trace: Option<crate::otel::RequestTrace>,
+}
+
+impl AccessEntry {
+    pub(crate) fn set_kind(&mut self, kind: AccessKind) {
+        self.record.kind = kind;
+    }
+
+    pub(crate) fn set_user(&mut self, user: &str) {
+        self.record.user = Some(user.to_owned());
+    }
+
+    pub(crate) fn set_upstream(&mut self, upstream: String) {
+        self.record.upstream = Some(upstream);
+    }
+
+    pub(crate) fn set_status(&mut self, status: http::StatusCode) {
+        self.record.status = Some(status.as_u16());
+    }
+
+    /// 记录隧道两个方向的字节数、首字节耗时和关闭原因
+    pub(crate) fn set_tunnel_stats(&mut self, stats: &TunnelStats) {
+        self.bytes_up.store(stats.from_client, Ordering::Relaxed);
+        self.record.bytes_down = stats.from_server;
+        self.record.ttfb_ms = stats.ttfb.map(|ttfb| ttfb.as_millis() as u64);
+        self.record.close_reason = Some(stats.close_reason.as_str());
+    }
+
+    /// 关联请求的trace，trace在访问日志输出时结束
+    #[cfg(feature = "otel")]
+    pub(crate) fn set_trace(&mut self, trace: crate::otel::RequestTrace) {
+        self.record.trace_id = Some(trace.trace_id());
+        self.trace = Some(trace);
+    }
+
+    #[cfg(feature = "otel")]
+    pub(crate) fn trace(&self) -> Option<&crate::otel::RequestTrace> {
+        self.trace.as_ref()
+    }
+
+    /// 不输出这条记录，例如交给axum router处理的请求
+    pub(crate) fn discard(mut self) {
+        self.discarded = true;
+    }
+
+    /// 统计请求body的字节数
+    pub(crate) fn count_request<B>(&self, req: Request<B>) -> Request<BoxBody<Bytes, io::Error>>
+    where
+        B: Body<Data = Bytes> + Send + Sync + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let bytes_up = self.bytes_up.clone();
+        req.map(|body| {
+            CountBody {
+                inner: body,
+                counter: bytes_up,
+            }
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .boxed()
+        })
+    }
+
+    /// 记录响应状态码，并在响应body结束时输出访问日志
+    pub(crate) fn finish_with(
+        mut self, resp: Response<BoxBody<Bytes, io::Error>>,
+    ) -> Response<BoxBody<Bytes, io::Error>> {
+        self.set_status(resp.status());
+        resp.map(|body| {
+            AccessLogBody {
+                inner: body,
+                entry: self,
+            }
+            .boxed()
+        })
+    }
+}
+
+impl Drop for AccessEntry {
+    fn drop(&mut self) {
+        if self.discarded {
+            return;
+        }
+        self.record.duration_ms = self.start.elapsed().as_millis() as u64;
+        self.record.bytes_up = self.bytes_up.load(Ordering::Relaxed);
+        #[cfg(feature = "otel")]
+        if let Some(trace) = self.trace.take() {
+            trace.end(self.record.status);
+        }
+        self.record.emit(self.format);
+    }
+}
+
+pin_project! {
+    /// 统计响应body的字节数，body被drop时输出访问日志
+    struct AccessLogBody<B> {
+        #[pin]
+        inner: B,
+        entry: AccessEntry,
+    }
+}
+
+impl<B> Body for AccessLogBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let this = self.project();
+        let polled = this.inner.poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &polled {
+            if let Some(data) = frame.data_ref() {
+                this.entry.record.bytes_down += data.len() as u64;
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pin_project! {
+    /// 统计请求body的字节数
+    struct CountBody<B> {
+        #[pin]
+        inner: B,
+        counter: Arc<AtomicU64>,
+    }
+}
+
+impl<B> Body for CountBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let this = self.project();
+        let polled = this.inner.poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &polled {
+            if let Some(data) = frame.data_ref() {
+                this.counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            timestamp: Local
+                .with_ymd_and_hms(2024, 10, 1, 8, 30, 0)
+                .single()
+                .unwrap_or_default(),
+            kind: AccessKind::Reverse,
+            client: "1.2.3.4".to_owned(),
+            user: None,
+            method: "GET".to_owned(),
+            target: "/index.html".to_owned(),
+            version: "HTTP/1.1".to_owned(),
+            status: Some(200),
+            bytes_up: 12,
+            bytes_down: 1024,
+            duration_ms: 35,
+            upstream: Some("https://example.com".to_owned()),
+            tls: true,
+            referer: Some("https://a.com/\"x\"".to_owned()),
+            user_agent: None,
+            close_reason: None,
+            ttfb_ms: None,
+            trace_id: None,
+        }
+    }
+
+    #[test]
+    fn test_format_json() {
+        let line = record().format(AccessLogFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap_or_default();
+        assert_eq!(value["kind"], "reverse");
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["bytes_down"], 1024);
+        assert_eq!(value["user"], serde_json::Value::Null);
+        assert_eq!(value["upstream"], "https://example.com");
+        assert!(value.get("trace_id").is_none());
+    }
+
+    #[test]
+    fn test_format_combined() {
+        let line = record().format(AccessLogFormat::Combined);
+        assert!(line.starts_with("1.2.3.4 - - [01/Oct/2024:08:30:00 "));
+        assert!(line.ends_with(
+            "\"GET /index.html HTTP/1.1\" 200 1024 \"https://a.com/\\\"x\\\"\" \"-\" 12 35ms https://example.com tls -"
+        ));
+    }
+
+    #[test]
+    fn test_format_with_trace_id() {
+        let mut record = record();
+        record.trace_id = Some("0af7651916cd43dd8448eb211c80319c".to_owned());
+        let value: serde_json::Value = serde_json::from_str(&record.format(AccessLogFormat::Json)).unwrap_or_default();
+        assert_eq!(value["trace_id"], "0af7651916cd43dd8448eb211c80319c");
+        assert!(record
+            .format(AccessLogFormat::Combined)
+            .ends_with("https://example.com tls - 0af7651916cd43dd8448eb211c80319c"));
+    }
+}
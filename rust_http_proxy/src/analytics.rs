@@ -0,0 +1,404 @@
+//! 静态网站的访问统计：按天统计各路径的浏览量、独立访客（IP哈希）、来源（搜索引擎或者Referer的host）和User-Agent类型
+//!
+//! 数据只保存在内存中，保留最近几天，每天的路径和来源数量有上限，超过后计入other。通过/stats查看
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use chrono::DateTime;
+use log::warn;
+use serde::Serialize;
+
+/// 没有指定--analytics-search-engine时识别的搜索引擎等来源
+pub(crate) const DEFAULT_SEARCH_ENGINES: &[&str] = &[
+    "google",
+    "baidu",
+    "bing",
+    "yandex",
+    "v2ex",
+    "github",
+    "stackoverflow",
+    "duckduckgo",
+];
+
+/// 保留的天数
+const RETAINED_DAYS: usize = 7;
+/// /stats中每个排行展示的数量
+const TOP_N: usize = 20;
+/// 路径、来源超过上限后计入的key
+const OVERFLOW_KEY: &str = "other";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// User-Agent中出现这些关键字时视为爬虫或者脚本
+const BOT_KEYWORDS: &[&str] = &[
+    "bot",
+    "spider",
+    "crawl",
+    "slurp",
+    "curl",
+    "wget",
+    "python",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "headless",
+    "facebookexternalhit",
+    "preview",
+];
+
+const TEMPLATE_NAME: &str = "stats.html";
+
+// 模板名以.html结尾，tera会自动转义变量
+static TERA: LazyLock<tera::Tera> = LazyLock::new(|| {
+    let mut tera = tera::Tera::default();
+    if let Err(e) = tera.add_raw_template(TEMPLATE_NAME, include_str!("../html/stats.html")) {
+        warn!("add stats template error: {}", e);
+    }
+    tera
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AgentClass {
+    Browser,
+    Bot,
+    Other,
+}
+
+impl AgentClass {
+    const ALL: [AgentClass; 3] = [AgentClass::Browser, AgentClass::Bot, AgentClass::Other];
+
+    pub(crate) fn classify(user_agent: &str) -> Self {
+        let user_agent = user_agent.to_ascii_lowercase();
+        if BOT_KEYWORDS.iter().any(|keyword| user_agent.contains(keyword)) {
+            AgentClass::Bot
+        } else if user_agent.starts_with("mozilla/") || user_agent.starts_with("opera") {
+            AgentClass::Browser
+        } else {
+            AgentClass::Other
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            AgentClass::Browser => "browser",
+            AgentClass::Bot => "bot",
+            AgentClass::Other => "other",
+        }
+    }
+}
+
+/// 一次页面浏览
+pub(crate) struct PageView<'a> {
+    pub(crate) path: &'a str,
+    /// 请求的Host，Referer的host与之相同时视为站内跳转，不统计来源
+    pub(crate) host: &'a str,
+    pub(crate) referer: &'a str,
+    pub(crate) user_agent: &'a str,
+    pub(crate) client_ip: IpAddr,
+}
+
+#[derive(Default)]
+struct DayStats {
+    /// 自unix epoch以来的天数（UTC）
+    day: u64,
+    page_views: HashMap<String, u64>,
+    referrers: HashMap<String, u64>,
+    visitors: HashSet<u64>,
+    /// 独立访客达到上限后不再增加
+    visitors_saturated: bool,
+    agents: [u64; 3],
+}
+
+pub(crate) struct Analytics {
+    search_engines: Vec<String>,
+    /// 每天路径、来源、独立访客的数量上限
+    max_keys: usize,
+    /// 访客IP的哈希使用进程内随机的key，重启后无法关联
+    hasher: RandomState,
+    days: Mutex<VecDeque<DayStats>>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Snapshot {
+    days: Vec<DaySummary>,
+    top_paths: Vec<Ranked>,
+    top_referrers: Vec<Ranked>,
+    agents: Vec<Ranked>,
+}
+
+#[derive(Serialize)]
+struct DaySummary {
+    date: String,
+    page_views: u64,
+    visitors: usize,
+    visitors_saturated: bool,
+}
+
+#[derive(Serialize)]
+struct Ranked {
+    key: String,
+    count: u64,
+}
+
+impl Analytics {
+    pub(crate) fn new(search_engines: Vec<String>, max_keys: usize) -> Self {
+        let search_engines = if search_engines.is_empty() {
+            DEFAULT_SEARCH_ENGINES.iter().map(|engine| engine.to_string()).collect()
+        } else {
+            search_engines
+        };
+        Analytics {
+            search_engines,
+            max_keys,
+            hasher: RandomState::new(),
+            days: Mutex::new(VecDeque::with_capacity(RETAINED_DAYS + 1)),
+        }
+    }
+
+    /// Referer对应的来源：属于搜索引擎列表时为搜索引擎的名字，否则为Referer的host；不是http(s)的URL时返回原值
+    pub(crate) fn referer_source(&self, referer: &str) -> String {
+        let Some(host) = referer_host(referer) else {
+            return referer.to_owned();
+        };
+        let host = host.to_ascii_lowercase();
+        self.search_engines
+            .iter()
+            .filter_map(|engine| host.find(engine.as_str()).map(|index| (index, engine)))
+            .min_by_key(|(index, _)| *index)
+            .map_or_else(|| host.clone(), |(_, engine)| engine.clone())
+    }
+
+    pub(crate) fn record(&self, view: PageView<'_>, now: SystemTime) {
+        let day = unix_secs(now) / SECONDS_PER_DAY;
+        let source = match referer_host(view.referer) {
+            Some(host) if !host.eq_ignore_ascii_case(strip_port(view.host)) => Some(self.referer_source(view.referer)),
+            _ => None,
+        };
+        let visitor = self.hasher.hash_one((day, view.client_ip.to_canonical()));
+        let agent = AgentClass::classify(view.user_agent);
+
+        let Ok(mut days) = self.days.lock() else {
+            return;
+        };
+        // 跨天时新建当天的统计
+        if !days.back().is_some_and(|stats| stats.day >= day) {
+            days.push_back(DayStats {
+                day,
+                ..Default::default()
+            });
+            while days.len() > RETAINED_DAYS {
+                days.pop_front();
+            }
+        }
+        let Some(stats) = days.back_mut() else {
+            return;
+        };
+        incr_bounded(&mut stats.page_views, view.path, self.max_keys);
+        if let Some(source) = source {
+            incr_bounded(&mut stats.referrers, &source, self.max_keys);
+        }
+        if stats.visitors.len() < self.max_keys {
+            stats.visitors.insert(visitor);
+        } else if !stats.visitors.contains(&visitor) {
+            stats.visitors_saturated = true;
+        }
+        stats.agents[agent as usize] += 1;
+    }
+
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        let Ok(days) = self.days.lock() else {
+            return Snapshot {
+                days: vec![],
+                top_paths: vec![],
+                top_referrers: vec![],
+                agents: vec![],
+            };
+        };
+        let mut paths = HashMap::<&str, u64>::new();
+        let mut referrers = HashMap::<&str, u64>::new();
+        let mut agents = [0u64; 3];
+        for stats in days.iter() {
+            for (path, count) in &stats.page_views {
+                *paths.entry(path).or_default() += count;
+            }
+            for (referrer, count) in &stats.referrers {
+                *referrers.entry(referrer).or_default() += count;
+            }
+            for (total, count) in agents.iter_mut().zip(stats.agents) {
+                *total += count;
+            }
+        }
+        Snapshot {
+            days: days
+                .iter()
+                .rev()
+                .map(|stats| DaySummary {
+                    date: DateTime::from_timestamp((stats.day * SECONDS_PER_DAY) as i64, 0)
+                        .map(|date| date.format("%Y-%m-%d").to_string())
+                        .unwrap_or_default(),
+                    page_views: stats.page_views.values().sum(),
+                    visitors: stats.visitors.len(),
+                    visitors_saturated: stats.visitors_saturated,
+                })
+                .collect(),
+            top_paths: top(paths),
+            top_referrers: top(referrers),
+            agents: AgentClass::ALL
+                .iter()
+                .map(|class| Ranked {
+                    key: class.as_str().to_owned(),
+                    count: agents[*class as usize],
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Snapshot {
+    pub(crate) fn render_html(&self) -> tera::Result<String> {
+        TERA.render(TEMPLATE_NAME, &tera::Context::from_serialize(self)?)
+    }
+
+    pub(crate) fn render_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+fn incr_bounded(map: &mut HashMap<String, u64>, key: &str, max_keys: usize) {
+    if let Some(count) = map.get_mut(key) {
+        *count += 1;
+    } else if map.len() < max_keys {
+        map.insert(key.to_owned(), 1);
+    } else {
+        *map.entry(OVERFLOW_KEY.to_owned()).or_default() += 1;
+    }
+}
+
+fn top(counts: HashMap<&str, u64>) -> Vec<Ranked> {
+    let mut ranked = counts
+        .into_iter()
+        .map(|(key, count)| Ranked {
+            key: key.to_owned(),
+            count,
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    ranked.truncate(TOP_N);
+    ranked
+}
+
+/// http(s) URL中的host，不含端口
+fn referer_host(referer: &str) -> Option<&str> {
+    let rest = referer
+        .strip_prefix("https://")
+        .or_else(|| referer.strip_prefix("http://"))?;
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    let host = strip_port(authority.rsplit('@').next().unwrap_or(authority));
+    (!host.is_empty()).then_some(host)
+}
+
+/// 去掉端口，IPv6保留方括号
+fn strip_port(authority: &str) -> &str {
+    if authority.starts_with('[') {
+        return authority.find(']').map_or(authority, |end| &authority[..=end]);
+    }
+    authority.split(':').next().unwrap_or(authority)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_referer_source() {
+        let analytics = Analytics::new(vec![], 100);
+        assert_eq!(analytics.referer_source("https://www.baidu.com/"), "baidu");
+        assert_eq!(analytics.referer_source("https://www.baidu.com"), "baidu");
+        assert_eq!(analytics.referer_source("http://www.baidu.com/"), "baidu");
+        assert_eq!(analytics.referer_source("sadasdasdsadas"), "sadasdasdsadas");
+        assert_eq!(analytics.referer_source("http://huaiwen.com/baidu.com/bing.com"), "huaiwen.com");
+        assert_eq!(analytics.referer_source("http://huaiwenbaidu.com/baidu.com/bing.com"), "baidu");
+        assert_eq!(analytics.referer_source("https://www.google.com.hk/"), "google");
+        assert_eq!(
+            analytics.referer_source(
+                "https://www.bing.com/search?q=google%E6%9C%8D%E5%8A%A1%E4%B8%8B%E8%BD%BD+anzhuo11&qs=ds&form=QBRE"
+            ),
+            "bing"
+        );
+        assert_eq!(analytics.referer_source("https://Example.com:8443/a"), "example.com");
+        let custom = Analytics::new(vec!["example".to_owned()], 100);
+        assert_eq!(custom.referer_source("https://www.google.com/"), "www.google.com");
+        assert_eq!(custom.referer_source("https://blog.example.com/"), "example");
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            AgentClass::classify("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 Chrome/120.0"),
+            AgentClass::Browser
+        );
+        assert_eq!(
+            AgentClass::classify("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"),
+            AgentClass::Bot
+        );
+        assert_eq!(AgentClass::classify("curl/8.5.0"), AgentClass::Bot);
+        assert_eq!(AgentClass::classify(""), AgentClass::Other);
+    }
+
+    #[test]
+    fn test_record() {
+        let analytics = Analytics::new(vec![], 2);
+        let day1 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let view = |path, referer, ip: [u8; 4]| PageView {
+            path,
+            host: "example.com:443",
+            referer,
+            user_agent: "Mozilla/5.0",
+            client_ip: ip.into(),
+        };
+        analytics.record(view("/", "https://www.google.com/", [1, 1, 1, 1]), day1);
+        analytics.record(view("/", "https://example.com/a.html", [1, 1, 1, 1]), day1);
+        analytics.record(view("/a.html", "", [2, 2, 2, 2]), day1);
+        // 超过上限
+        analytics.record(view("/b.html", "https://v2ex.com/t/1", [3, 3, 3, 3]), day1);
+        analytics.record(view("/c.html", "https://news.ycombinator.com/", [4, 4, 4, 4]), day1);
+        analytics.record(view("/", "", [1, 1, 1, 1]), day1 + Duration::from_secs(SECONDS_PER_DAY));
+
+        let snapshot = analytics.snapshot();
+        assert_eq!(snapshot.days.len(), 2);
+        assert_eq!(snapshot.days[0].page_views, 1);
+        assert_eq!((snapshot.days[1].date.as_str(), snapshot.days[1].page_views), ("2023-11-14", 5));
+        assert_eq!((snapshot.days[1].visitors, snapshot.days[1].visitors_saturated), (2, true));
+        let ranked = |ranked: &[Ranked]| ranked.iter().map(|r| (r.key.clone(), r.count)).collect::<Vec<_>>();
+        assert_eq!(
+            ranked(&snapshot.top_paths),
+            vec![("/".to_owned(), 3), ("other".to_owned(), 2), ("/a.html".to_owned(), 1)]
+        );
+        // 站内跳转不计入来源
+        assert_eq!(
+            ranked(&snapshot.top_referrers),
+            vec![
+                ("google".to_owned(), 1),
+                ("other".to_owned(), 1),
+                ("v2ex".to_owned(), 1)
+            ]
+        );
+        assert_eq!(snapshot.agents[0].count, 6);
+        // tera会转义/
+        assert!(snapshot
+            .render_html()
+            .is_ok_and(|html| html.contains("&#x2F;a.html") && html.contains("&ge;2")));
+    }
+}
@@ -0,0 +1,346 @@
+//! 目录列表（autoindex）：目录下没有index.html时列出目录内容，支持html和json两种输出
+//!
+//! html通过`?sort=name|size|mtime&order=asc|desc`排序，`?format=json`输出json
+
+use std::{cmp::Ordering, io, path::Path, sync::LazyLock, time::SystemTime};
+
+use chrono::{DateTime, Local, Utc};
+use http::{Error, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use log::warn;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Serialize;
+
+use crate::{
+    proxy::{empty_body, full_body},
+    web_func::{build_500_resp, SERVER_NAME},
+};
+
+/// 路径中的一段，除了unreserved字符都需要编码
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+const TEMPLATE_NAME: &str = "autoindex.html";
+
+// 模板名以.html结尾，tera会自动转义变量
+static TERA: LazyLock<tera::Tera> = LazyLock::new(|| {
+    let mut tera = tera::Tera::default();
+    if let Err(e) = tera.add_raw_template(TEMPLATE_NAME, include_str!("../html/autoindex.html")) {
+        warn!("add autoindex template error: {}", e);
+    }
+    tera
+});
+
+/// 按url路径前缀开启目录列表
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AutoIndexConfig {
+    prefixes: Vec<String>,
+    show_hidden: bool,
+}
+
+impl AutoIndexConfig {
+    pub(crate) fn new(prefixes: Vec<String>, show_hidden: bool) -> Self {
+        AutoIndexConfig { prefixes, show_hidden }
+    }
+
+    pub(crate) fn enabled_for(&self, url_path: &str) -> bool {
+        self.prefixes.iter().any(|prefix| url_path.starts_with(prefix.as_str()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Mtime,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ListQuery {
+    sort: SortKey,
+    desc: bool,
+    json: bool,
+}
+
+impl ListQuery {
+    fn parse(query: Option<&str>) -> Self {
+        let mut list_query = ListQuery {
+            sort: SortKey::Name,
+            desc: false,
+            json: false,
+        };
+        for pair in query.unwrap_or_default().split('&') {
+            match pair.split_once('=') {
+                Some(("sort", "name")) => list_query.sort = SortKey::Name,
+                Some(("sort", "size")) => list_query.sort = SortKey::Size,
+                Some(("sort", "mtime")) => list_query.sort = SortKey::Mtime,
+                Some(("order", "desc")) => list_query.desc = true,
+                Some(("order", "asc")) => list_query.desc = false,
+                Some(("format", "json")) => list_query.json = true,
+                _ => {}
+            }
+        }
+        list_query
+    }
+
+    /// 表头链接的排序方向：当前按该列升序时切换为降序
+    fn next_order(&self, key: SortKey) -> &'static str {
+        if self.sort == key && !self.desc {
+            "desc"
+        } else {
+            "asc"
+        }
+    }
+}
+
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    name: &'a str,
+    is_dir: bool,
+    /// 目录为null
+    size: Option<u64>,
+    mtime: String,
+}
+
+#[derive(Serialize)]
+struct HtmlEntry<'a> {
+    name: &'a str,
+    href: String,
+    is_dir: bool,
+    size: String,
+    mtime: String,
+}
+
+/// 列出dir的内容，url_path为目录对应的url路径，以/结尾
+pub(crate) async fn serve_dir(
+    dir: &Path, url_path: &str, query: Option<&str>, config: &AutoIndexConfig, need_body: bool,
+) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    let list_query = ListQuery::parse(query);
+    if !tokio::fs::metadata(dir).await.is_ok_and(|meta| meta.is_dir()) {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header(http::header::SERVER, SERVER_NAME)
+            .body(empty_body());
+    }
+    let mut entries = match read_dir(dir, config.show_hidden).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("read dir {:?} error: {}", dir, e);
+            return Ok(build_500_resp());
+        }
+    };
+    sort_entries(&mut entries, &list_query);
+    let (content_type, body) = if list_query.json {
+        ("application/json; charset=utf-8", render_json(url_path, &entries))
+    } else {
+        match render_html(url_path, &entries, &list_query) {
+            Ok(body) => ("text/html; charset=utf-8", body),
+            Err(e) => {
+                warn!("render autoindex error: {}", e);
+                return Ok(build_500_resp());
+            }
+        }
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::SERVER, SERVER_NAME)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .body(if need_body { full_body(body) } else { empty_body() })
+}
+
+async fn read_dir(dir: &Path, show_hidden: bool) -> io::Result<Vec<DirEntry>> {
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    let mut entries = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // .git始终隐藏，与serve_path禁止访问/.git/保持一致
+        if name == ".git" || (!show_hidden && name.starts_with('.')) {
+            continue;
+        }
+        // 跟随符号链接，无效的链接不展示
+        let meta = match tokio::fs::metadata(entry.path()).await {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        entries.push(DirEntry {
+            name,
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+    Ok(entries)
+}
+
+/// 目录总是排在文件前面
+fn sort_entries(entries: &mut [DirEntry], list_query: &ListQuery) {
+    entries.sort_by(|a, b| {
+        let ordering = match list_query.sort {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortKey::Mtime => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name)),
+        };
+        let ordering = if list_query.desc { ordering.reverse() } else { ordering };
+        match (a.is_dir, b.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => ordering,
+        }
+    });
+}
+
+fn render_json(url_path: &str, entries: &[DirEntry]) -> String {
+    let entries = entries
+        .iter()
+        .map(|entry| JsonEntry {
+            name: &entry.name,
+            is_dir: entry.is_dir,
+            size: if entry.is_dir { None } else { Some(entry.size) },
+            mtime: DateTime::<Utc>::from(entry.modified).to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "path": url_path,
+        "entries": entries,
+    })
+    .to_string()
+}
+
+fn render_html(url_path: &str, entries: &[DirEntry], list_query: &ListQuery) -> tera::Result<String> {
+    let entries = entries
+        .iter()
+        .map(|entry| HtmlEntry {
+            name: &entry.name,
+            href: utf8_percent_encode(&entry.name, PATH_SEGMENT).to_string() + if entry.is_dir { "/" } else { "" },
+            is_dir: entry.is_dir,
+            size: if entry.is_dir {
+                "-".to_owned()
+            } else {
+                human_size(entry.size)
+            },
+            mtime: DateTime::<Local>::from(entry.modified)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        })
+        .collect::<Vec<_>>();
+    let mut context = tera::Context::new();
+    context.insert("path", url_path);
+    context.insert("has_parent", &(url_path != "/"));
+    context.insert("entries", &entries);
+    context.insert("name_order", list_query.next_order(SortKey::Name));
+    context.insert("size_order", list_query.next_order(SortKey::Size));
+    context.insert("mtime_order", list_query.next_order(SortKey::Mtime));
+    TERA.render(TEMPLATE_NAME, &context)
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tempfile::TempDir;
+
+    /// 测试结束时TempDir被drop，目录随之删除
+    async fn prepare_dir() -> io::Result<TempDir> {
+        let dir = tempfile::tempdir()?;
+        tokio::fs::create_dir_all(dir.path().join("sub dir")).await?;
+        tokio::fs::create_dir_all(dir.path().join(".git")).await?;
+        tokio::fs::write(dir.path().join("small.txt"), b"1").await?;
+        tokio::fs::write(dir.path().join("<script>.txt"), vec![0u8; 2048]).await?;
+        tokio::fs::write(dir.path().join(".env"), b"SECRET=1").await?;
+        Ok(dir)
+    }
+
+    async fn body_string(resp: Response<BoxBody<Bytes, io::Error>>) -> String {
+        let body = resp
+            .into_body()
+            .collect()
+            .await
+            .map(|body| body.to_bytes())
+            .unwrap_or_default();
+        String::from_utf8_lossy(&body).into_owned()
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            ListQuery::parse(Some("sort=size&order=desc&format=json")),
+            ListQuery {
+                sort: SortKey::Size,
+                desc: true,
+                json: true,
+            }
+        );
+        let default = ListQuery::parse(None);
+        assert_eq!(default.sort, SortKey::Name);
+        assert_eq!(default.next_order(SortKey::Name), "desc");
+        assert_eq!(default.next_order(SortKey::Size), "asc");
+        assert_eq!(human_size(512), "512");
+        assert_eq!(human_size(1536), "1.5K");
+    }
+
+    #[tokio::test]
+    async fn test_serve_dir_html() -> io::Result<()> {
+        let dir = prepare_dir().await?;
+        let config = AutoIndexConfig::new(vec!["/files/".to_owned()], false);
+        assert!(config.enabled_for("/files/a/"));
+        assert!(!config.enabled_for("/other/"));
+        let resp = serve_dir(dir.path(), "/files/", None, &config, true)
+            .await
+            .map_err(io::Error::other)?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let html = body_string(resp).await;
+        assert!(html.contains("&lt;script&gt;.txt"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("href=\"%3Cscript%3E.txt\""));
+        assert!(html.contains("href=\"sub%20dir&#x2F;\""));
+        assert!(!html.contains(".env"));
+        assert!(!html.contains(".git"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_dir_json() -> io::Result<()> {
+        let dir = prepare_dir().await?;
+        let config = AutoIndexConfig::new(vec!["/".to_owned()], true);
+        let resp = serve_dir(dir.path(), "/", Some("format=json&sort=size&order=desc"), &config, true)
+            .await
+            .map_err(io::Error::other)?;
+        let value: serde_json::Value = serde_json::from_str(&body_string(resp).await)?;
+        let names: Vec<&str> = value["entries"]
+            .as_array()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| entry["name"].as_str().unwrap_or_default())
+                    .collect()
+            })
+            .unwrap_or_default();
+        // 目录在前，文件按大小降序，.git始终隐藏
+        assert_eq!(names, vec!["sub dir", "<script>.txt", ".env", "small.txt"]);
+        assert_eq!(value["entries"][0]["size"], serde_json::Value::Null);
+        assert_eq!(value["entries"][1]["size"], 2048);
+        Ok(())
+    }
+}
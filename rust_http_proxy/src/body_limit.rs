@@ -0,0 +1,128 @@
+//! 请求体大小限制：Content-Length超过限制时直接拒绝，chunked的请求体在转发过程中超过限制时中断
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use pin_project_lite::pin_project;
+
+/// 放在io::Error中，表示请求体超过限制，返回413
+#[derive(Debug)]
+pub(crate) struct BodyTooLarge(pub(crate) u64);
+
+impl Display for BodyTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "request body exceeds {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+impl BodyTooLarge {
+    /// 错误链中是否有BodyTooLarge，上游请求失败时用于区分是否应该返回413
+    pub(crate) fn find(e: &(dyn std::error::Error + 'static)) -> bool {
+        let mut source = Some(e);
+        while let Some(e) = source {
+            if e.is::<BodyTooLarge>() {
+                return true;
+            }
+            // io::Error的source()会跳过其中包装的错误
+            source = match e.downcast_ref::<io::Error>().and_then(|e| e.get_ref()) {
+                Some(inner) => Some(inner),
+                None => e.source(),
+            };
+        }
+        false
+    }
+}
+
+/// Content-Length（size_hint的下限）已经超过限制
+pub(crate) fn exceeds(body: &impl Body, limit: Option<u64>) -> bool {
+    limit.is_some_and(|limit| body.size_hint().lower() > limit)
+}
+
+pin_project! {
+    /// 超过limit字节后返回BodyTooLarge错误
+    pub(crate) struct LimitedBody<B> {
+        #[pin]
+        inner: B,
+        limit: u64,
+        read: u64,
+    }
+}
+
+impl<B> LimitedBody<B> {
+    pub(crate) fn new(inner: B, limit: u64) -> Self {
+        LimitedBody { inner, limit, read: 0 }
+    }
+}
+
+impl<B> Body for LimitedBody<B>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = self.project();
+        match this.inner.poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    *this.read += data.len() as u64;
+                    if *this.read > *this.limit {
+                        return Poll::Ready(Some(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            BodyTooLarge(*this.limit),
+                        ))));
+                    }
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::InvalidData, e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, StreamBody};
+
+    use super::*;
+    use crate::proxy::full_body;
+
+    #[tokio::test]
+    async fn test_limited_body() {
+        assert!(exceeds(&full_body("abcd"), Some(3)));
+        assert!(!exceeds(&full_body("abcd"), Some(4)));
+        assert!(!exceeds(&full_body("abcd"), None));
+
+        let chunks = || {
+            StreamBody::new(futures_util::stream::iter(
+                ["ab", "cd"].map(|chunk| Ok::<_, io::Error>(Frame::data(Bytes::from(chunk)))),
+            ))
+        };
+        // 大小未知的body不能提前判断
+        assert!(!exceeds(&chunks(), Some(3)));
+        let result = LimitedBody::new(chunks(), 3).collect().await;
+        assert!(result.is_err_and(|e| BodyTooLarge::find(&e)));
+        let result = LimitedBody::new(chunks(), 4).collect().await;
+        assert_eq!(result.ok().map(|body| body.to_bytes()), Some(Bytes::from("abcd")));
+        assert!(!BodyTooLarge::find(&io::Error::other("other")));
+    }
+}
@@ -0,0 +1,214 @@
+//! 静态文件的缓存规则：按路径glob或者扩展名设置Cache-Control和Expires，以及条件请求（If-None-Match、If-Modified-Since）的判断
+
+use std::time::{Duration, SystemTime};
+
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use http::HeaderMap;
+use httpdate::parse_http_date;
+use serde::{Deserialize, Serialize};
+
+/// 一条缓存规则
+///
+/// pattern包含`/`时匹配location下的请求路径，例如`/assets/**`；否则匹配文件名，例如`*.html`，`.css`等价于`*.css`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CacheRuleConfig {
+    pub(crate) pattern: String,
+    pub(crate) cache_control: String,
+    /// 是否根据Cache-Control的max-age设置Expires，用于不支持Cache-Control的旧客户端
+    #[serde(default)]
+    pub(crate) expires: bool,
+}
+
+impl CacheRuleConfig {
+    /// 解析命令行参数，形如 `*.html=no-cache`
+    pub(crate) fn parse_arg(arg: &str, expires: bool) -> Result<Self, String> {
+        let (pattern, cache_control) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("cache rule should be like '*.html=no-cache': {}", arg))?;
+        Ok(CacheRuleConfig {
+            pattern: pattern.trim().to_owned(),
+            cache_control: cache_control.trim().to_owned(),
+            expires,
+        })
+    }
+}
+
+struct CacheRule {
+    matcher: GlobMatcher,
+    match_path: bool,
+    cache_control: String,
+    max_age: Option<Duration>,
+    expires: bool,
+}
+
+/// 按顺序匹配的缓存规则，第一条匹配的生效
+#[derive(Default)]
+pub(crate) struct CacheRules {
+    rules: Vec<CacheRule>,
+}
+
+/// 响应的缓存头
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CacheHeaders<'a> {
+    pub(crate) cache_control: &'a str,
+    pub(crate) expires: Option<SystemTime>,
+}
+
+impl CacheRules {
+    pub(crate) fn new(configs: &[CacheRuleConfig]) -> Result<Self, String> {
+        let mut rules = Vec::with_capacity(configs.len());
+        for config in configs {
+            let match_path = config.pattern.contains('/');
+            let pattern = match config.pattern.strip_prefix('.') {
+                Some(extension) if !match_path => format!("*.{}", extension),
+                _ => config.pattern.clone(),
+            };
+            let glob = if match_path {
+                GlobBuilder::new(&pattern).literal_separator(true).build()
+            } else {
+                Glob::new(&pattern)
+            }
+            .map_err(|e| format!("wrong cache rule pattern {}: {}", config.pattern, e))?;
+            rules.push(CacheRule {
+                matcher: glob.compile_matcher(),
+                match_path,
+                max_age: parse_max_age(&config.cache_control),
+                cache_control: config.cache_control.clone(),
+                expires: config.expires,
+            });
+        }
+        Ok(CacheRules { rules })
+    }
+
+    /// url_path为location下的请求路径，file_name为实际返回的文件名（例如目录的index.html）
+    pub(crate) fn find(&self, url_path: &str, file_name: &str, now: SystemTime) -> Option<CacheHeaders<'_>> {
+        let rule = self.rules.iter().find(|rule| {
+            if rule.match_path {
+                rule.matcher.is_match(url_path)
+            } else {
+                rule.matcher.is_match(file_name)
+            }
+        })?;
+        Some(CacheHeaders {
+            cache_control: &rule.cache_control,
+            expires: rule.max_age.filter(|_| rule.expires).map(|max_age| now + max_age),
+        })
+    }
+}
+
+/// Cache-Control中的max-age，no-store和no-cache视为0
+fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    let mut max_age = None;
+    for directive in cache_control.split(',').map(str::trim) {
+        if directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("no-cache") {
+            return Some(Duration::ZERO);
+        }
+        if let Some((name, value)) = directive.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("max-age") {
+                max_age = value.trim().trim_matches('"').parse().ok().map(Duration::from_secs);
+            }
+        }
+    }
+    max_age
+}
+
+/// 条件GET是否可以返回304（RFC 9110 13.1.2、13.1.3）
+///
+/// 有If-None-Match时忽略If-Modified-Since。If-None-Match可以是`*`或者逗号分隔的列表，使用弱比较
+pub(crate) fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    if let Some(if_none_match) = headers.get(http::header::IF_NONE_MATCH) {
+        return if_none_match
+            .to_str()
+            .is_ok_and(|if_none_match| etag_list_matches(if_none_match, etag));
+    }
+    headers
+        .get(http::header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_http_date(value).ok())
+        // HTTP日期精确到秒
+        .is_some_and(|since| truncate_to_secs(last_modified) <= since)
+}
+
+fn etag_list_matches(if_none_match: &str, etag: &str) -> bool {
+    let if_none_match = if_none_match.trim();
+    if if_none_match == "*" {
+        return true;
+    }
+    let etag = opaque_tag(etag);
+    if_none_match
+        .split(',')
+        .map(|candidate| opaque_tag(candidate.trim()))
+        .any(|candidate| candidate == etag)
+}
+
+/// 弱比较只比较去掉W/之后的部分
+fn opaque_tag(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn rule(pattern: &str, cache_control: &str, expires: bool) -> CacheRuleConfig {
+        CacheRuleConfig {
+            pattern: pattern.to_owned(),
+            cache_control: cache_control.to_owned(),
+            expires,
+        }
+    }
+
+    #[test]
+    fn test_find() -> Result<(), String> {
+        let rules = CacheRules::new(&[
+            rule("/assets/*.js", "public, max-age=31536000, immutable", true),
+            rule("*.html", "no-cache", false),
+            rule(".css", "max-age=3600", false),
+        ])?;
+        let now = SystemTime::UNIX_EPOCH;
+        assert_eq!(
+            rules.find("/assets/app.3f2a.js", "app.3f2a.js", now),
+            Some(CacheHeaders {
+                cache_control: "public, max-age=31536000, immutable",
+                expires: Some(now + Duration::from_secs(31536000)),
+            })
+        );
+        // literal_separator：*不匹配/
+        assert_eq!(rules.find("/assets/vendor/app.js", "app.js", now), None);
+        assert_eq!(rules.find("/", "index.html", now).map(|h| h.cache_control), Some("no-cache"));
+        assert_eq!(rules.find("/a/b/c.css", "c.css", now).map(|h| h.cache_control), Some("max-age=3600"));
+        assert!(CacheRules::new(&[rule("/a/[", "no-cache", false)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let last_modified = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let etag = "\"18bcfe56834-10\"";
+        let headers = |name, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+        let if_none_match = |value| headers(http::header::IF_NONE_MATCH, value);
+        assert!(is_not_modified(&if_none_match("\"18bcfe56834-10\""), etag, last_modified));
+        assert!(is_not_modified(&if_none_match("\"other\", W/\"18bcfe56834-10\""), etag, last_modified));
+        assert!(is_not_modified(&if_none_match("*"), etag, last_modified));
+        assert!(!is_not_modified(&if_none_match("\"other\""), etag, last_modified));
+
+        let if_modified_since = |value| headers(http::header::IF_MODIFIED_SINCE, value);
+        assert!(is_not_modified(&if_modified_since("Tue, 14 Nov 2023 22:13:20 GMT"), etag, last_modified));
+        assert!(is_not_modified(&if_modified_since("Wed, 15 Nov 2023 00:00:00 GMT"), etag, last_modified));
+        assert!(!is_not_modified(&if_modified_since("Tue, 14 Nov 2023 22:13:19 GMT"), etag, last_modified));
+        assert!(!is_not_modified(&if_modified_since("invalid"), etag, last_modified));
+    }
+}
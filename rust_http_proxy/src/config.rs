@@ -1,12 +1,27 @@
 use base64::engine::general_purpose;
 use base64::Engine;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use http::Uri;
 use log::{info, warn};
 use log_x::init_log;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
 
-use crate::reverse::LocationConfig;
+use crate::access_log::AccessLogFormat;
+use crate::autoindex::AutoIndexConfig;
+use crate::cache_rule::CacheRuleConfig;
+use crate::dns::{self, DnsConfig, DnsServer, IpPreference};
+use crate::encoding::DEFAULT_COMPRESSIBLE_TYPES;
+use crate::error_page::ErrorPages;
+use crate::forwarded::TrustedProxies;
+use crate::hotlink::{HotlinkAction, HotlinkProtection};
+use crate::pool::PoolConfig;
+use crate::proxy_protocol::ProxyProtocolVersion;
+use crate::retry::RetryPolicy;
+use crate::reverse::{ErrorPagesConfig, LocationConfig, LocationEntry, StaticLocationConfig};
+use crate::signed_url::{self, SignedUrls};
+use crate::webdav::WebDavConfig;
 use crate::{DynError, IDLE_TIMEOUT};
 
 pub(crate) const DEFAULT_HOST: &str = "default_host";
@@ -26,6 +41,21 @@ pub struct Param {
     log_dir: String,
     #[arg(long, value_name = "LOG_FILE", default_value = "proxy.log")]
     log_file: String,
+    #[arg(
+        long,
+        value_name = "ACCESS_LOG_FILE",
+        default_value = "access.log",
+        help = "访问日志文件，位于LOG_DIR下"
+    )]
+    access_log_file: String,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "json",
+        help = "访问日志格式"
+    )]
+    access_log_format: AccessLogFormat,
     #[arg(
         short,
         long,
@@ -58,12 +88,106 @@ pub struct Param {
         short,
         long,
         value_name = "REFERER",
-        help = "Http Referer请求头处理 \n\
-        1. 图片资源的防盗链：针对png/jpeg/jpg等文件的请求，要求Request的Referer header要么为空，要么包含配置的值\n\
-        2. 外链访问监控：如果Referer不包含配置的值，并且访问html资源时，Prometheus counter req_from_out++，用于外链访问监控\n\
-        可以多次指定，也可以不指定"
+        help = "外链访问监控：如果Referer不包含配置的值，并且访问html资源时，Prometheus counter req_from_out++\n\
+        可以多次指定，也可以不指定。防盗链见--hotlink-allow-host"
     )]
     referer_keywords_to_self: Vec<String>,
+    #[arg(
+        long,
+        value_name = "HOST",
+        help = "防盗链：受保护的文件要求Referer的host与配置的值完全相同（不区分大小写），*.example.com匹配子域名\n\
+        可以多次指定，不指定则不开启防盗链"
+    )]
+    hotlink_allow_host: Vec<String>,
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        help = "防盗链保护的文件，PATTERN包含/时匹配请求路径，否则匹配文件名，.png等价于*.png\n\
+        可以多次指定，默认为.png .jpeg .jpg"
+    )]
+    hotlink_pattern: Vec<String>,
+    #[arg(long, help = "防盗链：拒绝没有Referer的请求，默认允许")]
+    hotlink_deny_empty_referer: bool,
+    #[arg(
+        long,
+        value_name = "ACTION",
+        default_value = "403",
+        help = "防盗链拦截时的响应：403、404，或者302重定向到占位图片，例如 /hotlink.png"
+    )]
+    hotlink_action: HotlinkAction,
+    #[arg(
+        long,
+        value_name = "PATH_PREFIX",
+        help = "该路径前缀下的静态文件需要带有效的签名参数才能访问，可以多次指定\n\
+        例如：--signed-url-prefix=/downloads/\n\
+        签名链接使用sign-url子命令生成"
+    )]
+    signed_url_prefix: Vec<String>,
+    #[arg(long, value_name = "SECRET", global = true, help = "签名链接的HMAC密钥")]
+    signed_url_secret: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH_PREFIX",
+        help = "开启上传：该路径前缀下支持PUT、DELETE、MKCOL和WebDAV的PROPFIND、COPY、MOVE，文件位于web_content_path下\n\
+        例如：--webdav-prefix=/dav/"
+    )]
+    webdav_prefix: Option<String>,
+    #[arg(
+        long,
+        value_name = "USER",
+        help = "上传的鉴权用户，格式为 'username:password'，可以多次指定"
+    )]
+    webdav_user: Vec<String>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "104857600",
+        help = "上传文件的最大字节数，超过返回413"
+    )]
+    webdav_max_size: u64,
+    #[arg(
+        long,
+        value_name = "PATH_PREFIX",
+        help = "对该路径前缀下的目录开启目录列表（autoindex），目录下没有index.html时列出目录内容\n\
+        例如：--autoindex=/downloads/\n\
+        可以多次指定"
+    )]
+    autoindex: Vec<String>,
+    #[arg(long, help = "目录列表中显示以.开头的文件")]
+    autoindex_show_hidden: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "单页应用的history API回退：浏览器导航请求的路径不存在时返回该文件（相对于web_content_path）\n\
+        例如：--spa-fallback=/index.html"
+    )]
+    spa_fallback: Option<String>,
+    #[arg(
+        long,
+        value_name = "CODE=PATH",
+        help = "自定义错误页面（相对于web_content_path），可以多次指定\n\
+        例如：--error-page=404=/404.html --error-page=502=/50x.html\n\
+        没有配置时403/404/500/502/504使用内置页面"
+    )]
+    error_page: Vec<String>,
+    #[arg(
+        long,
+        value_name = "PATTERN=CACHE_CONTROL",
+        help = "静态文件的缓存规则，按顺序匹配，第一条匹配的生效，可以多次指定\n\
+        PATTERN包含/时匹配请求路径，否则匹配文件名，.css等价于*.css\n\
+        例如：--cache-rule='/assets/**=public, max-age=31536000, immutable' --cache-rule='*.html=no-cache'"
+    )]
+    cache_rule: Vec<String>,
+    #[arg(long, help = "根据缓存规则中的max-age设置Expires响应头")]
+    cache_expires: bool,
+    #[arg(
+        long,
+        value_name = "CONTENT_TYPE_PREFIX",
+        help = "可以动态压缩（br/zstd/gzip）的Content-Type前缀，可以多次指定\n\
+        默认为text/html text/css application/javascript application/json text/xml application/xml text/plain text/markdown\n\
+        存在预压缩文件（file.br、file.zst、file.gz）时不受此限制"
+    )]
+    compress_type: Vec<String>,
     #[arg(
         long,
         help = "if enable, never send '407 Proxy Authentication Required' to client。\n\
@@ -86,57 +210,385 @@ pub struct Param {
         则访问 https://your_domain/https://cdnjs.cloudflare.com 会被代理到 https://cdnjs.cloudflare.com"
     )]
     append_upstream_url: Vec<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "8",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "正向代理连接池中每个目标（host:port）最多保留的空闲连接数，至少为1"
+    )]
+    forward_pool_max_idle_per_target: usize,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "512",
+        help = "正向代理连接池中总共最多保留的空闲连接数"
+    )]
+    forward_pool_max_idle: usize,
+    #[arg(
+        long,
+        help = "正向代理连接池在同一用户的不同客户端IP之间共享连接\n\
+        共享的连接的流量和proxy_conn日志记录在建立连接的客户端上"
+    )]
+    forward_pool_share_across_clients: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1",
+        help = "正向代理幂等请求（GET HEAD OPTIONS PUT DELETE）的总尝试次数，上游连接失败或超时时重试，1表示不重试"
+    )]
+    forward_retry_attempts: u32,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "100",
+        help = "正向代理第一次重试前等待的毫秒数，之后每次翻倍"
+    )]
+    forward_retry_backoff_ms: u64,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "0",
+        help = "正向代理每次尝试等待响应头的毫秒数，0表示不限制"
+    )]
+    forward_retry_timeout_ms: u64,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "65536",
+        help = "正向代理请求体不超过该大小时缓存下来用于重试，否则不重试"
+    )]
+    forward_retry_max_body: u64,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "0",
+        help = "正向代理和反向代理的请求体大小上限，超过时返回413，0表示不限制\n\
+        反向代理的location可以通过max_request_body单独配置"
+    )]
+    max_request_body: u64,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "受信任的代理（例如前面的负载均衡），形如10.0.0.0/8，可以多次指定\n\
+        来自这些地址的请求从X-Forwarded-For或Forwarded中取真实的客户端IP，并保留其X-Forwarded-*"
+    )]
+    trusted_proxy: Vec<String>,
+    #[arg(
+        long,
+        help = "正向代理也添加Via和X-Forwarded-For请求头。默认不添加，以免暴露代理（高匿）"
+    )]
+    forward_proxy_headers: bool,
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "在这些端口上接受PROXY protocol v1/v2头部，端口也需要通过--port监听，可以多次指定\n\
+        只解析来自--proxy-protocol-trusted的连接的头部，其他连接按普通的HTTP连接处理"
+    )]
+    proxy_protocol_port: Vec<u16>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "允许发送PROXY protocol头部的地址（例如前面的L4负载均衡），形如10.0.0.0/8，可以多次指定"
+    )]
+    proxy_protocol_trusted: Vec<String>,
+    #[arg(
+        long,
+        value_name = "VERSION",
+        help = "连接CONNECT请求的目标后，先发送PROXY protocol头部，告知目标真实的客户端地址"
+    )]
+    connect_proxy_protocol: Option<ProxyProtocolVersion>,
+    #[arg(
+        long,
+        value_name = "SERVER",
+        help = "解析上游域名的DNS服务器，按顺序查询，可以多次指定。不指定时使用系统的解析\n\
+        形如 8.8.8.8、udp://8.8.8.8:53、tcp://8.8.8.8、tls://1.1.1.1#cloudflare-dns.com、https://dns.google/dns-query"
+    )]
+    dns_server: Vec<String>,
+    #[arg(
+        long,
+        value_name = "HOST=IP",
+        help = "静态解析，形如example.com=10.0.0.1，优先于DNS服务器，同一个域名可以指定多次"
+    )]
+    dns_host: Vec<String>,
+    #[arg(
+        long,
+        value_name = "PREFERENCE",
+        value_enum,
+        default_value = "ipv6",
+        help = "地址族偏好：查询哪些记录，以及先连接哪个地址族"
+    )]
+    dns_prefer: IpPreference,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1024",
+        help = "按TTL缓存的域名数量，0表示不缓存。只缓存--dns-server的结果"
+    )]
+    dns_cache_size: usize,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "5000",
+        help = "每个DNS服务器的查询超时，超时后查询下一个"
+    )]
+    dns_timeout_ms: u64,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "250",
+        help = "Happy Eyeballs（RFC 8305）：上一个地址在该时间内没有连上时，同时连接下一个地址"
+    )]
+    happy_eyeballs_delay_ms: u64,
+    #[arg(
+        long,
+        value_name = "KEYWORD",
+        help = "访问统计（/stats）和req_from_out中识别的来源，Referer的host包含该关键字时计为该来源，可以多次指定\n\
+        默认为google baidu bing yandex v2ex github stackoverflow duckduckgo"
+    )]
+    analytics_search_engine: Vec<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "10000",
+        help = "访问统计中每天最多记录的路径、来源和独立访客数量，超过后路径和来源计入other"
+    )]
+    analytics_max_keys: usize,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "10000",
+        help = "proxy_traffic等带有客户端IP的指标，每个指标最多保留的label组合数量，超过后计入label值为other的series"
+    )]
+    metrics_max_series: usize,
+    #[arg(
+        long,
+        value_name = "HOURS",
+        default_value = "24",
+        help = "proxy_traffic等带有客户端IP的指标，空闲超过该时长的series会被删除"
+    )]
+    metrics_idle_hours: u64,
+    #[cfg(feature = "otel")]
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "OTLP/HTTP collector的traces地址，例如 http://127.0.0.1:4318/v1/traces\n\
+        不指定则不生成trace"
+    )]
+    otlp_endpoint: Option<String>,
+    #[cfg(feature = "otel")]
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "rust_http_proxy",
+        help = "上报trace时的service.name"
+    )]
+    otel_service_name: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 生成带过期时间的签名下载链接，需要指定与服务端相同的--signed-url-secret
+    SignUrl {
+        /// 文件的请求路径，例如 /downloads/app.tar.gz
+        #[arg(value_name = "PATH")]
+        path: String,
+        /// 有效期，例如 3600、30m、12h、7d
+        #[arg(long, value_name = "TTL", default_value = "1h")]
+        ttl: String,
+        /// 只允许该客户端IP访问
+        #[arg(long, value_name = "IP")]
+        ip: Option<IpAddr>,
+        /// 链接的前缀，例如 https://example.com
+        #[arg(long, value_name = "BASE_URL", default_value = "")]
+        base_url: String,
+    },
+}
+
+impl Command {
+    fn run(self, param: &Param) -> Result<(), DynError> {
+        match self {
+            Command::SignUrl {
+                path,
+                ttl,
+                ip,
+                base_url,
+            } => {
+                let secret = param
+                    .signed_url_secret
+                    .clone()
+                    .ok_or("--signed-url-secret is required to sign url")?;
+                if !path.starts_with('/') {
+                    return Err(format!("path should start with '/': {}", path).into());
+                }
+                let signed_urls = SignedUrls::new(Some(secret), vec![])?;
+                let expires = SystemTime::now()
+                    .checked_add(signed_url::parse_ttl(&ttl)?)
+                    .ok_or_else(|| format!("ttl {} is too large", ttl))?;
+                println!("{}{}", base_url.trim_end_matches('/'), signed_urls.signed_url(&path, expires, ip));
+                Ok(())
+            }
+        }
+    }
 }
 
 pub(crate) struct Config {
     pub(crate) cert: String,
     pub(crate) key: String,
     pub(crate) basic_auth: HashMap<String, String>,
-    pub(crate) web_content_path: String,
+    pub(crate) web_content: StaticLocationConfig,
+    pub(crate) error_pages: ErrorPages,
     pub(crate) referer_keywords_to_self: Vec<String>,
+    pub(crate) hotlink: HotlinkProtection,
+    pub(crate) signed_urls: SignedUrls,
+    pub(crate) webdav: Option<WebDavConfig>,
+    pub(crate) compressible_types: Vec<String>,
     pub(crate) never_ask_for_auth: bool,
     pub(crate) over_tls: bool,
     #[allow(dead_code)]
     pub(crate) hostname: String,
     pub(crate) port: Vec<u16>,
     pub(crate) reverse_proxy_config: ReverseProxyConfig,
+    pub(crate) forward_pool: PoolConfig,
+    pub(crate) forward_retry: RetryPolicy,
+    pub(crate) max_request_body: Option<u64>,
+    pub(crate) trusted_proxies: TrustedProxies,
+    pub(crate) forward_proxy_headers: bool,
+    pub(crate) proxy_protocol_ports: Vec<u16>,
+    pub(crate) proxy_protocol_trusted: TrustedProxies,
+    pub(crate) connect_proxy_protocol: Option<ProxyProtocolVersion>,
+    pub(crate) dns: DnsConfig,
+    pub(crate) access_log_format: AccessLogFormat,
+    pub(crate) analytics_search_engines: Vec<String>,
+    pub(crate) analytics_max_keys: usize,
+    pub(crate) metrics_max_series: usize,
+    pub(crate) metrics_idle_ttl: Duration,
+    #[cfg(feature = "otel")]
+    pub(crate) otlp_endpoint: Option<String>,
+    #[cfg(feature = "otel")]
+    pub(crate) otel_service_name: String,
 }
 
 impl TryFrom<Param> for Config {
     type Error = DynError;
     fn try_from(mut param: Param) -> Result<Self, Self::Error> {
-        let mut basic_auth = HashMap::new();
-        for raw_user in param.users {
-            let mut user = raw_user.split(':');
-            let username = user.next().unwrap_or("").to_string();
-            let password = user.next().unwrap_or("").to_string();
-            if !username.is_empty() && !password.is_empty() {
-                let base64 = general_purpose::STANDARD.encode(raw_user);
-                basic_auth.insert(format!("Basic {}", base64), username);
-            }
-        }
+        let basic_auth = parse_users(param.users);
+        let webdav = match param.webdav_prefix {
+            Some(prefix) => Some(WebDavConfig::new(
+                prefix,
+                &param.web_content_path,
+                parse_users(param.webdav_user),
+                param.webdav_max_size,
+            )?),
+            None => None,
+        };
         let reverse_proxy_config = parse_reverse_proxy_config(
+            &param.web_content_path,
             &param.reverse_proxy_config_file,
             &mut param.append_upstream_url,
             param.enable_github_proxy,
         )?;
+        if let Some(port) = param.proxy_protocol_port.iter().find(|port| !param.port.contains(port)) {
+            return Err(format!("proxy protocol port {} is not listened by --port", port).into());
+        }
         Ok(Config {
             cert: param.cert,
             key: param.key,
             basic_auth,
-            web_content_path: param.web_content_path,
+            error_pages: ErrorPages::parse_args(&param.web_content_path, &param.error_page)?,
+            web_content: StaticLocationConfig::web_content(
+                param.web_content_path,
+                AutoIndexConfig::new(param.autoindex, param.autoindex_show_hidden),
+                param.spa_fallback,
+                param
+                    .cache_rule
+                    .iter()
+                    .map(|arg| CacheRuleConfig::parse_arg(arg, param.cache_expires))
+                    .collect::<Result<_, _>>()?,
+            )?,
             referer_keywords_to_self: param.referer_keywords_to_self,
+            hotlink: HotlinkProtection::new(
+                &param.hotlink_pattern,
+                &param.hotlink_allow_host,
+                !param.hotlink_deny_empty_referer,
+                param.hotlink_action,
+            )?,
+            signed_urls: SignedUrls::new(param.signed_url_secret, param.signed_url_prefix)?,
+            webdav,
+            compressible_types: if param.compress_type.is_empty() {
+                DEFAULT_COMPRESSIBLE_TYPES.iter().map(|s| s.to_string()).collect()
+            } else {
+                param.compress_type
+            },
             never_ask_for_auth: param.never_ask_for_auth,
             over_tls: param.over_tls,
             hostname: param.hostname,
             port: param.port,
             reverse_proxy_config,
+            forward_pool: PoolConfig {
+                max_idle_per_target: param.forward_pool_max_idle_per_target,
+                max_idle: param.forward_pool_max_idle,
+                share_across_clients: param.forward_pool_share_across_clients,
+            },
+            forward_retry: RetryPolicy {
+                attempts: param.forward_retry_attempts.max(1),
+                backoff_ms: param.forward_retry_backoff_ms,
+                per_try_timeout_ms: param.forward_retry_timeout_ms,
+                max_replay_body: param.forward_retry_max_body,
+            },
+            max_request_body: (param.max_request_body > 0).then_some(param.max_request_body),
+            trusted_proxies: TrustedProxies::parse_args(&param.trusted_proxy)?,
+            forward_proxy_headers: param.forward_proxy_headers,
+            proxy_protocol_ports: param.proxy_protocol_port,
+            proxy_protocol_trusted: TrustedProxies::parse_args(&param.proxy_protocol_trusted)?,
+            connect_proxy_protocol: param.connect_proxy_protocol,
+            dns: DnsConfig {
+                servers: param
+                    .dns_server
+                    .iter()
+                    .map(|arg| DnsServer::parse(arg))
+                    .collect::<Result<_, _>>()?,
+                hosts: dns::parse_hosts(&param.dns_host)?,
+                prefer: param.dns_prefer,
+                cache_size: param.dns_cache_size,
+                timeout: Duration::from_millis(param.dns_timeout_ms),
+                happy_eyeballs_delay: Duration::from_millis(param.happy_eyeballs_delay_ms),
+            },
+            access_log_format: param.access_log_format,
+            analytics_search_engines: param.analytics_search_engine,
+            analytics_max_keys: param.analytics_max_keys,
+            metrics_max_series: param.metrics_max_series,
+            metrics_idle_ttl: Duration::from_secs(param.metrics_idle_hours * 60 * 60),
+            #[cfg(feature = "otel")]
+            otlp_endpoint: param.otlp_endpoint,
+            #[cfg(feature = "otel")]
+            otel_service_name: param.otel_service_name,
         })
     }
 }
 
+/// 'username:password'格式的用户，key为Authorization header的值
+fn parse_users(users: Vec<String>) -> HashMap<String, String> {
+    let mut basic_auth = HashMap::new();
+    for raw_user in users {
+        let mut user = raw_user.split(':');
+        let username = user.next().unwrap_or("").to_string();
+        let password = user.next().unwrap_or("").to_string();
+        if !username.is_empty() && !password.is_empty() {
+            let base64 = general_purpose::STANDARD.encode(raw_user);
+            basic_auth.insert(format!("Basic {}", base64), username);
+        }
+    }
+    basic_auth
+}
+
 pub(crate) struct ReverseProxyConfig {
     pub(crate) locations: HashMap<String, Vec<LocationConfig>>,
+    /// 与locations来自同一个配置文件，一个host出现在配置文件中时，两者都有这个host
+    pub(crate) static_locations: HashMap<String, Vec<StaticLocationConfig>>,
+    pub(crate) error_pages: HashMap<String, ErrorPages>,
     pub(crate) redirect_bachpaths: Vec<RedirectBackpaths>,
 }
 
@@ -150,12 +602,32 @@ fn truncate_string(s: &str, n: usize) -> &str {
 }
 
 fn parse_reverse_proxy_config(
-    reverse_proxy_config_file: &Option<String>, append_upstream_url: &mut Vec<String>, enable_github_proxy: bool,
+    web_content_path: &str, reverse_proxy_config_file: &Option<String>, append_upstream_url: &mut Vec<String>,
+    enable_github_proxy: bool,
 ) -> Result<ReverseProxyConfig, <Config as TryFrom<Param>>::Error> {
-    let mut locations: HashMap<String, Vec<LocationConfig>> = match reverse_proxy_config_file {
+    let entries: HashMap<String, Vec<LocationEntry>> = match reverse_proxy_config_file {
         Some(path) => serde_yaml::from_str(&std::fs::read_to_string(path)?)?,
         None => HashMap::new(),
     };
+    let SplitLocations {
+        mut locations,
+        mut static_locations,
+        error_pages,
+    } = split_location_entries(entries);
+    let error_pages = error_pages
+        .into_iter()
+        .map(|(host, configs)| {
+            let mut pages = HashMap::new();
+            let mut root = web_content_path.to_owned();
+            for config in configs {
+                pages.extend(config.error_pages);
+                if let Some(config_root) = config.root {
+                    root = config_root;
+                }
+            }
+            ErrorPages::new(&root, pages).map(|error_pages| (host, error_pages))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
     if enable_github_proxy {
         GITHUB_URL_BASE.iter().for_each(|domain| {
             append_upstream_url.push((*domain).to_owned());
@@ -187,6 +659,12 @@ fn parse_reverse_proxy_config(
                                 url_base: (*upstream_url_base).to_owned() + path,
                                 version: crate::reverse::Version::Auto,
                             },
+                            failover: vec![],
+                            retry: RetryPolicy::default(),
+                            timeouts: Default::default(),
+                            health: Default::default(),
+                            max_request_body: None,
+                            proxy_protocol: None,
                         });
                     }
                     Err(err) => {
@@ -239,6 +717,12 @@ fn parse_reverse_proxy_config(
             }
         }
     }
+    for static_location_config in static_locations.values_mut().flatten() {
+        static_location_config.normalize()?;
+    }
+    static_locations
+        .iter_mut()
+        .for_each(|(_, static_location_configs)| static_location_configs.sort());
     let mut redirect_bachpaths = Vec::<RedirectBackpaths>::new();
     for (host, locations) in &locations {
         for location in locations {
@@ -255,22 +739,52 @@ fn parse_reverse_proxy_config(
     }
     Ok(ReverseProxyConfig {
         locations,
+        static_locations,
+        error_pages,
         redirect_bachpaths,
     })
 }
 
+struct SplitLocations {
+    locations: HashMap<String, Vec<LocationConfig>>,
+    static_locations: HashMap<String, Vec<StaticLocationConfig>>,
+    error_pages: HashMap<String, Vec<ErrorPagesConfig>>,
+}
+
+/// 按类型拆分配置文件中的location
+fn split_location_entries(entries: HashMap<String, Vec<LocationEntry>>) -> SplitLocations {
+    let mut split = SplitLocations {
+        locations: HashMap::new(),
+        static_locations: HashMap::new(),
+        error_pages: HashMap::new(),
+    };
+    for (host, host_entries) in entries {
+        let mut proxies = vec![];
+        let mut statics = vec![];
+        let mut error_pages = vec![];
+        for entry in host_entries {
+            match entry {
+                LocationEntry::Proxy(location_config) => proxies.push(location_config),
+                LocationEntry::Static(static_location_config) => statics.push(static_location_config),
+                LocationEntry::ErrorPages(error_pages_config) => error_pages.push(error_pages_config),
+            }
+        }
+        split.locations.insert(host.clone(), proxies);
+        split.static_locations.insert(host.clone(), statics);
+        if !error_pages.is_empty() {
+            split.error_pages.insert(host, error_pages);
+        }
+    }
+    split
+}
+
 pub(crate) struct RedirectBackpaths {
     pub(crate) redirect_url: String,
     pub(crate) host: String,
     pub(crate) location: String,
 }
 
-pub(crate) fn load_config() -> Result<Config, DynError> {
-    let mut param = Param::parse();
-    param.hostname = get_hostname();
-    if let Err(log_init_error) = init_log(&param.log_dir, &param.log_file) {
-        return Err(format!("init log error:{}", log_init_error).into());
-    }
+pub(crate) fn install_crypto_provider() {
     #[cfg(all(feature = "ring", not(feature = "aws_lc_rs")))]
     {
         info!("use ring as default crypto provider");
@@ -281,6 +795,20 @@ pub(crate) fn load_config() -> Result<Config, DynError> {
         info!("use aws_lc_rs as default crypto provider");
         let _ = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider().install_default();
     }
+}
+
+pub(crate) fn load_config() -> Result<Config, DynError> {
+    let mut param = Param::parse();
+    if let Some(command) = param.command.take() {
+        // 子命令执行完直接退出，不启动服务
+        command.run(&param)?;
+        std::process::exit(0);
+    }
+    param.hostname = get_hostname();
+    if let Err(log_init_error) = init_log(&param.log_dir, &param.log_file, &param.access_log_file) {
+        return Err(format!("init log error:{}", log_init_error).into());
+    }
+    install_crypto_provider();
     info!("hostname seems to be {}", param.hostname);
     let config = Config::try_from(param)?;
     log_config(&config);
@@ -292,9 +820,18 @@ fn log_config(config: &Config) {
     if !config.basic_auth.is_empty() && !config.never_ask_for_auth {
         warn!("do not serve web content to avoid being detected!");
     } else {
-        info!("serve web content of \"{}\"", config.web_content_path);
+        info!("serve web content of \"{}\"", config.web_content.root);
         if !config.referer_keywords_to_self.is_empty() {
-            info!("Referer header to images must contain {:?}", config.referer_keywords_to_self);
+            info!("Referer keywords of self: {:?}", config.referer_keywords_to_self);
+        }
+        if !config.hotlink.allow_hosts().is_empty() {
+            info!("hotlink protection allows Referer hosts {:?}", config.hotlink.allow_hosts());
+        }
+        if !config.signed_urls.prefixes().is_empty() {
+            info!("signed url is required for {:?}", config.signed_urls.prefixes());
+        }
+        if let Some(webdav) = &config.webdav {
+            info!("webdav upload is enabled for {}", webdav.prefix());
         }
     }
     info!("basic auth is {:?}", config.basic_auth);
@@ -314,6 +851,15 @@ fn log_config(config: &Config) {
                 );
             }
         });
+    config
+        .reverse_proxy_config
+        .static_locations
+        .iter()
+        .for_each(|(host, static_locations)| {
+            for ele in static_locations {
+                info!("    {:<70} -> {}", format!("http(s)://{}:port{}**", host, ele.location), ele.root);
+            }
+        });
 }
 
 #[cfg(unix)]
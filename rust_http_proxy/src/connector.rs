@@ -0,0 +1,174 @@
+//! 连接上游：将DNS解析和TCP建连分开，分别记录耗时；有多个地址时使用Happy Eyeballs（RFC 8305）
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+
+use http::Uri;
+use hyper_util::{
+    client::legacy::connect::{dns::Name, HttpConnector},
+    rt::TokioIo,
+};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+use crate::{
+    dns::Resolver,
+    latency::{LatencyMetrics, FORWARD_HOST},
+    proxy_protocol::{self, ProxyProtocolVersion},
+};
+
+/// 连接正向代理的目标地址，target的格式为host:port
+pub(crate) async fn connect(
+    target: &str, kind: &'static str, resolver: &Resolver, latency: &LatencyMetrics,
+) -> io::Result<TcpStream> {
+    let addrs = match target.parse::<SocketAddr>() {
+        Ok(addr) => vec![addr],
+        Err(_) => {
+            let start = Instant::now();
+            let result = resolver.resolve(target).await;
+            latency.observe_dns(kind, FORWARD_HOST, start.elapsed(), result.is_ok());
+            result?
+        }
+    };
+    let start = Instant::now();
+    let result = happy_eyeballs(&addrs, resolver.happy_eyeballs_delay()).await;
+    latency.observe_connect(kind, start.elapsed(), result.is_ok());
+    result
+}
+
+/// 按顺序连接addrs：上一个连接delay内没有结果或者失败时发起下一个，使用最先建立的连接
+async fn happy_eyeballs(addrs: &[SocketAddr], delay: Duration) -> io::Result<TcpStream> {
+    let mut remaining = addrs.iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect");
+    attempts.extend(remaining.next().map(|addr| TcpStream::connect(*addr)));
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    last_error = e;
+                    attempts.extend(remaining.next().map(|addr| TcpStream::connect(*addr)));
+                }
+            },
+            _ = tokio::time::sleep(delay), if remaining.len() > 0 => {
+                attempts.extend(remaining.next().map(|addr| TcpStream::connect(*addr)));
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// 反向代理使用的DNS resolver，记录解析耗时。上游域名来自配置文件，可以作为label
+#[derive(Clone)]
+pub(crate) struct TimedResolver {
+    resolver: Resolver,
+    latency: LatencyMetrics,
+}
+
+impl TimedResolver {
+    pub(crate) fn new(resolver: Resolver, latency: LatencyMetrics) -> Self {
+        TimedResolver { resolver, latency }
+    }
+}
+
+impl Service<Name> for TimedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let latency = self.latency.clone();
+        let host = name.as_str().to_owned();
+        let resolver = self.resolver.clone();
+        #[cfg(feature = "otel")]
+        let dns_trace = crate::otel::start_dns_span(&host);
+        Box::pin(async move {
+            let start = Instant::now();
+            // 端口由HttpConnector设置
+            let result = resolver.lookup(&host).await.map(|addrs| {
+                addrs
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, 0))
+                    .collect::<Vec<_>>()
+                    .into_iter()
+            });
+            latency.observe_dns("reverse", &host, start.elapsed(), result.is_ok());
+            #[cfg(feature = "otel")]
+            if let Some(cx) = dns_trace {
+                crate::otel::end_dns_span(cx, result.is_ok());
+            }
+            result
+        })
+    }
+}
+
+/// 反向代理的location配置了proxy_protocol时使用：建连后先写入PROXY protocol头部
+///
+/// 头部中带有客户端地址，所以每个请求使用单独的connector，连接不复用
+#[derive(Clone)]
+pub(crate) struct ProxyProtocolConnector {
+    inner: HttpConnector<TimedResolver>,
+    version: ProxyProtocolVersion,
+    client: SocketAddr,
+}
+
+impl ProxyProtocolConnector {
+    pub(crate) fn new(inner: HttpConnector<TimedResolver>, version: ProxyProtocolVersion, client: SocketAddr) -> Self {
+        ProxyProtocolConnector { inner, version, client }
+    }
+}
+
+impl Service<Uri> for ProxyProtocolConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<TokioIo<TcpStream>, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let connecting = self.inner.call(dst);
+        let (version, client) = (self.version, self.client);
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            proxy_protocol::write_header(stream.inner_mut(), version, client).await?;
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let addr = listener.local_addr()?;
+        // 第一个地址连接失败后立即连接下一个，不需要等待delay
+        let start = Instant::now();
+        let stream = happy_eyeballs(&[closed, addr], Duration::from_secs(10)).await?;
+        assert_eq!(stream.peer_addr()?, addr);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(happy_eyeballs(&[closed], Duration::from_millis(10)).await.is_err());
+        assert!(happy_eyeballs(&[], Duration::from_millis(10)).await.is_err());
+        Ok(())
+    }
+}
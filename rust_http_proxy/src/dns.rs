@@ -0,0 +1,697 @@
+//! 上游的DNS解析：静态hosts、按TTL缓存，通过UDP、TCP、DoT、DoH查询配置的DNS服务器
+//!
+//! 没有配置--dns-server时使用系统的解析（getaddrinfo），不缓存；静态hosts和地址族偏好同样生效
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use http::{header, Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{self, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use log::debug;
+use prom_label::LabelImpl;
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{counter::Counter, family::Family, histogram::Histogram},
+    registry::Registry,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig},
+    TlsConnector,
+};
+
+use crate::{
+    dns_message::{self, Answer, RecordType, NOERROR, NXDOMAIN},
+    latency::latency_histogram,
+};
+
+/// 缓存的最长时间，TTL更长时也按这个时间过期
+const MAX_TTL: u32 = 24 * 60 * 60;
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// 地址族偏好：决定查询哪些记录，以及Happy Eyeballs先连接哪个地址族
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum IpPreference {
+    /// IPv6和IPv4交替，IPv6在前（RFC 8305）
+    Ipv6,
+    /// IPv4和IPv6交替，IPv4在前
+    Ipv4,
+    /// 只使用IPv4
+    Ipv4Only,
+    /// 只使用IPv6
+    Ipv6Only,
+}
+
+impl IpPreference {
+    fn record_types(self) -> &'static [RecordType] {
+        match self {
+            IpPreference::Ipv6 | IpPreference::Ipv4 => &[RecordType::Aaaa, RecordType::A],
+            IpPreference::Ipv4Only => &[RecordType::A],
+            IpPreference::Ipv6Only => &[RecordType::Aaaa],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Transport {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    Tls(SocketAddr, ServerName<'static>),
+    Https(Uri),
+}
+
+/// 上游DNS服务器，name为配置中的写法，用作指标的label
+#[derive(Clone, Debug)]
+pub(crate) struct DnsServer {
+    name: String,
+    transport: Transport,
+}
+
+impl Display for DnsServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl DnsServer {
+    /// 解析 8.8.8.8、udp://8.8.8.8:53、tcp://8.8.8.8、tls://1.1.1.1#cloudflare-dns.com、https://dns.google/dns-query
+    ///
+    /// tls://不带#时使用IP校验证书；https://的域名使用系统的解析
+    pub(crate) fn parse(arg: &str) -> Result<Self, String> {
+        let wrong = || format!("wrong dns server: {}", arg);
+        let transport = match arg.split_once("://") {
+            None => Transport::Udp(socket_addr(arg, 53).ok_or_else(wrong)?),
+            Some(("udp", addr)) => Transport::Udp(socket_addr(addr, 53).ok_or_else(wrong)?),
+            Some(("tcp", addr)) => Transport::Tcp(socket_addr(addr, 53).ok_or_else(wrong)?),
+            Some(("tls", addr)) => {
+                let (addr, server_name) = match addr.split_once('#') {
+                    Some((addr, server_name)) => (socket_addr(addr, 853).ok_or_else(wrong)?, server_name.to_owned()),
+                    None => {
+                        let addr = socket_addr(addr, 853).ok_or_else(wrong)?;
+                        (addr, addr.ip().to_string())
+                    }
+                };
+                Transport::Tls(addr, ServerName::try_from(server_name).map_err(|_| wrong())?)
+            }
+            Some(("https", _)) => Transport::Https(arg.parse().map_err(|_| wrong())?),
+            Some(_) => return Err(wrong()),
+        };
+        Ok(DnsServer {
+            name: arg.to_owned(),
+            transport,
+        })
+    }
+}
+
+/// 1.1.1.1、1.1.1.1:53、2001:db8::1、[2001:db8::1]:53
+fn socket_addr(addr: &str, default_port: u16) -> Option<SocketAddr> {
+    addr.parse::<SocketAddr>().ok().or_else(|| {
+        addr.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, default_port))
+    })
+}
+
+/// 解析 `example.com=10.0.0.1` 形式的静态hosts，同一个域名可以指定多次
+pub(crate) fn parse_hosts(args: &[String]) -> Result<HashMap<String, Vec<IpAddr>>, String> {
+    let mut hosts = HashMap::<String, Vec<IpAddr>>::new();
+    for arg in args {
+        let (name, ip) = arg.split_once('=').ok_or_else(|| format!("wrong dns host: {}", arg))?;
+        let ip = ip
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("wrong ip of dns host: {}", arg))?;
+        hosts.entry(normalize(name)).or_default().push(ip);
+    }
+    Ok(hosts)
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct DnsConfig {
+    pub(crate) servers: Vec<DnsServer>,
+    pub(crate) hosts: HashMap<String, Vec<IpAddr>>,
+    pub(crate) prefer: IpPreference,
+    /// 缓存的域名数量，0表示不缓存
+    pub(crate) cache_size: usize,
+    /// 每个DNS服务器的查询超时，超时后查询下一个
+    pub(crate) timeout: Duration,
+    /// Happy Eyeballs中发起下一个连接前等待的时间
+    pub(crate) happy_eyeballs_delay: Duration,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            servers: vec![],
+            hosts: HashMap::new(),
+            prefer: IpPreference::Ipv6,
+            cache_size: 1024,
+            timeout: Duration::from_secs(5),
+            happy_eyeballs_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct DnsMetrics {
+    query: Family<LabelImpl<DnsQueryLabel>, Histogram>,
+    cache: Family<LabelImpl<DnsCacheLabel>, Counter>,
+}
+
+impl DnsMetrics {
+    pub(crate) fn register(registry: &mut Registry) -> Self {
+        let query = Family::<LabelImpl<DnsQueryLabel>, Histogram>::new_with_constructor(latency_histogram);
+        registry.register("dns_query_seconds", "Time of queries to configured DNS servers", query.clone());
+        let cache = Family::<LabelImpl<DnsCacheLabel>, Counter>::default();
+        registry.register("dns_cache", "DNS cache lookups", cache.clone());
+        DnsMetrics { query, cache }
+    }
+
+    fn observe_query(&self, server: &DnsServer, record_type: RecordType, result: &'static str, elapsed: Duration) {
+        self.query
+            .get_or_create(&LabelImpl::new(DnsQueryLabel {
+                server: server.name.clone(),
+                record_type: record_type.as_str(),
+                result,
+            }))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn observe_cache(&self, hit: bool) {
+        self.cache
+            .get_or_create(&LabelImpl::new(DnsCacheLabel {
+                result: if hit { "hit" } else { "miss" },
+            }))
+            .inc();
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct DnsQueryLabel {
+    pub server: String,
+    pub record_type: &'static str,
+    /// 响应码（noerror、nxdomain、servfail等），或者timeout、error
+    pub result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct DnsCacheLabel {
+    pub result: &'static str,
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+type DohClient = legacy::Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+#[derive(Clone)]
+pub(crate) struct Resolver {
+    inner: Arc<ResolverInner>,
+}
+
+struct ResolverInner {
+    config: DnsConfig,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    metrics: DnsMetrics,
+    tls_connector: TlsConnector,
+    doh_client: DohClient,
+}
+
+impl Resolver {
+    /// tls_config用于DoT和DoH，不能设置ALPN
+    pub(crate) fn new(config: DnsConfig, tls_config: ClientConfig, metrics: DnsMetrics) -> Self {
+        let doh_client = legacy::Client::builder(TokioExecutor::new()).build(
+            HttpsConnectorBuilder::new()
+                .with_tls_config(tls_config.clone())
+                .https_or_http()
+                .enable_all_versions()
+                .build(),
+        );
+        Resolver {
+            inner: Arc::new(ResolverInner {
+                config,
+                cache: Mutex::new(HashMap::new()),
+                metrics,
+                tls_connector: TlsConnector::from(Arc::new(tls_config)),
+                doh_client,
+            }),
+        }
+    }
+
+    pub(crate) fn happy_eyeballs_delay(&self) -> Duration {
+        self.inner.config.happy_eyeballs_delay
+    }
+
+    /// 解析host:port，地址按偏好排序
+    pub(crate) async fn resolve(&self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address: {}", target));
+        let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Ok(self
+            .lookup(host)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// 解析域名，顺序为静态hosts、缓存、DNS服务器（没有配置时为系统的解析）
+    pub(crate) async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let config = &self.inner.config;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let name = normalize(host);
+        let addrs = match config.hosts.get(&name) {
+            Some(addrs) => addrs.clone(),
+            None if config.servers.is_empty() => lookup_host((name.as_str(), 0)).await?.map(|addr| addr.ip()).collect(),
+            None => self.query_cached(&name).await?,
+        };
+        let addrs = sort_addrs(addrs, config.prefer);
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no address of {}", name)));
+        }
+        Ok(addrs)
+    }
+
+    async fn query_cached(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let inner = &self.inner;
+        let cached = inner
+            .cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| entry.addrs.clone());
+        inner.metrics.observe_cache(cached.is_some());
+        if let Some(addrs) = cached {
+            return Ok(addrs);
+        }
+        let record_types = inner.config.prefer.record_types();
+        let results =
+            futures_util::future::join_all(record_types.iter().map(|record_type| self.query(name, *record_type))).await;
+        let (mut addrs, mut ttl) = (vec![], MAX_TTL);
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(records) => {
+                    for (ip, record_ttl) in records {
+                        addrs.push(ip);
+                        ttl = ttl.min(record_ttl);
+                    }
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            // 只要有一种记录查到了地址就可以使用，但是不缓存
+            Some(e) if addrs.is_empty() => return Err(e),
+            Some(e) => debug!("dns query of {} partly failed: {}", name, e),
+            None => self.store(name, &addrs, ttl),
+        }
+        Ok(addrs)
+    }
+
+    /// 缓存满时先清理过期的，仍然满时淘汰最早过期的
+    fn store(&self, name: &str, addrs: &[IpAddr], ttl: u32) {
+        let cache_size = self.inner.config.cache_size;
+        if cache_size == 0 || ttl == 0 || addrs.is_empty() {
+            return;
+        }
+        let mut cache = self.inner.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if cache.len() >= cache_size && !cache.contains_key(name) {
+            cache.retain(|_, entry| entry.expires > now);
+            let earliest = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(name, _)| name.clone());
+            if let (true, Some(earliest)) = (cache.len() >= cache_size, earliest) {
+                cache.remove(&earliest);
+            }
+        }
+        cache.insert(
+            name.to_owned(),
+            CacheEntry {
+                addrs: addrs.to_vec(),
+                expires: now + Duration::from_secs(ttl.into()),
+            },
+        );
+    }
+
+    /// 依次查询DNS服务器，NOERROR和NXDOMAIN是确定的结果，其他响应码、超时和错误时查询下一个
+    async fn query(&self, name: &str, record_type: RecordType) -> io::Result<Vec<(IpAddr, u32)>> {
+        let inner = &self.inner;
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no dns server");
+        for server in &inner.config.servers {
+            let start = Instant::now();
+            let result = tokio::time::timeout(inner.config.timeout, self.exchange(server, name, record_type))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "dns query timeout")));
+            let label = match &result {
+                Ok(answer) => dns_message::rcode_str(answer.rcode),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => "timeout",
+                Err(_) => "error",
+            };
+            inner.metrics.observe_query(server, record_type, label, start.elapsed());
+            match result {
+                Ok(answer) if answer.rcode == NOERROR || answer.rcode == NXDOMAIN => return Ok(answer.records),
+                Ok(answer) => {
+                    last_error = io::Error::other(format!(
+                        "dns server {} answered {} for {} {}",
+                        server,
+                        dns_message::rcode_str(answer.rcode),
+                        record_type.as_str(),
+                        name
+                    ))
+                }
+                Err(e) => {
+                    last_error = io::Error::new(
+                        e.kind(),
+                        format!("dns query to {} for {} {} failed: {}", server, record_type.as_str(), name, e),
+                    )
+                }
+            }
+            debug!("{}", last_error);
+        }
+        Err(last_error)
+    }
+
+    async fn exchange(&self, server: &DnsServer, name: &str, record_type: RecordType) -> io::Result<Answer> {
+        let id = match server.transport {
+            // RFC 8484建议DoH的id为0，便于HTTP缓存
+            Transport::Https(_) => 0,
+            _ => rand::random::<u16>(),
+        };
+        let query = dns_message::encode_query(id, name, record_type)?;
+        match &server.transport {
+            Transport::Udp(addr) => {
+                let answer = udp_exchange(*addr, id, record_type, &query).await?;
+                if !answer.truncated {
+                    return Ok(answer);
+                }
+                // 响应被截断，使用TCP重新查询
+                stream_exchange(TcpStream::connect(addr).await?, id, record_type, &query).await
+            }
+            Transport::Tcp(addr) => stream_exchange(TcpStream::connect(addr).await?, id, record_type, &query).await,
+            Transport::Tls(addr, server_name) => {
+                let stream = self
+                    .inner
+                    .tls_connector
+                    .connect(server_name.clone(), TcpStream::connect(addr).await?)
+                    .await?;
+                stream_exchange(stream, id, record_type, &query).await
+            }
+            Transport::Https(uri) => {
+                let req = Request::post(uri.clone())
+                    .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                    .header(header::ACCEPT, DNS_MESSAGE)
+                    .body(Full::new(Bytes::from(query)))
+                    .map_err(io::Error::other)?;
+                let resp = self.inner.doh_client.request(req).await.map_err(io::Error::other)?;
+                if resp.status() != StatusCode::OK {
+                    return Err(io::Error::other(format!("doh server responded {}", resp.status())));
+                }
+                let body = resp.into_body().collect().await.map_err(io::Error::other)?.to_bytes();
+                dns_message::parse_response(id, record_type, &body)
+            }
+        }
+    }
+}
+
+/// id不一致的响应可能是伪造的，忽略后继续等待
+async fn udp_exchange(addr: SocketAddr, id: u16, record_type: RecordType, query: &[u8]) -> io::Result<Answer> {
+    let local = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; 4096];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        match dns_message::parse_response(id, record_type, &buf[..len]) {
+            Ok(answer) => return Ok(answer),
+            Err(e) => debug!("ignore dns response from {}: {}", addr, e),
+        }
+    }
+}
+
+/// TCP和DoT的报文前面有两字节的长度
+async fn stream_exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S, id: u16, record_type: RecordType, query: &[u8],
+) -> io::Result<Answer> {
+    let mut buf = (query.len() as u16).to_be_bytes().to_vec();
+    buf.extend_from_slice(query);
+    stream.write_all(&buf).await?;
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    dns_message::parse_response(id, record_type, &buf)
+}
+
+/// RFC 8305第4节：两个地址族交替排列，偏好的地址族在前；只使用一个地址族时去掉另一个
+pub(crate) fn sort_addrs(addrs: Vec<IpAddr>, prefer: IpPreference) -> Vec<IpAddr> {
+    let mut unique = vec![];
+    for ip in addrs.into_iter().map(|ip| ip.to_canonical()) {
+        if !unique.contains(&ip) {
+            unique.push(ip);
+        }
+    }
+    let (v4, v6): (Vec<_>, Vec<_>) = unique.into_iter().partition(IpAddr::is_ipv4);
+    let (first, second) = match prefer {
+        IpPreference::Ipv6 => (v6, v4),
+        IpPreference::Ipv4 => (v4, v6),
+        IpPreference::Ipv4Only => (v4, vec![]),
+        IpPreference::Ipv6Only => (v6, vec![]),
+    };
+    let mut sorted = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return sorted,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::Ipv6Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use hyper::{body::Incoming, server::conn::http1, service::service_fn, Response};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::RootCertStore;
+
+    use super::*;
+
+    const V4: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    fn resolver(config: DnsConfig) -> Resolver {
+        crate::config::install_crypto_provider();
+        let tls_config = ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        Resolver::new(config, tls_config, DnsMetrics::register(&mut Registry::default()))
+    }
+
+    fn server(name: &str, transport: Transport) -> DnsServer {
+        DnsServer {
+            name: name.to_owned(),
+            transport,
+        }
+    }
+
+    /// 本地的DNS服务器，UDP和TCP使用同一个端口。truncate_udp为true时UDP只返回截断的空响应
+    async fn stub_server(truncate_udp: bool) -> io::Result<(SocketAddr, Arc<AtomicUsize>)> {
+        let udp = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = udp.local_addr()?;
+        let tcp = TcpListener::bind(addr).await?;
+        let queries = Arc::new(AtomicUsize::new(0));
+        let udp_queries = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            while let Ok((len, from)) = udp.recv_from(&mut buf).await {
+                udp_queries.fetch_add(1, Ordering::SeqCst);
+                let records = if truncate_udp {
+                    vec![]
+                } else {
+                    vec![(V4, 300), (V6, 60)]
+                };
+                let response = dns_message::encode_response(&buf[..len], NOERROR, truncate_udp, &records);
+                let _ = udp.send_to(&response, from).await;
+            }
+        });
+        let tcp_queries = queries.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = tcp.accept().await {
+                tcp_queries.fetch_add(1, Ordering::SeqCst);
+                let Ok(len) = stream.read_u16().await else { continue };
+                let mut query = vec![0; len as usize];
+                if stream.read_exact(&mut query).await.is_err() {
+                    continue;
+                }
+                let response = dns_message::encode_response(&query, NOERROR, false, &[(V4, 300), (V6, 60)]);
+                let mut buf = (response.len() as u16).to_be_bytes().to_vec();
+                buf.extend_from_slice(&response);
+                let _ = stream.write_all(&buf).await;
+            }
+        });
+        Ok((addr, queries))
+    }
+
+    #[tokio::test]
+    async fn test_lookup() -> io::Result<()> {
+        let (addr, queries) = stub_server(false).await?;
+        let resolver = resolver(DnsConfig {
+            servers: vec![server("udp", Transport::Udp(addr))],
+            hosts: parse_hosts(&["Static.Test.=192.168.0.1".to_owned()]).map_err(io::Error::other)?,
+            ..Default::default()
+        });
+        assert_eq!(resolver.lookup("example.com").await?, vec![V6, V4]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        // 缓存命中，不再查询
+        assert_eq!(resolver.resolve("EXAMPLE.com.:443").await?[0], SocketAddr::new(V6, 443));
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        assert_eq!(resolver.lookup("static.test").await?, vec![IpAddr::from([192, 168, 0, 1])]);
+        assert_eq!(resolver.resolve("[::1]:80").await?, vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 80))]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        assert!(resolver.resolve("example.com").await.is_err());
+
+        let resolver = self::resolver(DnsConfig {
+            servers: vec![server("tcp", Transport::Tcp(addr))],
+            prefer: IpPreference::Ipv4Only,
+            cache_size: 0,
+            ..Default::default()
+        });
+        assert_eq!(resolver.lookup("example.com").await?, vec![V4]);
+        assert_eq!(resolver.lookup("example.com").await?, vec![V4]);
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fallback() -> io::Result<()> {
+        // 不回答的UDP服务器，超时后查询下一个服务器
+        let silent = UdpSocket::bind("127.0.0.1:0").await?;
+        let (addr, queries) = stub_server(true).await?;
+        let resolver = resolver(DnsConfig {
+            servers: vec![
+                server("silent", Transport::Udp(silent.local_addr()?)),
+                server("truncated", Transport::Udp(addr)),
+            ],
+            prefer: IpPreference::Ipv6Only,
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        // UDP响应被截断，使用TCP重新查询
+        assert_eq!(resolver.lookup("example.com").await?, vec![V6]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        let resolver = self::resolver(DnsConfig {
+            servers: vec![server("silent", Transport::Udp(silent.local_addr()?))],
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        assert!(resolver
+            .lookup("example.com")
+            .await
+            .is_err_and(|e| e.kind() == io::ErrorKind::TimedOut));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_doh() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let content_type = req.headers().get(header::CONTENT_TYPE).cloned();
+                    let query = req
+                        .into_body()
+                        .collect()
+                        .await
+                        .map(|body| body.to_bytes())
+                        .unwrap_or_default();
+                    let mut resp = Response::new(Full::new(Bytes::from(dns_message::encode_response(
+                        &query,
+                        NOERROR,
+                        false,
+                        &[(V4, 300)],
+                    ))));
+                    if content_type.as_ref().map(|value| value.as_bytes()) != Some(DNS_MESSAGE.as_bytes()) {
+                        *resp.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                    }
+                    Ok::<_, Infallible>(resp)
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        let uri = format!("http://{}/dns-query", addr).parse().map_err(io::Error::other)?;
+        let resolver = resolver(DnsConfig {
+            servers: vec![server("doh", Transport::Https(uri))],
+            ..Default::default()
+        });
+        assert_eq!(resolver.lookup("example.com").await?, vec![V4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_and_sort() {
+        assert!(matches!(DnsServer::parse("8.8.8.8").map(|server| server.transport),
+            Ok(Transport::Udp(addr)) if addr == SocketAddr::from(([8, 8, 8, 8], 53))));
+        assert!(matches!(DnsServer::parse("tcp://[2001:4860:4860::8888]").map(|server| server.transport),
+            Ok(Transport::Tcp(addr)) if addr.port() == 53 && addr.is_ipv6()));
+        assert!(matches!(DnsServer::parse("tls://1.1.1.1#cloudflare-dns.com").map(|server| server.transport),
+            Ok(Transport::Tls(addr, name)) if addr.port() == 853 && name.to_str() == "cloudflare-dns.com"));
+        assert!(matches!(
+            DnsServer::parse("https://dns.google/dns-query").map(|server| server.transport),
+            Ok(Transport::Https(_))
+        ));
+        assert!(DnsServer::parse("dns.google").is_err());
+        assert!(DnsServer::parse("quic://1.1.1.1").is_err());
+        assert!(parse_hosts(&["example.com".to_owned()]).is_err());
+
+        let addrs = vec![V4, IpAddr::from([10, 0, 0, 2]), V6, V4, IpAddr::from([10, 0, 0, 3])];
+        assert_eq!(
+            sort_addrs(addrs.clone(), IpPreference::Ipv6),
+            vec![V6, V4, IpAddr::from([10, 0, 0, 2]), IpAddr::from([10, 0, 0, 3])]
+        );
+        assert_eq!(sort_addrs(addrs.clone(), IpPreference::Ipv4)[..3], [V4, V6, IpAddr::from([10, 0, 0, 2])]);
+        assert_eq!(sort_addrs(addrs, IpPreference::Ipv6Only), vec![V6]);
+    }
+}
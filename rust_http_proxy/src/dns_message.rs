@@ -0,0 +1,248 @@
+//! DNS报文（RFC 1035）：只生成A/AAAA查询，只解析响应中的A/AAAA记录，CNAME由上游DNS递归解析
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// 响应码
+pub(crate) const NOERROR: u8 = 0;
+pub(crate) const NXDOMAIN: u8 = 3;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            RecordType::A => "A",
+            RecordType::Aaaa => "AAAA",
+        }
+    }
+}
+
+/// 响应中的地址和TTL
+#[derive(Debug, Default)]
+pub(crate) struct Answer {
+    pub(crate) rcode: u8,
+    /// UDP响应被截断，需要使用TCP重新查询
+    pub(crate) truncated: bool,
+    pub(crate) records: Vec<(IpAddr, u32)>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid dns message: {}", msg))
+}
+
+/// 生成查询报文，设置RD（期望递归）
+pub(crate) fn encode_query(id: u16, name: &str, record_type: RecordType) -> io::Result<Vec<u8>> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 || !name.is_ascii() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid domain name: {}", name)));
+    }
+    let mut buf = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // QDCOUNT=1，ANCOUNT、NSCOUNT、ARCOUNT=0
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid domain name: {}", name)));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&record_type.code().to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("truncated message"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// 跳过域名，遇到压缩指针时指针之后就是域名的结尾
+    fn skip_name(&mut self) -> io::Result<()> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Ok(()),
+                len if len & 0xc0 == 0xc0 => {
+                    self.take(1)?;
+                    return Ok(());
+                }
+                len if len & 0xc0 == 0 => {
+                    self.take(len as usize)?;
+                }
+                _ => return Err(invalid("unknown label type")),
+            }
+        }
+    }
+}
+
+/// 解析响应，id不一致时返回错误。只返回record_type类型的记录
+pub(crate) fn parse_response(id: u16, record_type: RecordType, buf: &[u8]) -> io::Result<Answer> {
+    let mut reader = Reader { buf, pos: 0 };
+    if reader.u16()? != id {
+        return Err(invalid("id mismatch"));
+    }
+    let flags = reader.u16()?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(invalid("not a response"));
+    }
+    let mut answer = Answer {
+        rcode: (flags & 0x000f) as u8,
+        truncated: flags & FLAG_TRUNCATED != 0,
+        records: vec![],
+    };
+    let question_count = reader.u16()?;
+    let answer_count = reader.u16()?;
+    // NSCOUNT、ARCOUNT
+    reader.take(4)?;
+    for _ in 0..question_count {
+        reader.skip_name()?;
+        reader.take(4)?;
+    }
+    for _ in 0..answer_count {
+        reader.skip_name()?;
+        let (rtype, class, ttl) = (reader.u16()?, reader.u16()?, reader.u32()?);
+        let len = reader.u16()? as usize;
+        let data = reader.take(len)?;
+        if class != CLASS_IN || rtype != record_type.code() {
+            continue;
+        }
+        let ip = match (record_type, len) {
+            (RecordType::A, 4) => IpAddr::from(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (RecordType::Aaaa, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                IpAddr::from(Ipv6Addr::from(octets))
+            }
+            _ => return Err(invalid("wrong rdata length")),
+        };
+        answer.records.push((ip, ttl));
+    }
+    Ok(answer)
+}
+
+/// 响应码的名称，用于指标
+pub(crate) fn rcode_str(rcode: u8) -> &'static str {
+    match rcode {
+        NOERROR => "noerror",
+        1 => "formerr",
+        2 => "servfail",
+        NXDOMAIN => "nxdomain",
+        4 => "notimp",
+        5 => "refused",
+        _ => "other",
+    }
+}
+
+/// 测试中的DNS服务器使用：按查询的类型从records中挑选记录生成响应，回答中的域名使用压缩指针指向问题
+#[cfg(test)]
+pub(crate) fn encode_response(query: &[u8], rcode: u8, truncated: bool, records: &[(IpAddr, u32)]) -> Vec<u8> {
+    let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+    let records = records
+        .iter()
+        .filter(|(ip, _)| match ip {
+            IpAddr::V4(_) => qtype == RecordType::A.code(),
+            IpAddr::V6(_) => qtype == RecordType::Aaaa.code(),
+        })
+        .collect::<Vec<_>>();
+    let mut flags = FLAG_RESPONSE | FLAG_RECURSION_DESIRED | 0x0080 | rcode as u16;
+    if truncated {
+        flags |= FLAG_TRUNCATED;
+    }
+    let mut buf = query[..2].to_vec();
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&[0, 1]);
+    buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(&query[HEADER_LEN..]);
+    for (ip, ttl) in records {
+        buf.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        let data = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&data);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_parse() -> io::Result<()> {
+        let query = encode_query(0x1234, "www.example.com.", RecordType::A)?;
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[HEADER_LEN..HEADER_LEN + 5], b"\x03www\x07");
+        assert_eq!(&query[query.len() - 4..], &[0, 1, 0, 1]);
+        assert!(encode_query(1, "a..com", RecordType::A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), RecordType::A).is_err());
+
+        let v4 = IpAddr::from([1, 2, 3, 4]);
+        let v6 = IpAddr::from(Ipv6Addr::LOCALHOST);
+        let response = encode_response(&query, NOERROR, false, &[(v4, 300), (v6, 60)]);
+        let answer = parse_response(0x1234, RecordType::A, &response)?;
+        assert_eq!(answer.records, vec![(v4, 300)]);
+        assert!(!answer.truncated);
+        assert!(parse_response(0x4321, RecordType::A, &response).is_err());
+        assert!(parse_response(0x1234, RecordType::A, &response[..response.len() - 1]).is_err());
+        // 查询报文不是响应
+        assert!(parse_response(0x1234, RecordType::A, &query).is_err());
+
+        let query = encode_query(7, "example.com", RecordType::Aaaa)?;
+        let answer = parse_response(7, RecordType::Aaaa, &encode_response(&query, NOERROR, true, &[(v6, 60)]))?;
+        assert_eq!(answer.records, vec![(v6, 60)]);
+        assert!(answer.truncated);
+        let answer = parse_response(7, RecordType::Aaaa, &encode_response(&query, NXDOMAIN, false, &[]))?;
+        assert_eq!(rcode_str(answer.rcode), "nxdomain");
+        assert!(answer.records.is_empty());
+        Ok(())
+    }
+}
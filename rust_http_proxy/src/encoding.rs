@@ -0,0 +1,256 @@
+//! 静态文件的内容编码协商：按Accept-Encoding的q值选择br/zstd/gzip，优先使用预压缩的同名文件（file.br、file.zst、file.gz）
+
+use std::{
+    fs::Metadata,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+    Level,
+};
+use tokio::{
+    fs::metadata,
+    io::{AsyncRead, BufReader},
+};
+
+/// 默认可以动态压缩的Content-Type前缀
+pub(crate) const DEFAULT_COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/html",
+    "text/css",
+    "application/javascript",
+    "application/json",
+    "text/xml",
+    "application/xml",
+    "text/plain",
+    "text/markdown",
+];
+
+/// 动态压缩的brotli级别
+const BROTLI_QUALITY: i32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    /// 服务端的偏好顺序，q值相同时靠前的优先
+    const PREFERENCE: [Encoding; 4] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Identity];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    /// 预压缩文件的后缀
+    fn extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Zstd => Some("zst"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Identity => None,
+        }
+    }
+
+    fn matches(self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str()) || (self == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+
+    /// 不同编码的ETag不能相同，否则缓存可能把gzip的内容返回给不支持gzip的客户端
+    pub(crate) fn etag(self, etag: &str) -> String {
+        match self {
+            Encoding::Identity => etag.to_owned(),
+            _ => format!("{}-{}\"", etag.trim_end_matches('"'), self.as_str()),
+        }
+    }
+
+    /// 动态压缩。gzip和zstd使用默认的压缩级别；brotli的默认级别是最高的11，动态压缩太慢，使用BROTLI_QUALITY
+    pub(crate) fn encode<T>(self, async_read: T) -> Pin<Box<dyn AsyncRead + Send + Sync + Unpin>>
+    where
+        T: AsyncRead + Send + Sync + Unpin + 'static,
+    {
+        match self {
+            Encoding::Brotli => {
+                Box::pin(BrotliEncoder::with_quality(BufReader::new(async_read), Level::Precise(BROTLI_QUALITY)))
+            }
+            Encoding::Zstd => Box::pin(ZstdEncoder::with_quality(BufReader::new(async_read), Level::Default)),
+            Encoding::Gzip => Box::pin(GzipEncoder::with_quality(BufReader::new(async_read), Level::Default)),
+            Encoding::Identity => Box::pin(async_read),
+        }
+    }
+}
+
+/// 解析后的Accept-Encoding
+pub(crate) struct AcceptEncoding {
+    codings: Vec<(String, f32)>,
+}
+
+impl AcceptEncoding {
+    pub(crate) fn parse(accept_encoding: &str) -> Self {
+        let codings = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let coding = parts.next()?.trim();
+                if coding.is_empty() {
+                    return None;
+                }
+                let q = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0)
+                    .clamp(0.0, 1.0);
+                Some((coding.to_owned(), q))
+            })
+            .collect();
+        AcceptEncoding { codings }
+    }
+
+    /// 编码的q值。没有列出的编码使用`*`的q值；identity没有被排除时默认可以接受
+    pub(crate) fn q(&self, encoding: Encoding) -> f32 {
+        let find = |pred: &dyn Fn(&str) -> bool| self.codings.iter().find(|(coding, _)| pred(coding)).map(|c| c.1);
+        find(&|coding| encoding.matches(coding))
+            .or_else(|| find(&|coding| coding == "*"))
+            .unwrap_or(if encoding == Encoding::Identity { 1.0 } else { 0.0 })
+    }
+
+    pub(crate) fn accepts(&self, encoding: Encoding) -> bool {
+        self.q(encoding) > 0.0
+    }
+
+    /// 在候选编码中选择q值最高的，q值相同时按服务端偏好。都不可接受时返回identity
+    pub(crate) fn negotiate(&self, candidates: &[Encoding]) -> Encoding {
+        let mut best = (Encoding::Identity, 0.0);
+        for encoding in Encoding::PREFERENCE {
+            if !candidates.contains(&encoding) {
+                continue;
+            }
+            let q = self.q(encoding);
+            if q > best.1 {
+                best = (encoding, q);
+            }
+        }
+        best.0
+    }
+}
+
+/// 选定的响应表示
+pub(crate) struct Representation {
+    pub(crate) path: PathBuf,
+    pub(crate) meta: Metadata,
+    pub(crate) encoding: Encoding,
+    /// 为true时表示需要动态压缩path的内容
+    pub(crate) on_the_fly: bool,
+}
+
+/// 按Accept-Encoding选择预压缩文件、动态压缩或者原文件
+pub(crate) async fn select(
+    path: PathBuf, meta: Metadata, accept: &AcceptEncoding, compressible: bool,
+) -> Representation {
+    let mut precompressed = Vec::new();
+    for encoding in Encoding::PREFERENCE {
+        let Some(extension) = encoding.extension() else {
+            continue;
+        };
+        if !accept.accepts(encoding) {
+            continue;
+        }
+        let sibling = sibling_path(&path, extension);
+        if let Ok(sibling_meta) = metadata(&sibling).await {
+            if sibling_meta.is_file() {
+                precompressed.push((encoding, sibling, sibling_meta));
+            }
+        }
+    }
+    let mut candidates = precompressed.iter().map(|(encoding, ..)| *encoding).collect::<Vec<_>>();
+    // 动态压缩只在没有任何预压缩文件时使用
+    if compressible && candidates.is_empty() {
+        candidates.extend_from_slice(&[Encoding::Brotli, Encoding::Zstd, Encoding::Gzip]);
+    }
+    candidates.push(Encoding::Identity);
+    let encoding = accept.negotiate(&candidates);
+    match precompressed.into_iter().find(|(e, ..)| *e == encoding) {
+        Some((encoding, path, meta)) => Representation {
+            path,
+            meta,
+            encoding,
+            on_the_fly: false,
+        },
+        None => Representation {
+            path,
+            meta,
+            encoding,
+            on_the_fly: encoding != Encoding::Identity,
+        },
+    }
+}
+
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(extension);
+    PathBuf::from(sibling)
+}
+
+/// Content-Type是否在可以动态压缩的列表中
+pub(crate) fn is_compressible(content_type: &str, compressible_types: &[String]) -> bool {
+    compressible_types
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let all = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Identity];
+        assert_eq!(AcceptEncoding::parse("gzip, deflate, br, zstd").negotiate(&all), Encoding::Brotli);
+        assert_eq!(AcceptEncoding::parse("gzip;q=1.0, br;q=0.5").negotiate(&all), Encoding::Gzip);
+        assert_eq!(AcceptEncoding::parse("br;q=0, *").negotiate(&all), Encoding::Zstd);
+        assert_eq!(AcceptEncoding::parse("x-gzip").negotiate(&all), Encoding::Gzip);
+        assert_eq!(AcceptEncoding::parse("").negotiate(&all), Encoding::Identity);
+        assert_eq!(AcceptEncoding::parse("gzip;q=0").negotiate(&all), Encoding::Identity);
+        assert_eq!(AcceptEncoding::parse("br").negotiate(&[Encoding::Gzip, Encoding::Identity]), Encoding::Identity);
+        assert!(!AcceptEncoding::parse("gzip;q=0").accepts(Encoding::Gzip));
+        assert!(!AcceptEncoding::parse("*;q=0").accepts(Encoding::Identity));
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(Encoding::Brotli.etag("\"abc-10\""), "\"abc-10-br\"");
+        assert_eq!(Encoding::Identity.etag("\"abc-10\""), "\"abc-10\"");
+    }
+
+    #[tokio::test]
+    async fn test_select_precompressed() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        let path = dir.join("app.js");
+        tokio::fs::write(&path, b"console.log(1)").await?;
+        tokio::fs::write(dir.join("app.js.gz"), b"gz").await?;
+        let meta = metadata(&path).await?;
+
+        // 有预压缩的gz时，即使客户端更偏好br也不动态压缩
+        let r = select(path.clone(), meta.clone(), &AcceptEncoding::parse("br, gzip"), true).await;
+        assert_eq!((r.encoding, r.on_the_fly), (Encoding::Gzip, false));
+        assert_eq!(r.path, dir.join("app.js.gz"));
+        // 客户端不支持gzip时动态压缩
+        let r = select(path.clone(), meta.clone(), &AcceptEncoding::parse("br"), true).await;
+        assert_eq!((r.encoding, r.on_the_fly), (Encoding::Brotli, true));
+        // 不在压缩类型列表中时使用原文件
+        let r = select(path.clone(), meta, &AcceptEncoding::parse("br"), false).await;
+        assert_eq!((r.encoding, r.on_the_fly), (Encoding::Identity, false));
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! 自定义错误页面：先找Host的配置，再找全局配置（--error-page），都没有时使用内置页面
+
+use std::{collections::HashMap, io, path::PathBuf};
+
+use http::{Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use log::warn;
+
+use crate::{proxy::full_body, web_func::SERVER_NAME, BODY404};
+
+/// 有内置页面的状态码
+pub(crate) const EMBEDDED_STATUSES: [StatusCode; 5] = [
+    StatusCode::FORBIDDEN,
+    StatusCode::NOT_FOUND,
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// 状态码到错误页面文件的映射
+#[derive(Default)]
+pub(crate) struct ErrorPages {
+    pages: HashMap<StatusCode, PathBuf>,
+}
+
+impl ErrorPages {
+    /// pages的值为root下以/开头的路径
+    pub(crate) fn new(root: &str, pages: HashMap<u16, String>) -> Result<Self, String> {
+        let root = root.trim_end_matches('/');
+        let mut error_pages = ErrorPages::default();
+        for (code, page) in pages {
+            let status = StatusCode::from_u16(code)
+                .ok()
+                .filter(|status| status.is_client_error() || status.is_server_error())
+                .ok_or_else(|| format!("error page status should be 4xx or 5xx: {}", code))?;
+            if !page.starts_with('/') || page.contains("/..") {
+                return Err(format!("error page should start with '/' and not contain '/..': {}", page));
+            }
+            error_pages
+                .pages
+                .insert(status, PathBuf::from(format!("{}{}", root, page)));
+        }
+        Ok(error_pages)
+    }
+
+    /// 解析命令行参数，形如 404=/404.html
+    pub(crate) fn parse_args(root: &str, args: &[String]) -> Result<Self, String> {
+        let mut pages = HashMap::new();
+        for arg in args {
+            let (code, page) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("error page should be like 404=/404.html: {}", arg))?;
+            let code = code
+                .trim()
+                .parse::<u16>()
+                .map_err(|_| format!("wrong status code of error page: {}", arg))?;
+            pages.insert(code, page.trim().to_owned());
+        }
+        Self::new(root, pages)
+    }
+
+    /// 读取配置的错误页面，没有配置或者读取失败时返回None
+    pub(crate) async fn load(&self, status: StatusCode) -> Option<Bytes> {
+        let path = self.pages.get(&status)?;
+        match tokio::fs::read(path).await {
+            Ok(content) => Some(Bytes::from(content)),
+            Err(e) => {
+                warn!("read error page {} failed: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// 依次查找pages中配置的页面
+pub(crate) async fn load(pages: &[&ErrorPages], status: StatusCode) -> Option<Bytes> {
+    for error_pages in pages {
+        if let Some(content) = error_pages.load(status).await {
+            return Some(content);
+        }
+    }
+    None
+}
+
+/// 内置的错误页面，与404页面的样式相同
+pub(crate) fn embedded(status: StatusCode) -> Bytes {
+    if status == StatusCode::NOT_FOUND {
+        return Bytes::from_static(BODY404.as_bytes());
+    }
+    let title = format!("{} {}", status.as_u16(), status.canonical_reason().unwrap_or_default().to_uppercase());
+    Bytes::from(BODY404.replace("404 NOT FOUND", &title))
+}
+
+/// 内置的错误页面，标题下面显示错误原因
+pub(crate) fn embedded_with_detail(status: StatusCode, detail: &str) -> Bytes {
+    let page = String::from_utf8_lossy(&embedded(status)).into_owned();
+    Bytes::from(page.replacen("</h1>", &format!("</h1>\n        <p>{}</p>", detail), 1))
+}
+
+/// 错误页面的响应，没有配置页面时使用内置页面
+pub(crate) async fn response(pages: &[&ErrorPages], status: StatusCode) -> Response<BoxBody<Bytes, io::Error>> {
+    response_with_detail(pages, status, None).await
+}
+
+/// 与response相同，没有配置页面时内置页面中显示detail
+pub(crate) async fn response_with_detail(
+    pages: &[&ErrorPages], status: StatusCode, detail: Option<&str>,
+) -> Response<BoxBody<Bytes, io::Error>> {
+    let body = match (load(pages, status).await, detail) {
+        (Some(body), _) => body,
+        (None, Some(detail)) => embedded_with_detail(status, detail),
+        (None, None) => embedded(status),
+    };
+    let mut resp = Response::new(full_body(body));
+    *resp.status_mut() = status;
+    let headers = resp.headers_mut();
+    headers.insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("text/html; charset=utf-8"));
+    headers.insert(http::header::SERVER, http::HeaderValue::from_static(SERVER_NAME));
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_error_pages() -> Result<(), crate::DynError> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path();
+        tokio::fs::write(root.join("404.html"), b"host 404").await?;
+        tokio::fs::write(root.join("50x.html"), b"global 50x").await?;
+        let root_str = root.to_string_lossy();
+        let host = ErrorPages::new(&root_str, HashMap::from([(404, "/404.html".to_owned())]))?;
+        let global = ErrorPages::parse_args(&root_str, &["502=/50x.html".to_owned(), "504=/missing.html".to_owned()])?;
+
+        let body = |resp: Response<BoxBody<Bytes, io::Error>>| async move {
+            resp.into_body()
+                .collect()
+                .await
+                .map(|body| body.to_bytes())
+                .unwrap_or_default()
+        };
+        assert_eq!(body(response(&[&host, &global], StatusCode::NOT_FOUND).await).await, "host 404");
+        assert_eq!(body(response(&[&host, &global], StatusCode::BAD_GATEWAY).await).await, "global 50x");
+        // 文件不存在时使用内置页面
+        let resp = response(&[&host, &global], StatusCode::GATEWAY_TIMEOUT).await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(String::from_utf8_lossy(&body(resp).await).contains("504 GATEWAY TIMEOUT"));
+        let resp = response_with_detail(&[&host], StatusCode::GATEWAY_TIMEOUT, Some("upstream connect timeout")).await;
+        assert!(String::from_utf8_lossy(&body(resp).await).contains("<p>upstream connect timeout</p>"));
+        // 配置了页面时不显示detail
+        let resp = response_with_detail(&[&global], StatusCode::BAD_GATEWAY, Some("detail")).await;
+        assert_eq!(body(resp).await, "global 50x");
+
+        assert!(ErrorPages::parse_args(&root_str, &["200=/ok.html".to_owned()]).is_err());
+        assert!(ErrorPages::parse_args(&root_str, &["404=/../etc/passwd".to_owned()]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! 受信任的代理（例如前面的L4负载均衡）和X-Forwarded-*、Forwarded头
+//!
+//! 请求来自受信任的地址时，从X-Forwarded-For或者Forwarded中取真实的客户端IP；
+//! 转发给上游时追加X-Forwarded-For，设置X-Forwarded-Proto、X-Forwarded-Host和Via
+
+use std::net::{IpAddr, SocketAddr};
+
+use http::{
+    header::{self, HeaderName},
+    HeaderMap, HeaderValue, Version,
+};
+
+use crate::proxy_status;
+
+pub(crate) const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub(crate) const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub(crate) const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// 形如 10.0.0.0/8、2001:db8::/32，不带前缀长度时为单个地址
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("wrong ip of trusted proxy: {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("wrong prefix length of trusted proxy: {}", s))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_eq(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// 比较bits位整数的高prefix位
+fn prefix_eq(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || net >> shift == ip >> shift
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies {
+    cidrs: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    /// 解析命令行参数
+    pub(crate) fn parse_args(args: &[String]) -> Result<Self, String> {
+        Ok(TrustedProxies {
+            cidrs: args.iter().map(|arg| Cidr::parse(arg)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// 真实的客户端地址：从右往左跳过受信任的代理，取第一个不受信任的地址
+    ///
+    /// 优先使用X-Forwarded-For，没有时使用Forwarded的for参数。端口无法得知，保留连接的端口
+    pub(crate) fn real_client(&self, headers: &HeaderMap, peer: SocketAddr) -> SocketAddr {
+        if !self.contains(peer.ip()) {
+            return peer;
+        }
+        let mut chain = forwarded_for(headers);
+        let mut client = peer.ip();
+        while let Some(hop) = chain.pop() {
+            match hop {
+                Some(ip) => {
+                    client = ip;
+                    if !self.contains(ip) {
+                        break;
+                    }
+                }
+                // unknown或者混淆过的标识，无法继续往前追溯
+                None => break,
+            }
+        }
+        SocketAddr::new(client.to_canonical(), peer.port())
+    }
+
+    /// 追加发给上游的X-Forwarded-For和Via
+    ///
+    /// 来自受信任代理的X-Forwarded-For保留并追加，否则丢弃客户端伪造的值
+    pub(crate) fn append_forwarded_for(&self, headers: &mut HeaderMap, peer: IpAddr, version: Version) {
+        let trusted = self.contains(peer);
+        let peer = peer.to_canonical().to_string();
+        let forwarded_for = match headers.get(X_FORWARDED_FOR).and_then(|value| value.to_str().ok()) {
+            Some(previous) if trusted => format!("{}, {}", previous, peer),
+            _ => peer,
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+        headers.append(header::VIA, proxy_status::via(version));
+    }
+
+    /// 设置X-Forwarded-Proto和X-Forwarded-Host，来自受信任代理的值保留
+    pub(crate) fn set_forwarded_proto_host(&self, headers: &mut HeaderMap, peer: IpAddr, proto: &str, host: &str) {
+        let trusted = self.contains(peer);
+        for (name, value) in [(X_FORWARDED_PROTO, proto), (X_FORWARDED_HOST, host)] {
+            if trusted && headers.contains_key(&name) {
+                continue;
+            }
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+/// 从X-Forwarded-For或者Forwarded中取出的地址，从左到右为客户端到最近的代理。None表示unknown等无法识别的值
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let x_forwarded_for = values(X_FORWARDED_FOR);
+    if !x_forwarded_for.is_empty() {
+        return x_forwarded_for.into_iter().map(parse_node).collect();
+    }
+    values(header::FORWARDED)
+        .into_iter()
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("for").then(|| parse_node(value.trim()))
+            })
+        })
+        .collect()
+}
+
+/// 解析 1.2.3.4、1.2.3.4:80、"[2001:db8::1]:4711"、2001:db8::1 形式的地址
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted(args: &[&str]) -> TrustedProxies {
+        TrustedProxies::parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>()).unwrap_or_default()
+    }
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_real_client() {
+        let proxies = trusted(&["10.0.0.0/8", "2001:db8::/32", "192.168.1.1"]);
+        assert!(TrustedProxies::parse_args(&["10.0.0.0/33".to_owned()]).is_err());
+        assert!(TrustedProxies::parse_args(&["localhost".to_owned()]).is_err());
+        assert!(proxies.contains("10.1.2.3".parse().unwrap_or(IpAddr::from([0; 4]))));
+        assert!(proxies.contains("::ffff:10.1.2.3".parse().unwrap_or(IpAddr::from([0; 4]))));
+        assert!(!proxies.contains("192.168.1.2".parse().unwrap_or(IpAddr::from([0; 4]))));
+        assert!(trusted(&["0.0.0.0/0"]).contains(IpAddr::from([8, 8, 8, 8])));
+
+        let lb = SocketAddr::from(([10, 0, 0, 1], 4000));
+        let xff = headers(&[(X_FORWARDED_FOR, "1.1.1.1, 2.2.2.2, 10.0.0.2")]);
+        assert_eq!(proxies.real_client(&xff, lb), SocketAddr::from(([2, 2, 2, 2], 4000)));
+        // 不受信任的来源不读取X-Forwarded-For
+        let untrusted = SocketAddr::from(([3, 3, 3, 3], 4000));
+        assert_eq!(proxies.real_client(&xff, untrusted), untrusted);
+
+        let forwarded = headers(&[(header::FORWARDED, "for=\"[2001:db9::1]:4711\";proto=https, for=10.0.0.2")]);
+        assert_eq!(proxies.real_client(&forwarded, lb).ip().to_string(), "2001:db9::1");
+        let unknown = headers(&[(header::FORWARDED, "for=unknown, for=10.0.0.2")]);
+        assert_eq!(proxies.real_client(&unknown, lb).ip(), IpAddr::from([10, 0, 0, 2]));
+        assert_eq!(proxies.real_client(&HeaderMap::new(), lb), lb);
+    }
+
+    #[test]
+    fn test_set_forwarded_headers() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let mut map = headers(&[(X_FORWARDED_FOR, "1.1.1.1"), (X_FORWARDED_PROTO, "https")]);
+        let lb = IpAddr::from([10, 0, 0, 1]);
+        proxies.append_forwarded_for(&mut map, lb, Version::HTTP_11);
+        proxies.set_forwarded_proto_host(&mut map, lb, "http", "example.com");
+        assert_eq!(map.get(X_FORWARDED_FOR).map(HeaderValue::as_bytes), Some(&b"1.1.1.1, 10.0.0.1"[..]));
+        assert_eq!(map.get(X_FORWARDED_PROTO).map(HeaderValue::as_bytes), Some(&b"https"[..]));
+        assert_eq!(map.get(X_FORWARDED_HOST).map(HeaderValue::as_bytes), Some(&b"example.com"[..]));
+        assert_eq!(map.get(header::VIA).map(HeaderValue::as_bytes), Some(&b"1.1 rust_http_proxy"[..]));
+
+        // 伪造的X-Forwarded-*被覆盖
+        let mut map = headers(&[(X_FORWARDED_FOR, "1.1.1.1"), (X_FORWARDED_PROTO, "https")]);
+        let client = IpAddr::from([3, 3, 3, 3]);
+        proxies.append_forwarded_for(&mut map, client, Version::HTTP_2);
+        proxies.set_forwarded_proto_host(&mut map, client, "http", "example.com");
+        assert_eq!(map.get(X_FORWARDED_FOR).map(HeaderValue::as_bytes), Some(&b"3.3.3.3"[..]));
+        assert_eq!(map.get(X_FORWARDED_PROTO).map(HeaderValue::as_bytes), Some(&b"http"[..]));
+        assert_eq!(map.get(header::VIA).map(HeaderValue::as_bytes), Some(&b"2 rust_http_proxy"[..]));
+    }
+}
@@ -0,0 +1,127 @@
+//! upstream的被动健康检查：连续失败max_fails次后在fail_timeout_ms内不再转发，类似nginx的max_fails
+//!
+//! 只有一个upstream的location不做健康检查，总是尝试转发
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::reverse::Upstream;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd)]
+#[serde(default)]
+pub(crate) struct HealthConfig {
+    /// 连续失败多少次后标记为不可用，0表示不做健康检查
+    pub(crate) max_fails: u32,
+    /// 标记为不可用的毫秒数，之后重新尝试
+    pub(crate) fail_timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            max_fails: 1,
+            fail_timeout_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    fails: u32,
+    down_until: Option<Instant>,
+}
+
+/// 所有upstream的健康状态，key为upstream的url_base
+#[derive(Default)]
+pub(crate) struct UpstreamHealth {
+    states: Mutex<HashMap<String, State>>,
+}
+
+impl UpstreamHealth {
+    /// 过滤掉不可用的upstream，返回空表示没有可用的upstream
+    pub(crate) fn healthy<'a>(&self, upstreams: Vec<&'a Upstream>, config: &HealthConfig) -> Vec<&'a Upstream> {
+        if upstreams.len() <= 1 || config.max_fails == 0 {
+            return upstreams;
+        }
+        let now = Instant::now();
+        let states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        upstreams
+            .into_iter()
+            .filter(|upstream| {
+                states
+                    .get(&upstream.url_base)
+                    .and_then(|state| state.down_until)
+                    .map_or(true, |down_until| down_until <= now)
+            })
+            .collect()
+    }
+
+    /// 记录一次转发的结果，ok为false表示连接失败或者超时
+    pub(crate) fn report(&self, upstream: &Upstream, ok: bool, config: &HealthConfig) {
+        if config.max_fails == 0 {
+            return;
+        }
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        if ok {
+            states.remove(&upstream.url_base);
+            return;
+        }
+        let state = states.entry(upstream.url_base.clone()).or_default();
+        state.fails += 1;
+        if state.fails >= config.max_fails {
+            state.fails = 0;
+            state.down_until = Some(Instant::now() + Duration::from_millis(config.fail_timeout_ms));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse::Version;
+
+    fn upstream(url_base: &str) -> Upstream {
+        Upstream {
+            url_base: url_base.to_owned(),
+            version: Version::Auto,
+        }
+    }
+
+    #[test]
+    fn test_health() {
+        let health = UpstreamHealth::default();
+        let config = HealthConfig {
+            max_fails: 2,
+            fail_timeout_ms: 60_000,
+        };
+        let (a, b) = (upstream("http://a"), upstream("http://b"));
+        health.report(&a, false, &config);
+        assert_eq!(health.healthy(vec![&a, &b], &config).len(), 2);
+        health.report(&a, false, &config);
+        let healthy = health.healthy(vec![&a, &b], &config);
+        assert_eq!(healthy.len(), 1);
+        assert_eq!(healthy[0].url_base, "http://b");
+
+        health.report(&b, false, &config);
+        health.report(&b, false, &config);
+        assert!(health.healthy(vec![&a, &b], &config).is_empty());
+        // 只有一个upstream时不过滤
+        assert_eq!(health.healthy(vec![&a], &config).len(), 1);
+
+        // 成功后恢复
+        health.report(&a, true, &config);
+        assert_eq!(health.healthy(vec![&a, &b], &config).len(), 1);
+
+        let expired = HealthConfig {
+            max_fails: 1,
+            fail_timeout_ms: 0,
+        };
+        health.report(&b, false, &expired);
+        assert_eq!(health.healthy(vec![&a, &b], &expired).len(), 2);
+    }
+}
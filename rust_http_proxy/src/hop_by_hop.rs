@@ -0,0 +1,188 @@
+//! RFC 7230 6.1的逐跳头部：只对单个连接有意义，代理转发前需要删除
+//!
+//! 同时拒绝Content-Length和Transfer-Encoding有歧义的请求，避免与上游对请求边界的理解不一致（请求走私）
+
+use http::{
+    header::{self, HeaderName},
+    HeaderMap, HeaderValue, Version,
+};
+
+/// 固定的逐跳头部，包括发给代理自己的Proxy-Connection和Proxy-Authorization；Connection中列出的头部也是逐跳的
+const HOP_BY_HOP: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Connection头中列出的token（小写）
+fn connection_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// 删除逐跳头部
+///
+/// upgrade为true时保留Upgrade和Connection中列出的扩展头部（例如h2c的HTTP2-Settings），由上游完成协议升级；
+/// 客户端接受trailers时保留 `TE: trailers`，gRPC等依赖trailers的协议需要它
+pub(crate) fn remove_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let tokens = connection_tokens(headers);
+    let accepts_trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            coding
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case("trailers")
+        });
+    let upgrade_value = headers.get(header::UPGRADE).cloned();
+
+    let mut kept = vec![];
+    for token in &tokens {
+        let Ok(name) = HeaderName::from_bytes(token.as_bytes()) else {
+            continue;
+        };
+        if upgrade && !HOP_BY_HOP.contains(&name) {
+            kept.push(name);
+        } else {
+            headers.remove(name);
+        }
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+
+    if accepts_trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+    if let (true, Some(upgrade_value)) = (upgrade, upgrade_value) {
+        headers.insert(header::UPGRADE, upgrade_value);
+        let connection = std::iter::once("upgrade")
+            .chain(kept.iter().map(HeaderName::as_str))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(connection) = HeaderValue::from_str(&connection) {
+            headers.insert(header::CONNECTION, connection);
+        }
+    }
+}
+
+/// 检查HTTP/1.x请求的消息边界，有歧义时返回原因
+///
+/// - 同时有Content-Length和Transfer-Encoding
+/// - 多个不一致或者不合法的Content-Length
+/// - Transfer-Encoding的最后一个编码不是chunked，或者HTTP/1.0请求带有Transfer-Encoding
+pub(crate) fn check_framing(headers: &HeaderMap, version: Version) -> Result<(), &'static str> {
+    if version != Version::HTTP_10 && version != Version::HTTP_11 {
+        return Ok(());
+    }
+    let has_content_length = headers.contains_key(header::CONTENT_LENGTH);
+    let transfer_encodings = headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .map(|value| value.to_str().map_err(|_| "invalid Transfer-Encoding"))
+        .collect::<Result<Vec<_>, _>>()?;
+    if !transfer_encodings.is_empty() {
+        if has_content_length {
+            return Err("both Content-Length and Transfer-Encoding present");
+        }
+        if version == Version::HTTP_10 {
+            return Err("Transfer-Encoding in HTTP/1.0 request");
+        }
+        let last = transfer_encodings
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .rfind(|coding| !coding.is_empty());
+        if !last.is_some_and(|coding| coding.eq_ignore_ascii_case("chunked")) {
+            return Err("final Transfer-Encoding is not chunked");
+        }
+    }
+    let mut content_length = None;
+    for value in headers.get_all(header::CONTENT_LENGTH) {
+        for length in value.to_str().map_err(|_| "invalid Content-Length")?.split(',') {
+            let length = length.trim();
+            if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err("invalid Content-Length");
+            }
+            let length = length.parse::<u64>().map_err(|_| "invalid Content-Length")?;
+            if content_length.is_some_and(|previous| previous != length) {
+                return Err("conflicting Content-Length");
+            }
+            content_length = Some(length);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_remove_hop_by_hop() {
+        let mut map = headers(&[
+            ("connection", "keep-alive, x-secret"),
+            ("keep-alive", "timeout=5"),
+            ("x-secret", "1"),
+            ("te", "trailers, deflate;q=0.5"),
+            ("transfer-encoding", "chunked"),
+            ("proxy-authorization", "Basic dXNlcjpwYXNz"),
+            ("upgrade", "websocket"),
+            ("accept", "*/*"),
+        ]);
+        remove_hop_by_hop(&mut map, false);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(header::TE).map(HeaderValue::as_bytes), Some(&b"trailers"[..]));
+        assert!(map.contains_key(header::ACCEPT));
+
+        let mut map = headers(&[
+            ("connection", "Upgrade, HTTP2-Settings, keep-alive"),
+            ("keep-alive", "timeout=5"),
+            ("upgrade", "h2c"),
+            ("http2-settings", "AAMAAABkAAQAAP__"),
+        ]);
+        remove_hop_by_hop(&mut map, true);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(header::CONNECTION).map(HeaderValue::as_bytes), Some(&b"upgrade, http2-settings"[..]));
+        assert_eq!(map.get(header::UPGRADE).map(HeaderValue::as_bytes), Some(&b"h2c"[..]));
+        assert!(map.contains_key("http2-settings"));
+    }
+
+    #[test]
+    fn test_check_framing() {
+        let v11 = Version::HTTP_11;
+        assert!(check_framing(&headers(&[("content-length", "10")]), v11).is_ok());
+        assert!(check_framing(&headers(&[("content-length", "10, 10")]), v11).is_ok());
+        assert!(check_framing(&headers(&[("transfer-encoding", "gzip, chunked")]), v11).is_ok());
+        assert!(check_framing(&headers(&[("content-length", "10"), ("transfer-encoding", "chunked")]), v11).is_err());
+        assert!(check_framing(&headers(&[("content-length", "10"), ("content-length", "11")]), v11).is_err());
+        assert!(check_framing(&headers(&[("content-length", "+10")]), v11).is_err());
+        assert!(check_framing(&headers(&[("transfer-encoding", "chunked, gzip")]), v11).is_err());
+        assert!(check_framing(&headers(&[("transfer-encoding", "chunked")]), Version::HTTP_10).is_err());
+        assert!(check_framing(&headers(&[("transfer-encoding", "chunked")]), Version::HTTP_2).is_ok());
+    }
+}
@@ -0,0 +1,187 @@
+//! 防盗链：按扩展名或者路径glob匹配受保护的静态文件，要求Referer的host在允许列表中
+
+use std::str::FromStr;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use http::{StatusCode, Uri};
+
+/// 没有指定--hotlink-pattern时保护的文件
+const DEFAULT_PATTERNS: &[&str] = &[".png", ".jpeg", ".jpg"];
+
+/// 被拦截时的响应
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HotlinkAction {
+    Status(StatusCode),
+    /// 302重定向到占位图片
+    Redirect(String),
+}
+
+impl FromStr for HotlinkAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "403" => Ok(HotlinkAction::Status(StatusCode::FORBIDDEN)),
+            "404" => Ok(HotlinkAction::Status(StatusCode::NOT_FOUND)),
+            target if target.starts_with('/') || target.starts_with("http://") || target.starts_with("https://") => {
+                target
+                    .parse::<Uri>()
+                    .map_err(|e| format!("wrong hotlink redirect target {}: {}", target, e))?;
+                Ok(HotlinkAction::Redirect(target.to_owned()))
+            }
+            other => Err(format!("hotlink action should be 403, 404 or a redirect url: {}", other)),
+        }
+    }
+}
+
+/// 防盗链规则。allow_hosts为空时不启用
+pub(crate) struct HotlinkProtection {
+    patterns: GlobSet,
+    /// 小写的host，`*.example.com`匹配example.com的子域名
+    allow_hosts: Vec<String>,
+    allow_empty_referer: bool,
+    pub(crate) action: HotlinkAction,
+}
+
+impl Default for HotlinkProtection {
+    fn default() -> Self {
+        HotlinkProtection {
+            patterns: GlobSet::empty(),
+            allow_hosts: vec![],
+            allow_empty_referer: true,
+            action: HotlinkAction::Status(StatusCode::FORBIDDEN),
+        }
+    }
+}
+
+impl HotlinkProtection {
+    /// patterns的规则与缓存规则相同：包含`/`时匹配请求路径，否则匹配文件名，`.png`等价于`*.png`
+    pub(crate) fn new(
+        patterns: &[String], allow_hosts: &[String], allow_empty_referer: bool, action: HotlinkAction,
+    ) -> Result<Self, String> {
+        let mut builder = GlobSetBuilder::new();
+        let defaults = DEFAULT_PATTERNS.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let patterns = if patterns.is_empty() { &defaults } else { patterns };
+        for pattern in patterns {
+            builder.add(compile_pattern(pattern)?);
+        }
+        let patterns = builder.build().map_err(|e| format!("wrong hotlink patterns: {}", e))?;
+        Ok(HotlinkProtection {
+            patterns,
+            allow_hosts: allow_hosts
+                .iter()
+                .map(|host| host.trim().to_ascii_lowercase())
+                .collect(),
+            allow_empty_referer,
+            action,
+        })
+    }
+
+    pub(crate) fn allow_hosts(&self) -> &[String] {
+        &self.allow_hosts
+    }
+
+    /// 检查请求，被拦截时返回用于统计的Referer host
+    pub(crate) fn check(&self, path: &str, referer: &str) -> Option<String> {
+        if self.allow_hosts.is_empty() || !self.is_protected(path) {
+            return None;
+        }
+        // 占位图片本身不拦截，否则会循环重定向
+        if let HotlinkAction::Redirect(target) = &self.action {
+            if target == path {
+                return None;
+            }
+        }
+        if referer.is_empty() {
+            return if self.allow_empty_referer {
+                None
+            } else {
+                Some(EMPTY_REFERER.to_owned())
+            };
+        }
+        match referer_host(referer) {
+            Some(host) if self.is_allowed(&host) => None,
+            Some(host) => Some(host),
+            None => Some(INVALID_REFERER.to_owned()),
+        }
+    }
+
+    fn is_protected(&self, path: &str) -> bool {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        self.patterns.is_match(path) || self.patterns.is_match(file_name)
+    }
+
+    fn is_allowed(&self, host: &str) -> bool {
+        self.allow_hosts.iter().any(|allow| match allow.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.')),
+            None => allow == host,
+        })
+    }
+}
+
+/// 统计时空Referer的label值
+const EMPTY_REFERER: &str = "empty";
+/// 统计时无法解析的Referer的label值
+const INVALID_REFERER: &str = "invalid";
+
+fn compile_pattern(pattern: &str) -> Result<Glob, String> {
+    let match_path = pattern.contains('/');
+    let normalized = match pattern.strip_prefix('.') {
+        Some(extension) if !match_path => format!("*.{}", extension),
+        _ => pattern.to_owned(),
+    };
+    // 扩展名不区分大小写，避免a.JPG绕过
+    GlobBuilder::new(&normalized)
+        .literal_separator(match_path)
+        .case_insensitive(!match_path)
+        .build()
+        .map_err(|e| format!("wrong hotlink pattern {}: {}", pattern, e))
+}
+
+/// Referer中的host，小写，不含端口
+fn referer_host(referer: &str) -> Option<String> {
+    let uri = referer.parse::<Uri>().ok()?;
+    match uri.scheme_str() {
+        Some("http" | "https") => uri.host().map(|host| host.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() -> Result<(), String> {
+        let protection = HotlinkProtection::new(
+            &[".webp".to_owned(), "/static/**".to_owned()],
+            &["example.com".to_owned(), "*.cdn.example.com".to_owned()],
+            false,
+            "/hotlink.webp".parse()?,
+        )?;
+        assert_eq!(protection.check("/a/b.webp", "https://example.com/page"), None);
+        assert_eq!(protection.check("/a/b.webp", "https://img.cdn.example.com:8443/"), None);
+        // 没有子串匹配
+        assert_eq!(
+            protection.check("/a/b.webp", "https://example.com.evil.org/"),
+            Some("example.com.evil.org".to_owned())
+        );
+        assert_eq!(protection.check("/a/b.webp", "https://evil.org/?example.com"), Some("evil.org".to_owned()));
+        assert_eq!(protection.check("/a/b.webp", "https://badcdn.example.com/"), Some("badcdn.example.com".to_owned()));
+        assert_eq!(protection.check("/static/app.js", ""), Some("empty".to_owned()));
+        assert_eq!(protection.check("/a/b.webp", "not a url"), Some("invalid".to_owned()));
+        // 不受保护的文件和占位图片不拦截
+        assert_eq!(protection.check("/a/b.png", "https://evil.org/"), None);
+        assert_eq!(protection.check("/hotlink.webp", "https://evil.org/"), None);
+
+        let defaults = HotlinkProtection::new(&[], &["example.com".to_owned()], true, "404".parse()?)?;
+        assert_eq!(defaults.check("/a.JPG", "https://evil.org/"), Some("evil.org".to_owned()));
+        assert_eq!(defaults.check("/a.jpg", "https://EXAMPLE.com/"), None);
+        assert_eq!(defaults.check("/a.jpg", ""), None);
+        assert_eq!(defaults.action, HotlinkAction::Status(StatusCode::NOT_FOUND));
+        // 没有允许的host时不启用
+        assert_eq!(HotlinkProtection::default().check("/a.jpg", "https://evil.org/"), None);
+        assert!("500".parse::<HotlinkAction>().is_err());
+        Ok(())
+    }
+}
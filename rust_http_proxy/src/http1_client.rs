@@ -1,36 +1,55 @@
 //! HTTP Client
 
 use std::{
-    collections::VecDeque,
     error::Error,
     fmt::Debug,
     io::{self, ErrorKind},
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use http::{header, HeaderMap, HeaderValue, Version};
+use http::{header, uri::Parts, HeaderMap, HeaderValue, Uri, Version};
 use hyper::{
     body::{self, Body},
-    client::conn::http1,
+    client::conn::{http1, http2},
     http::uri::Scheme,
     Request, Response,
 };
-use hyper_util::rt::TokioIo;
-use io_x::{CounterIO, TimeoutIO};
+use hyper_rustls::ConfigBuilderExt;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use io_x::{CounterIO, IoStats, StatsIO, TimeoutIO};
 use log::{debug, error, info, trace, warn};
-use lru_time_cache::LruCache;
 use prom_label::LabelImpl;
-use tokio::{net::TcpStream, sync::Mutex};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig},
+    TlsConnector,
+};
 
+use crate::access_log::AccessLog;
+use crate::connector;
+use crate::dns::Resolver;
+use crate::latency::LatencyMetrics;
+use crate::pool::{Pool, PoolConfig, PoolKey, PoolMetrics, Poolable};
 use crate::proxy::AccessLabel;
+use crate::tunnel::{CloseReason, TunnelKind, TunnelMetrics, TunnelStats};
 
 const CONNECTION_EXPIRE_DURATION: Duration = Duration::from_secs(if !cfg!(debug_assertions) { 30 } else { 10 });
 
+/// 请求没有发出时（例如复用的连接已经关闭）错误中带有原始的请求，可以换一个连接重新发送
+type SendResult<B> = Result<Response<body::Incoming>, (io::Error, Option<Request<B>>)>;
+
 /// HTTPClient, supporting HTTP/1.1 and H2, HTTPS.
 pub struct HttpClient<B> {
-    #[allow(clippy::type_complexity)]
-    cache_conn: Arc<Mutex<LruCache<AccessLabel, VecDeque<(HttpConnection<B>, Instant)>>>>,
+    pool: Pool<HttpConnection<B>>,
+    tls_connector: TlsConnector,
+    tunnel_metrics: TunnelMetrics,
+    resolver: Resolver,
+    latency: LatencyMetrics,
+    access_log: AccessLog,
 }
 
 impl<B> HttpClient<B>
@@ -40,84 +59,112 @@ where
     B::Error: Into<Box<dyn ::std::error::Error + Send + Sync>>,
 {
     /// Create a new HttpClient
-    pub fn new() -> HttpClient<B> {
-        HttpClient {
-            cache_conn: Arc::new(Mutex::new(LruCache::with_expiry_duration(CONNECTION_EXPIRE_DURATION))),
-        }
+    ///
+    /// 上游连接关闭时，会记录到tunnel_metrics和access_log
+    pub fn new(
+        pool_config: PoolConfig, pool_metrics: PoolMetrics, tunnel_metrics: TunnelMetrics, resolver: Resolver,
+        latency: LatencyMetrics, access_log: AccessLog,
+    ) -> io::Result<HttpClient<B>> {
+        // 使用系统的证书校验，通过ALPN优先协商h2
+        let mut tls_config = ClientConfig::builder()
+            .try_with_platform_verifier()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?
+            .with_no_client_auth();
+        tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(HttpClient {
+            pool: Pool::new(pool_config, CONNECTION_EXPIRE_DURATION, pool_metrics),
+            tls_connector: TlsConnector::from(Arc::new(tls_config)),
+            tunnel_metrics,
+            resolver,
+            latency,
+            access_log,
+        })
     }
 
     /// Make HTTP requests
+    ///
+    /// req的uri需要是absolute-form，根据协商出的协议转换成origin-form(HTTP/1.1)或者保留scheme和authority(HTTP/2)
+    ///
+    /// 开启连接共享时，上游连接的流量和日志记录在建立连接的客户端上
     #[inline]
     pub async fn send_request(
         &self, req: Request<B>, access_label: &AccessLabel,
         stream_map_func: impl FnOnce(TcpStream, AccessLabel) -> CounterIO<TcpStream, LabelImpl<AccessLabel>>,
     ) -> Result<Response<body::Incoming>, std::io::Error> {
+        let scheme = req.uri().scheme().cloned().unwrap_or(Scheme::HTTP);
+        let key = self.pool.key(access_label, &scheme);
+        let mut req = req;
         // 1. Check if there is an available client
-        if let Some(c) = self.get_cached_connection(access_label).await {
-            debug!("HTTP client for host: {} taken from cache", &access_label);
-            match self.send_request_conn(access_label, c, req).await {
-                Ok(o) => return Ok(o),
-                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        if let Some(c) = self.pool.checkout(&key) {
+            debug!("HTTP client for host: {} taken from pool", &access_label);
+            match self.send_request_conn(access_label, key.clone(), c, req).await {
+                Ok(resp) => return Ok(resp),
+                // 复用的连接已经不可用，请求还没有发出，换新连接重新发送
+                Err((err, Some(unsent))) => {
+                    debug!("pooled HTTP connection for host: {} is unavailable: {}", access_label, err);
+                    req = unsent;
+                }
+                Err((err, None)) => return Err(err),
             }
         }
 
         // 2. If no. Make a new connection
-        let scheme = match req.uri().scheme() {
-            Some(s) => s,
-            None => &Scheme::HTTP,
+        let host = req.uri().host().unwrap_or_default();
+        let connecting = async {
+            let stream =
+                connector::connect(&access_label.target, TunnelKind::Forward.as_str(), &self.resolver, &self.latency)
+                    .await?;
+            HttpConnection::connect(
+                &scheme,
+                host,
+                access_label,
+                stream_map_func(stream, access_label.clone()),
+                &self.tls_connector,
+                self.on_close(),
+            )
+            .await
         };
-
-        let c = match HttpConnection::connect(scheme, access_label, stream_map_func).await {
+        let c = match connecting.await {
             Ok(c) => c,
             Err(err) => {
                 error!("failed to connect to host: {}, error: {}", &access_label.target, err);
                 return Err(io::Error::new(io::ErrorKind::InvalidData, err));
             }
         };
+        self.pool.metrics().on_connect();
+        // HTTP/2连接可以多路复用，建立之后立即放入连接池，供并发的请求共享
+        if let Some(shared) = c.share() {
+            self.pool.put(key.clone(), shared);
+        }
 
-        self.send_request_conn(access_label, c, req)
+        self.send_request_conn(access_label, key, c, req)
             .await
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .map_err(|(err, _)| err)
     }
 
-    async fn get_cached_connection(&self, access_label: &AccessLabel) -> Option<HttpConnection<B>> {
-        if let Some(q) = self.cache_conn.lock().await.get_mut(access_label) {
-            debug!("HTTP client for host: {} found in cache, len: {}", access_label, q.len());
-            while let Some((c, inst)) = q.pop_front() {
-                let now = Instant::now();
-                if now - inst >= CONNECTION_EXPIRE_DURATION {
-                    debug!("HTTP connection for host: {} expired", access_label,);
-                    continue;
-                }
-                if c.is_closed() {
-                    // true at once after connection.await return
-                    debug!("HTTP connection for host: {} is closed", access_label,);
-                    continue;
-                }
-                return Some(c);
-            }
-        } else {
-            debug!("HTTP client for host: {} not found in cache", access_label);
+    /// 上游连接关闭时的回调
+    fn on_close(&self) -> impl FnOnce(&AccessLabel, TunnelStats) + Send + 'static {
+        let tunnel_metrics = self.tunnel_metrics.clone();
+        let access_log = self.access_log.clone();
+        let pool_metrics = self.pool.metrics().clone();
+        move |access_label: &AccessLabel, stats: TunnelStats| {
+            pool_metrics.on_connection_close();
+            tunnel_metrics.observe(TunnelKind::Forward, &stats);
+            access_log.log_proxy_conn(&access_label.client, &access_label.username, &access_label.target, &stats);
         }
-        None
     }
 
     async fn send_request_conn(
-        &self, access_label: &AccessLabel, mut c: HttpConnection<B>, req: Request<B>,
-    ) -> hyper::Result<Response<body::Incoming>> {
+        &self, access_label: &AccessLabel, key: PoolKey, mut c: HttpConnection<B>, req: Request<B>,
+    ) -> SendResult<B> {
         trace!("HTTP making request to host: {}, request: {:?}", access_label, req);
         let response = c.send_request(req).await?;
         trace!("HTTP received response from host: {}, response: {:?}", access_label, response);
 
-        // Check keep-alive
-        if check_keep_alive(response.version(), response.headers(), false) {
+        // Check keep-alive, HTTP/2连接已经在连接池中
+        if matches!(c, HttpConnection::Http1(_)) && check_keep_alive(response.version(), response.headers(), false) {
             trace!("HTTP connection keep-alive for host: {}, response: {:?}", access_label, response);
-            self.cache_conn
-                .lock()
-                .await
-                .entry(access_label.clone())
-                .or_insert_with(VecDeque::new)
-                .push_back((c, Instant::now()));
+            self.pool.put(key, c);
         }
 
         Ok(response)
@@ -168,9 +215,10 @@ fn get_keep_alive_val(values: header::GetAll<HeaderValue>) -> Option<bool> {
     conn_keep_alive
 }
 
-#[allow(dead_code)]
 enum HttpConnection<B> {
     Http1(http1::SendRequest<B>),
+    /// SendRequest可以clone，多个请求在同一个连接上多路复用
+    Http2(http2::SendRequest<B>),
 }
 
 impl<B> HttpConnection<B>
@@ -180,30 +228,42 @@ where
     B::Error: Into<Box<dyn ::std::error::Error + Send + Sync>>,
 {
     async fn connect(
-        scheme: &Scheme, access_label: &AccessLabel,
-        stream_map_func: impl FnOnce(TcpStream, AccessLabel) -> CounterIO<TcpStream, LabelImpl<AccessLabel>>,
+        scheme: &Scheme, host: &str, access_label: &AccessLabel, stream: CounterIO<TcpStream, LabelImpl<AccessLabel>>,
+        tls_connector: &TlsConnector, on_close: impl FnOnce(&AccessLabel, TunnelStats) + Send + 'static,
     ) -> io::Result<HttpConnection<B>> {
         if *scheme != Scheme::HTTP && *scheme != Scheme::HTTPS {
             return Err(io::Error::new(ErrorKind::InvalidInput, "invalid scheme"));
         }
 
-        let stream = TcpStream::connect(&access_label.target).await?;
-        let stream: CounterIO<TcpStream, LabelImpl<AccessLabel>> = stream_map_func(stream, access_label.clone());
+        // CounterIO在TLS之下，统计的是实际传输的字节数
+        let stream = StatsIO::new(TimeoutIO::new(stream, CONNECTION_EXPIRE_DURATION));
+        let io_stats = stream.stats();
 
-        HttpConnection::connect_http_http1(scheme, access_label, stream).await
+        if *scheme == Scheme::HTTP {
+            return HttpConnection::connect_http_http1(access_label, Box::pin(stream), io_stats, on_close).await;
+        }
+        let tls_stream = tls_connector.connect(server_name(host)?, Box::pin(stream)).await?;
+        if tls_stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+            HttpConnection::connect_http_http2(access_label, tls_stream, io_stats, on_close).await
+        } else {
+            HttpConnection::connect_http_http1(access_label, tls_stream, io_stats, on_close).await
+        }
     }
 
-    async fn connect_http_http1(
-        scheme: &Scheme, access_label: &AccessLabel, stream: CounterIO<TcpStream, LabelImpl<AccessLabel>>,
-    ) -> io::Result<HttpConnection<B>> {
-        trace!("HTTP making new HTTP/1.1 connection to host: {}, scheme: {}", access_label, scheme);
-        let stream = TimeoutIO::new(stream, CONNECTION_EXPIRE_DURATION);
+    async fn connect_http_http1<S>(
+        access_label: &AccessLabel, stream: S, io_stats: Arc<IoStats>,
+        on_close: impl FnOnce(&AccessLabel, TunnelStats) + Send + 'static,
+    ) -> io::Result<HttpConnection<B>>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        trace!("HTTP making new HTTP/1.1 connection to host: {}", access_label);
 
         // HTTP/1.x
         let (send_request, connection) = match http1::Builder::new()
             .preserve_header_case(true)
             .title_case_headers(true)
-            .handshake(Box::pin(TokioIo::new(stream)))
+            .handshake(TokioIo::new(stream))
             .await
         {
             Ok(s) => s,
@@ -212,27 +272,117 @@ where
 
         let access_label = access_label.clone();
         tokio::spawn(async move {
-            if let Err(err) = connection.await {
+            let result = connection.await;
+            on_close(&access_label, TunnelStats::new(&io_stats, CloseReason::from_hyper_result(&result)));
+            if let Err(err) = result {
                 handle_http1_connection_error(err, access_label);
             }
         });
         Ok(HttpConnection::Http1(send_request))
     }
 
+    async fn connect_http_http2<S>(
+        access_label: &AccessLabel, stream: S, io_stats: Arc<IoStats>,
+        on_close: impl FnOnce(&AccessLabel, TunnelStats) + Send + 'static,
+    ) -> io::Result<HttpConnection<B>>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        trace!("HTTP making new HTTP/2 connection to host: {}", access_label);
+
+        let (send_request, connection) = http2::Builder::new(TokioExecutor::new())
+            .timer(TokioTimer::new())
+            .handshake(TokioIo::new(stream))
+            .await
+            .map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err))?;
+
+        let access_label = access_label.clone();
+        tokio::spawn(async move {
+            let result = connection.await;
+            on_close(&access_label, TunnelStats::new(&io_stats, CloseReason::from_hyper_result(&result)));
+            if let Err(err) = result {
+                handle_http1_connection_error(err, access_label);
+            }
+        });
+        Ok(HttpConnection::Http2(send_request))
+    }
+
     #[inline]
-    pub async fn send_request(&mut self, req: Request<B>) -> hyper::Result<Response<body::Incoming>> {
+    pub async fn send_request(&mut self, mut req: Request<B>) -> SendResult<B> {
+        let (uri, host) = (req.uri().clone(), req.headers().get(header::HOST).cloned());
+        let result = match self {
+            HttpConnection::Http1(r) => {
+                adapt_request(&mut req, Version::HTTP_11).map_err(|e| (e, None))?;
+                r.try_send_request(req).await
+            }
+            HttpConnection::Http2(r) => {
+                adapt_request(&mut req, Version::HTTP_2).map_err(|e| (e, None))?;
+                r.try_send_request(req).await
+            }
+        };
+        result.map_err(|mut e| {
+            // 还原成absolute-form，新连接可能协商出不同的协议
+            let unsent = e.take_message().map(|mut req| {
+                *req.uri_mut() = uri;
+                if let Some(host) = host {
+                    req.headers_mut().insert(header::HOST, host);
+                }
+                req
+            });
+            (io::Error::new(ErrorKind::InvalidData, e.into_error()), unsent)
+        })
+    }
+}
+
+impl<B> Poolable for HttpConnection<B> {
+    fn is_closed(&self) -> bool {
         match self {
-            HttpConnection::Http1(r) => r.send_request(req).await,
+            HttpConnection::Http1(r) => r.is_closed(),
+            HttpConnection::Http2(r) => r.is_closed(),
         }
     }
 
-    pub fn is_closed(&self) -> bool {
+    fn share(&self) -> Option<Self> {
         match self {
-            HttpConnection::Http1(r) => r.is_closed(),
+            HttpConnection::Http1(_) => None,
+            HttpConnection::Http2(r) => Some(HttpConnection::Http2(r.clone())),
         }
     }
 }
 
+/// HTTP/1.1使用origin-form；HTTP/2需要absolute-form来生成:scheme和:authority，Host头由:authority代替
+fn adapt_request<B>(req: &mut Request<B>, version: Version) -> io::Result<()> {
+    *req.version_mut() = version;
+    if version == Version::HTTP_2 {
+        req.headers_mut().remove(header::HOST);
+        Ok(())
+    } else {
+        origin_form(req.uri_mut())
+    }
+}
+
+fn origin_form(uri: &mut Uri) -> io::Result<()> {
+    let path = match uri.path_and_query() {
+        Some(path) if path.as_str() != "/" => {
+            let mut parts = Parts::default();
+            parts.path_and_query = Some(path.clone());
+            Uri::from_parts(parts).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+        }
+        _none_or_just_slash => {
+            debug_assert!(Uri::default() == "/");
+            Uri::default()
+        }
+    };
+    *uri = path;
+    Ok(())
+}
+
+/// TLS的SNI和证书校验使用的域名，IPv6地址需要去掉方括号
+fn server_name(host: &str) -> io::Result<ServerName<'static>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_owned()).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
 fn handle_http1_connection_error(err: hyper::Error, access_label: AccessLabel) {
     if let Some(source) = err.source() {
         if let Some(io_err) = source.downcast_ref::<io::Error>() {
@@ -249,3 +399,36 @@ fn handle_http1_connection_error(err: hyper::Error, access_label: AccessLabel) {
         warn!("[legacy proxy io error] [{}] to {}", err, access_label);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapt_request() -> io::Result<()> {
+        let mut req = Request::builder()
+            .uri("https://example.com:8443/a?b=c")
+            .header(header::HOST, "example.com:8443")
+            .body(())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let mut h2_req = Request::builder()
+            .uri(req.uri().clone())
+            .header(header::HOST, "example.com:8443")
+            .body(())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        adapt_request(&mut req, Version::HTTP_11)?;
+        assert_eq!(req.uri(), "/a?b=c");
+        assert_eq!(req.headers().get(header::HOST), Some(&HeaderValue::from_static("example.com:8443")));
+
+        adapt_request(&mut h2_req, Version::HTTP_2)?;
+        assert_eq!(h2_req.version(), Version::HTTP_2);
+        assert_eq!(h2_req.uri(), "https://example.com:8443/a?b=c");
+        assert!(h2_req.headers().get(header::HOST).is_none());
+
+        assert!(server_name("[::1]").is_ok());
+        assert!(server_name("example.com").is_ok());
+        assert!(server_name("").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! 延迟直方图：DNS解析、上游建连、反向代理响应头、静态文件服务
+//!
+//! label只使用配置中的location、upstream等有限取值，不使用客户端IP和正向代理的目标地址
+
+use std::time::Duration;
+
+use prom_label::LabelImpl;
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{
+        family::Family,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+/// 正向代理的目标地址由客户端决定，不能作为label
+pub(crate) const FORWARD_HOST: &str = "-";
+
+#[derive(Clone)]
+pub(crate) struct LatencyMetrics {
+    dns: Family<LabelImpl<DnsLabel>, Histogram>,
+    connect: Family<LabelImpl<ConnectLabel>, Histogram>,
+    reverse_response: Family<LabelImpl<ReverseResponseLabel>, Histogram>,
+    static_serve: Family<LabelImpl<StaticServeLabel>, Histogram>,
+}
+
+pub(crate) fn latency_histogram() -> Histogram {
+    // 1ms ~ 32s
+    Histogram::new(exponential_buckets(0.001, 2.0, 16))
+}
+
+impl LatencyMetrics {
+    pub(crate) fn register(registry: &mut Registry) -> Self {
+        let dns = Family::<LabelImpl<DnsLabel>, Histogram>::new_with_constructor(latency_histogram);
+        registry.register("upstream_dns_seconds", "DNS resolution time of upstream hosts", dns.clone());
+        let connect = Family::<LabelImpl<ConnectLabel>, Histogram>::new_with_constructor(latency_histogram);
+        registry.register("upstream_connect_seconds", "TCP connect time to upstream", connect.clone());
+        let reverse_response =
+            Family::<LabelImpl<ReverseResponseLabel>, Histogram>::new_with_constructor(latency_histogram);
+        registry.register(
+            "reverse_proxy_response_seconds",
+            "Time to response headers of reverse proxy upstream",
+            reverse_response.clone(),
+        );
+        let static_serve = Family::<LabelImpl<StaticServeLabel>, Histogram>::new_with_constructor(latency_histogram);
+        registry.register(
+            "static_serve_seconds",
+            "Time to response headers of static file serving",
+            static_serve.clone(),
+        );
+        LatencyMetrics {
+            dns,
+            connect,
+            reverse_response,
+            static_serve,
+        }
+    }
+
+    pub(crate) fn observe_dns(&self, kind: &'static str, host: &str, elapsed: Duration, ok: bool) {
+        self.dns
+            .get_or_create(&LabelImpl::new(DnsLabel {
+                kind,
+                host: host.to_owned(),
+                result: result_str(ok),
+            }))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn observe_connect(&self, kind: &'static str, elapsed: Duration, ok: bool) {
+        self.connect
+            .get_or_create(&LabelImpl::new(ConnectLabel {
+                kind,
+                result: result_str(ok),
+            }))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn observe_reverse_response(&self, location: &str, upstream: &str, elapsed: Duration, ok: bool) {
+        self.reverse_response
+            .get_or_create(&LabelImpl::new(ReverseResponseLabel {
+                location: location.to_owned(),
+                upstream: upstream.to_owned(),
+                result: result_str(ok),
+            }))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn observe_static_serve(&self, status: http::StatusCode, elapsed: Duration) {
+        self.static_serve
+            .get_or_create(&LabelImpl::new(StaticServeLabel {
+                status: status.as_u16(),
+            }))
+            .observe(elapsed.as_secs_f64());
+    }
+}
+
+fn result_str(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "error"
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct DnsLabel {
+    pub kind: &'static str,
+    pub host: String,
+    pub result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct ConnectLabel {
+    pub kind: &'static str,
+    pub result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct ReverseResponseLabel {
+    pub location: String,
+    pub upstream: String,
+    pub result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct StaticServeLabel {
+    pub status: u16,
+}
@@ -0,0 +1,143 @@
+//! 接受PROXY protocol的端口：axum-bootstrap自己accept连接，拿不到连接开头的字节，所以这些端口使用单独的accept循环
+//!
+//! 读取头部之后的处理与其他端口相同：先经过ProxyHandler，Continue的请求交给axum的Router，错误转换为AppError的响应
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+use axum::{response::IntoResponse, Router};
+use axum_bootstrap::AppError;
+use http::Request;
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use io_x::TimeoutIO;
+use log::{info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tower_service::Service;
+
+use crate::{
+    proxy::{InterceptResultAdapter, ProxyHandler},
+    proxy_protocol, DynError, IDLE_TIMEOUT,
+};
+
+/// 等待PROXY protocol头部的时间
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// 重新加载证书的间隔，与其他端口一样每天加载一次
+const CERT_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub(crate) async fn serve(port: u16, proxy_handler: Arc<ProxyHandler>, router: Router) -> Result<(), DynError> {
+    let config = &proxy_handler.config;
+    let tls_acceptor = match config.over_tls {
+        true => {
+            let tls_acceptor = Arc::new(RwLock::new(tls_acceptor(&config.cert, &config.key)?));
+            tokio::spawn(refresh_tls_acceptor(tls_acceptor.clone(), config.cert.clone(), config.key.clone()));
+            Some(tls_acceptor)
+        }
+        false => None,
+    };
+    let listener = TcpListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).await?;
+    info!("listening on port {} with PROXY protocol", port);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("accept error: {}", e);
+                continue;
+            }
+        };
+        let proxy_handler = proxy_handler.clone();
+        let router = router.clone();
+        let tls_acceptor = tls_acceptor
+            .as_ref()
+            .map(|tls_acceptor| tls_acceptor.read().unwrap_or_else(PoisonError::into_inner).clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, peer, proxy_handler, router, tls_acceptor).await {
+                info!("connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(
+    mut stream: TcpStream, peer: SocketAddr, proxy_handler: Arc<ProxyHandler>, router: Router,
+    tls_acceptor: Option<TlsAcceptor>,
+) -> Result<(), DynError> {
+    // 不受信任的来源不读取头部，带有头部时HTTP解析失败
+    let client_socket_addr = match proxy_handler.config.proxy_protocol_trusted.contains(peer.ip()) {
+        true => tokio::time::timeout(HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timeout reading PROXY protocol header"))??
+            .map_or(peer, |header| header.source),
+        false => peer,
+    };
+    let stream = Box::pin(TimeoutIO::new(stream, IDLE_TIMEOUT));
+    match tls_acceptor {
+        Some(tls_acceptor) => {
+            let stream = tls_acceptor.accept(stream).await?;
+            serve_http(stream, client_socket_addr, proxy_handler, router).await
+        }
+        None => serve_http(stream, client_socket_addr, proxy_handler, router).await,
+    }
+}
+
+/// 与axum-bootstrap的ReqInterceptor相同：Return直接响应，Continue交给Router，Err转换为AppError的响应
+async fn serve_http<IO>(
+    io: IO, client_socket_addr: SocketAddr, proxy_handler: Arc<ProxyHandler>, router: Router,
+) -> Result<(), DynError>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = service_fn(move |req: Request<Incoming>| {
+        let proxy_handler = proxy_handler.clone();
+        let mut router = router.clone();
+        async move {
+            let resp = match proxy_handler.proxy(req, client_socket_addr).await {
+                Ok(InterceptResultAdapter::Return(resp)) => resp.map(axum::body::Body::new),
+                Ok(InterceptResultAdapter::Continue(req)) => {
+                    router.call(req).await.unwrap_or_else(|never| match never {})
+                }
+                Err(err) => AppError::new(err).into_response(),
+            };
+            Ok::<_, io::Error>(resp)
+        }
+    });
+    auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(io), service)
+        .await
+}
+
+/// 定时重新加载证书，加载失败时继续使用之前的证书
+async fn refresh_tls_acceptor(tls_acceptor: Arc<RwLock<TlsAcceptor>>, cert: String, key: String) {
+    let mut interval = tokio::time::interval(CERT_REFRESH_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match self::tls_acceptor(&cert, &key) {
+            Ok(new) => *tls_acceptor.write().unwrap_or_else(PoisonError::into_inner) = new,
+            Err(e) => warn!("reload certificate {} error: {}", cert, e),
+        }
+    }
+}
+
+fn tls_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, DynError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?)).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| format!("no private key in {}", key))?;
+    let mut tls_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
@@ -1,17 +1,45 @@
 #![deny(warnings)]
 #![deny(clippy::unwrap_used)]
 #![deny(clippy::expect_used)]
+mod access_log;
 mod address;
+mod analytics;
+mod autoindex;
+mod body_limit;
+mod cache_rule;
 mod config;
+mod connector;
+mod dns;
+mod dns_message;
 #[cfg(all(target_os = "linux", feature = "bpf"))]
 mod ebpf;
+mod encoding;
+mod error_page;
+mod forwarded;
+mod health;
+mod hop_by_hop;
+mod hotlink;
 mod http1_client;
 mod ip_x;
+mod latency;
 #[cfg(target_os = "linux")]
 mod linux_monitor;
+mod listener;
+#[cfg(feature = "otel")]
+mod otel;
+mod pool;
 mod proxy;
+mod proxy_protocol;
+mod proxy_status;
+mod range;
+mod retry;
 mod reverse;
+mod signed_url;
+mod tunnel;
+mod upgrade;
+mod upstream_timeout;
 mod web_func;
+mod webdav;
 
 use crate::config::Config;
 
@@ -46,6 +74,14 @@ static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 async fn main() -> Result<(), DynError> {
     let proxy_config: Config = load_config()?;
     let ports = proxy_config.port.clone();
+    #[cfg(feature = "otel")]
+    let _tracer_provider = match &proxy_config.otlp_endpoint {
+        Some(endpoint) => {
+            info!("export traces to {}", endpoint);
+            Some(otel::init_tracer_provider(endpoint, &proxy_config.otel_service_name)?)
+        }
+        None => None,
+    };
     let proxy_handler = Arc::new(ProxyHandler::new(proxy_config)?);
     #[cfg(feature = "jemalloc")]
     info!("jemalloc is enabled");
@@ -86,6 +122,9 @@ impl ReqInterceptor for ProxyInterceptor {
 
 async fn bootstrap(port: u16, proxy_handler: Arc<ProxyHandler>) -> Result<(), DynError> {
     let config = &proxy_handler.config;
+    if config.proxy_protocol_ports.contains(&port) {
+        return listener::serve(port, proxy_handler.clone(), build_router(proxy_handler)).await;
+    }
     let tls_param = match config.over_tls {
         true => Some(TlsParam {
             tls: true,
@@ -97,8 +136,10 @@ async fn bootstrap(port: u16, proxy_handler: Arc<ProxyHandler>) -> Result<(), Dy
     axum_bootstrap::new_server_with_interceptor::<ProxyInterceptor>(
         port,
         tls_param,
-        ProxyInterceptor { proxy_handler },
-        build_router(),
+        ProxyInterceptor {
+            proxy_handler: proxy_handler.clone(),
+        },
+        build_router(proxy_handler),
     )
     .with_timeout(IDLE_TIMEOUT)
     .run()
@@ -106,15 +147,21 @@ async fn bootstrap(port: u16, proxy_handler: Arc<ProxyHandler>) -> Result<(), Dy
 }
 
 pub(crate) const BODY404: &str = include_str!("../html/404.html");
-pub(crate) fn build_router() -> Router {
+pub(crate) fn build_router(proxy_handler: Arc<ProxyHandler>) -> Router {
     // build our application with a route
     Router::new()
         .route("/time", get(|| async { (StatusCode::OK, format!("{}", Local::now())) }))
-        .fallback(get(|| async {
-            let mut header_map = HeaderMap::new();
-            #[allow(clippy::expect_used)]
-            header_map.insert("content-type", "text/html; charset=utf-8".parse().expect("should be valid header"));
-            (StatusCode::NOT_FOUND, header_map, BODY404)
+        .fallback(get(move |headers: HeaderMap| {
+            let proxy_handler = proxy_handler.clone();
+            async move {
+                let authority = headers
+                    .get(http::header::HOST)
+                    .and_then(|host| host.to_str().ok())
+                    .and_then(|host| host.parse::<http::uri::Authority>().ok());
+                proxy_handler
+                    .error_response(authority.as_ref().map(|authority| authority.host()), StatusCode::NOT_FOUND)
+                    .await
+            }
         }))
         .layer((CorsLayer::permissive(), TimeoutLayer::new(Duration::from_secs(30)), CompressionLayer::new()))
 }
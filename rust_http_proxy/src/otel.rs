@@ -0,0 +1,243 @@
+//! OpenTelemetry：为反向代理请求生成span，通过OTLP/HTTP导出，并向上游传递W3C trace context
+//!
+//! 一个反向代理请求的span结构：
+//! - `{method} {location}` server span，从location匹配开始，到响应body发送完毕结束
+//!   - `location_match`
+//!   - `{method} upstream` client span，包含上游建连和等待响应头
+//!     - `dns_resolve` 仅在需要新建连接时出现
+
+use std::{error::Error, time::SystemTime};
+
+use http::{Request, StatusCode};
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    propagation::TextMapPropagator,
+    trace::{Span, SpanKind, SpanRef, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+
+use crate::DynError;
+
+const TRACER_NAME: &str = "rust_http_proxy";
+
+/// 初始化OTLP/HTTP导出并设置为全局的TracerProvider，endpoint形如 http://127.0.0.1:4318/v1/traces
+pub(crate) fn init_tracer_provider(endpoint: &str, service_name: &str) -> Result<SdkTracerProvider, DynError> {
+    let exporter = SpanExporter::builder().with_http().with_endpoint(endpoint).build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name.to_owned()).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(provider)
+}
+
+fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
+
+/// 一个反向代理请求的trace，由AccessEntry持有，在访问日志输出时结束
+pub(crate) struct RequestTrace {
+    cx: Context,
+}
+
+impl RequestTrace {
+    /// 从请求头中提取客户端传来的traceparent/tracestate作为parent，没有则开始新的trace
+    ///
+    /// match_start为开始匹配location的时间
+    pub(crate) fn start<B>(req: &Request<B>, location: &str, match_start: SystemTime) -> Self {
+        let tracer = tracer();
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+        let span = tracer
+            .span_builder(format!("{} {}", req.method(), location))
+            .with_kind(SpanKind::Server)
+            .with_start_time(match_start)
+            .with_attributes([
+                KeyValue::new("http.request.method", req.method().to_string()),
+                KeyValue::new("url.path", req.uri().path().to_owned()),
+                KeyValue::new("http.route", location.to_owned()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+        tracer
+            .span_builder("location_match")
+            .with_start_time(match_start)
+            .start_with_context(&tracer, &cx)
+            .end();
+        RequestTrace { cx }
+    }
+
+    pub(crate) fn trace_id(&self) -> String {
+        self.cx.span().span_context().trace_id().to_string()
+    }
+
+    /// 开始上游请求的client span，并将其trace context写入上游请求头，替换客户端传来的traceparent/tracestate
+    pub(crate) fn start_upstream<B>(&self, upstream_req: &mut Request<B>) -> Context {
+        let tracer = tracer();
+        let span = tracer
+            .span_builder(format!("{} upstream", upstream_req.method()))
+            .with_kind(SpanKind::Client)
+            .with_attributes([
+                KeyValue::new("http.request.method", upstream_req.method().to_string()),
+                KeyValue::new("url.full", upstream_req.uri().to_string()),
+                KeyValue::new("server.address", upstream_req.uri().host().unwrap_or_default().to_owned()),
+            ])
+            .start_with_context(&tracer, &self.cx);
+        let cx = self.cx.with_span(span);
+        TraceContextPropagator::new().inject_context(&cx, &mut HeaderInjector(upstream_req.headers_mut()));
+        cx
+    }
+
+    /// status为None表示没有响应给客户端
+    pub(crate) fn end(self, status: Option<u16>) {
+        end_span(self.cx.span(), status.and_then(|status| StatusCode::from_u16(status).ok()), None);
+    }
+}
+
+/// 结束上游请求的client span
+pub(crate) fn end_upstream<E: Error>(cx: &Context, result: Result<StatusCode, &E>) {
+    match result {
+        Ok(status) => end_span(cx.span(), Some(status), None),
+        Err(e) => end_span(cx.span(), None, Some(e)),
+    }
+}
+
+fn end_span(span: SpanRef<'_>, status: Option<StatusCode>, error: Option<&dyn Error>) {
+    match status {
+        Some(status) => {
+            span.set_attribute(KeyValue::new("http.response.status_code", status.as_u16() as i64));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+        None => {
+            if let Some(error) = error {
+                span.record_error(error);
+            }
+            span.set_status(Status::error("no response"));
+        }
+    }
+    span.end();
+}
+
+/// 在当前上游请求的span下开始DNS解析的span，没有进行中的trace时返回None
+pub(crate) fn start_dns_span(host: &str) -> Option<Context> {
+    let parent = Context::current();
+    if !parent.has_active_span() {
+        return None;
+    }
+    let tracer = tracer();
+    let span = tracer
+        .span_builder("dns_resolve")
+        .with_kind(SpanKind::Internal)
+        .with_attributes([KeyValue::new("server.address", host.to_owned())])
+        .start_with_context(&tracer, &parent);
+    Some(parent.with_span(span))
+}
+
+pub(crate) fn end_dns_span(cx: Context, ok: bool) {
+    let span = cx.span();
+    if !ok {
+        span.set_status(Status::error("dns resolve failed"));
+    }
+    span.end();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use hyper::{body::Bytes, server::conn::http1, service::service_fn, Response};
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    /// 本地的OTLP/HTTP collector替身，收到的每个请求的path和body发送到channel
+    async fn start_otlp_receiver() -> Result<(String, mpsc::Receiver<(String, Bytes)>), DynError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr()?);
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let tx = tx.clone();
+                        async move {
+                            let path = req.uri().path().to_owned();
+                            let body = req
+                                .into_body()
+                                .collect()
+                                .await
+                                .map(|body| body.to_bytes())
+                                .unwrap_or_default();
+                            let _ = tx.send((path, body)).await;
+                            Ok::<_, Infallible>(Response::new(Full::new(Bytes::new())))
+                        }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        Ok((endpoint, rx))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_propagate_and_export() -> Result<(), DynError> {
+        let (endpoint, mut received) = start_otlp_receiver().await?;
+        let provider = init_tracer_provider(&endpoint, "test")?;
+
+        // 客户端带有traceparent/tracestate：沿用trace id，向上游传递新的span id和原样的tracestate
+        let req = Request::builder()
+            .uri("/api/users")
+            .header("traceparent", TRACEPARENT)
+            .header("tracestate", "congo=t61rcWkgMzE")
+            .body(())?;
+        let trace = RequestTrace::start(&req, "/api", SystemTime::now());
+        assert_eq!(trace.trace_id(), "0af7651916cd43dd8448eb211c80319c");
+        let mut upstream_req = Request::builder()
+            .uri("http://127.0.0.1:8080/users")
+            .header("traceparent", TRACEPARENT)
+            .body(())?;
+        let upstream_cx = trace.start_upstream(&mut upstream_req);
+        let traceparent = upstream_req.headers()["traceparent"].to_str()?.to_owned();
+        assert!(traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert_ne!(traceparent, TRACEPARENT);
+        assert_eq!(upstream_req.headers()["tracestate"], "congo=t61rcWkgMzE");
+        end_upstream::<std::io::Error>(&upstream_cx, Ok(StatusCode::OK));
+        trace.end(Some(200));
+
+        // 客户端没有traceparent：开始新的trace
+        let req = Request::builder().uri("/api/users").body(())?;
+        let trace = RequestTrace::start(&req, "/api", SystemTime::now());
+        let new_trace_id = trace.trace_id();
+        assert_ne!(new_trace_id, "0af7651916cd43dd8448eb211c80319c");
+        let mut upstream_req = Request::builder().uri("http://127.0.0.1:8080/users").body(())?;
+        let upstream_cx = trace.start_upstream(&mut upstream_req);
+        assert!(upstream_req.headers()["traceparent"]
+            .to_str()?
+            .starts_with(&format!("00-{}-", new_trace_id)));
+        end_upstream(&upstream_cx, Err(&std::io::Error::other("connection refused")));
+        trace.end(None);
+
+        // flush会阻塞等待导出完成
+        tokio::task::spawn_blocking(move || provider.force_flush()).await??;
+        let mut exported = Vec::new();
+        while let Ok((path, body)) = received.try_recv() {
+            assert_eq!(path, "/v1/traces");
+            exported.extend_from_slice(&body);
+        }
+        // protobuf编码的ExportTraceServiceRequest中，trace id以16字节原样出现
+        for trace_id in ["0af7651916cd43dd8448eb211c80319c", new_trace_id.as_str()] {
+            let bytes = u128::from_str_radix(trace_id, 16)?.to_be_bytes();
+            assert!(exported.windows(bytes.len()).any(|window| window == bytes));
+        }
+        Ok(())
+    }
+}
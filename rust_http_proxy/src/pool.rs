@@ -0,0 +1,405 @@
+//! 正向代理的上游连接池：按目标分片加锁，限制每个目标和总的空闲连接数
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{BuildHasher, RandomState},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use http::uri::Scheme;
+use prom_label::LabelImpl;
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::Registry,
+};
+
+use crate::proxy::AccessLabel;
+
+/// 分片数量，不同目标的连接存取不会竞争同一把锁
+const SHARDS: usize = 16;
+
+#[derive(Clone, Debug)]
+pub(crate) struct PoolConfig {
+    /// 每个目标最多保留的空闲连接数
+    pub(crate) max_idle_per_target: usize,
+    /// 所有目标总共最多保留的空闲连接数
+    pub(crate) max_idle: usize,
+    /// 同一用户的不同客户端IP之间共享连接
+    pub(crate) share_across_clients: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_idle_per_target: 8,
+            max_idle: 512,
+            share_across_clients: false,
+        }
+    }
+}
+
+/// 连接池中的连接
+pub(crate) trait Poolable: Sized {
+    fn is_closed(&self) -> bool;
+    /// 可以多路复用的连接（HTTP/2）返回共享的句柄，连接本身留在池中
+    fn share(&self) -> Option<Self>;
+}
+
+/// 连接池的key。http和https的连接不能混用；开启共享时不区分客户端IP
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct PoolKey {
+    target: String,
+    scheme: Scheme,
+    username: String,
+    client: Option<String>,
+}
+
+/// 连接被移出连接池的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EvictReason {
+    /// 空闲超时
+    Expired,
+    /// 上游已经关闭
+    Closed,
+    /// 超过单个目标的空闲连接数
+    TargetLimit,
+    /// 超过总的空闲连接数
+    TotalLimit,
+}
+
+impl EvictReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EvictReason::Expired => "expired",
+            EvictReason::Closed => "closed",
+            EvictReason::TargetLimit => "target_limit",
+            EvictReason::TotalLimit => "total_limit",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EvictLabel {
+    reason: &'static str,
+}
+
+#[derive(Clone)]
+pub(crate) struct PoolMetrics {
+    idle: Gauge,
+    active: Gauge,
+    reuses: Counter,
+    connects: Counter,
+    evictions: Family<LabelImpl<EvictLabel>, Counter>,
+}
+
+impl PoolMetrics {
+    pub(crate) fn register(registry: &mut Registry) -> Self {
+        let metrics = PoolMetrics {
+            idle: Gauge::default(),
+            active: Gauge::default(),
+            reuses: Counter::default(),
+            connects: Counter::default(),
+            evictions: Family::default(),
+        };
+        registry.register(
+            "forward_pool_idle_connections",
+            "Upstream connections kept in the forward proxy pool",
+            metrics.idle.clone(),
+        );
+        registry.register(
+            "forward_pool_active_connections",
+            "Open upstream connections of the forward proxy",
+            metrics.active.clone(),
+        );
+        registry.register("forward_pool_reuses", "Requests served by pooled connections", metrics.reuses.clone());
+        registry.register("forward_pool_connects", "New upstream connections", metrics.connects.clone());
+        registry.register(
+            "forward_pool_evictions",
+            "Connections removed from the forward proxy pool",
+            metrics.evictions.clone(),
+        );
+        metrics
+    }
+
+    /// 新建上游连接，连接关闭时需要调用on_connection_close
+    pub(crate) fn on_connect(&self) {
+        self.connects.inc();
+        self.active.inc();
+    }
+
+    pub(crate) fn on_connection_close(&self) {
+        self.active.dec();
+    }
+
+    fn evict(&self, reason: EvictReason, count: usize) {
+        self.evictions
+            .get_or_create(&LabelImpl::new(EvictLabel {
+                reason: reason.as_str(),
+            }))
+            .inc_by(count as u64);
+    }
+}
+
+struct Idle<T> {
+    conn: T,
+    last_used: Instant,
+}
+
+type Shard<T> = HashMap<PoolKey, VecDeque<Idle<T>>>;
+
+pub(crate) struct Pool<T> {
+    shards: Vec<Mutex<Shard<T>>>,
+    /// 所有分片中的空闲连接数
+    idle: AtomicUsize,
+    hasher: RandomState,
+    config: PoolConfig,
+    idle_timeout: Duration,
+    metrics: PoolMetrics,
+}
+
+impl<T: Poolable> Pool<T> {
+    pub(crate) fn new(config: PoolConfig, idle_timeout: Duration, metrics: PoolMetrics) -> Self {
+        Pool {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            idle: AtomicUsize::new(0),
+            hasher: RandomState::new(),
+            config,
+            idle_timeout,
+            metrics,
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> &PoolMetrics {
+        &self.metrics
+    }
+
+    pub(crate) fn key(&self, access_label: &AccessLabel, scheme: &Scheme) -> PoolKey {
+        PoolKey {
+            target: access_label.target.clone(),
+            scheme: scheme.clone(),
+            username: access_label.username.clone(),
+            client: (!self.config.share_across_clients).then(|| access_label.client.clone()),
+        }
+    }
+
+    /// 取出一个可用的连接，HTTP/2连接会留在池中继续共享
+    pub(crate) fn checkout(&self, key: &PoolKey) -> Option<T> {
+        let now = Instant::now();
+        let mut shard = self.shard(key);
+        self.purge(&mut shard, now);
+        let queue = shard.get_mut(key)?;
+        let conn = match queue.front_mut() {
+            Some(idle) => match idle.conn.share() {
+                Some(shared) => {
+                    idle.last_used = now;
+                    shared
+                }
+                None => {
+                    let conn = queue.pop_front()?.conn;
+                    self.on_removed(1);
+                    conn
+                }
+            },
+            None => return None,
+        };
+        if queue.is_empty() {
+            shard.remove(key);
+        }
+        self.metrics.reuses.inc();
+        Some(conn)
+    }
+
+    /// 放回连接，超过限制时丢弃
+    pub(crate) fn put(&self, key: PoolKey, conn: T) {
+        if self.config.max_idle_per_target == 0 {
+            self.metrics.evict(EvictReason::TargetLimit, 1);
+            return;
+        }
+        let now = Instant::now();
+        let mut shard = self.shard(&key);
+        self.purge(&mut shard, now);
+        let queue = shard.entry(key).or_default();
+        // 丢弃最久没有使用的连接
+        if queue.len() >= self.config.max_idle_per_target && queue.pop_front().is_some() {
+            self.on_removed(1);
+            self.metrics.evict(EvictReason::TargetLimit, 1);
+        }
+        if self
+            .idle
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |idle| (idle < self.config.max_idle).then_some(idle + 1))
+            .is_err()
+        {
+            self.metrics.evict(EvictReason::TotalLimit, 1);
+        } else {
+            self.metrics.idle.inc();
+            queue.push_back(Idle { conn, last_used: now });
+        }
+    }
+
+    fn shard(&self, key: &PoolKey) -> std::sync::MutexGuard<'_, Shard<T>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        // 持有锁时不会panic，忽略poison
+        self.shards[index].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// 清理分片中过期和已关闭的连接
+    fn purge(&self, shard: &mut Shard<T>, now: Instant) {
+        let (mut expired, mut closed) = (0, 0);
+        shard.retain(|_, queue| {
+            queue.retain(|idle| {
+                if now - idle.last_used >= self.idle_timeout {
+                    expired += 1;
+                    false
+                } else if idle.conn.is_closed() {
+                    closed += 1;
+                    false
+                } else {
+                    true
+                }
+            });
+            !queue.is_empty()
+        });
+        if expired + closed > 0 {
+            self.on_removed(expired + closed);
+            self.metrics.evict(EvictReason::Expired, expired);
+            self.metrics.evict(EvictReason::Closed, closed);
+        }
+    }
+
+    fn on_removed(&self, count: usize) {
+        self.idle.fetch_sub(count, Ordering::AcqRel);
+        self.metrics.idle.dec_by(count as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct MockConn {
+        id: usize,
+        shared: bool,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl MockConn {
+        fn new(id: usize, shared: bool) -> Self {
+            MockConn {
+                id,
+                shared,
+                closed: Arc::new(AtomicBool::new(false)),
+            }
+        }
+    }
+
+    impl Poolable for MockConn {
+        fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::Relaxed)
+        }
+
+        fn share(&self) -> Option<Self> {
+            self.shared.then(|| self.clone())
+        }
+    }
+
+    fn label(client: &str, target: &str) -> AccessLabel {
+        AccessLabel {
+            client: client.to_owned(),
+            target: target.to_owned(),
+            username: "user".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut registry = Registry::default();
+        let config = PoolConfig {
+            max_idle_per_target: 2,
+            max_idle: 3,
+            share_across_clients: false,
+        };
+        let pool = Pool::new(config, Duration::from_secs(30), PoolMetrics::register(&mut registry));
+        let a = pool.key(&label("1.1.1.1", "a:80"), &Scheme::HTTP);
+        assert_ne!(a, pool.key(&label("1.1.1.1", "a:80"), &Scheme::HTTPS));
+        assert_ne!(a, pool.key(&label("2.2.2.2", "a:80"), &Scheme::HTTP));
+
+        // 每个目标最多2个，丢弃最旧的
+        for id in 0..3 {
+            pool.put(a.clone(), MockConn::new(id, false));
+        }
+        assert_eq!(pool.metrics.idle.get(), 2);
+        assert_eq!(pool.checkout(&a).map(|c| c.id), Some(1));
+        assert_eq!(pool.checkout(&a).map(|c| c.id), Some(2));
+        assert!(pool.checkout(&a).is_none());
+
+        // 总数最多3个
+        for id in 0..4 {
+            pool.put(pool.key(&label("1.1.1.1", &format!("t{}:80", id)), &Scheme::HTTP), MockConn::new(id, false));
+        }
+        assert_eq!(pool.metrics.idle.get(), 3);
+        assert!(pool
+            .checkout(&pool.key(&label("1.1.1.1", "t3:80"), &Scheme::HTTP))
+            .is_none());
+
+        // 已关闭的连接被清理
+        let t0 = pool.key(&label("1.1.1.1", "t0:80"), &Scheme::HTTP);
+        let closed = MockConn::new(9, false);
+        closed.closed.store(true, Ordering::Relaxed);
+        pool.put(t0.clone(), closed);
+        assert_eq!(pool.checkout(&t0).map(|c| c.id), Some(0));
+        assert!(pool.checkout(&t0).is_none());
+
+        // 共享的连接留在池中
+        let h2 = pool.key(&label("1.1.1.1", "h2:443"), &Scheme::HTTPS);
+        pool.put(h2.clone(), MockConn::new(7, true));
+        assert_eq!(pool.checkout(&h2).map(|c| c.id), Some(7));
+        assert_eq!(pool.checkout(&h2).map(|c| c.id), Some(7));
+        assert_eq!(pool.metrics.reuses.get(), 5);
+    }
+
+    #[test]
+    fn test_zero_max_idle_per_target() {
+        let mut registry = Registry::default();
+        let config = PoolConfig {
+            max_idle_per_target: 0,
+            ..Default::default()
+        };
+        let pool = Pool::new(config, Duration::from_secs(30), PoolMetrics::register(&mut registry));
+        let a = pool.key(&label("1.1.1.1", "a:80"), &Scheme::HTTP);
+        for id in 0..3 {
+            pool.put(a.clone(), MockConn::new(id, false));
+        }
+        assert!(pool.checkout(&a).is_none());
+        assert_eq!(pool.metrics.idle.get(), 0);
+        assert_eq!(pool.idle.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_share_across_clients() {
+        let mut registry = Registry::default();
+        let config = PoolConfig {
+            share_across_clients: true,
+            ..Default::default()
+        };
+        let pool = Pool::new(config, Duration::from_secs(30), PoolMetrics::register(&mut registry));
+        pool.put(pool.key(&label("1.1.1.1", "a:80"), &Scheme::HTTP), MockConn::new(1, false));
+        let other_user = AccessLabel {
+            username: "other".to_owned(),
+            ..label("2.2.2.2", "a:80")
+        };
+        assert!(pool.checkout(&pool.key(&other_user, &Scheme::HTTP)).is_none());
+        assert_eq!(
+            pool.checkout(&pool.key(&label("2.2.2.2", "a:80"), &Scheme::HTTP))
+                .map(|c| c.id),
+            Some(1)
+        );
+    }
+}
@@ -3,21 +3,37 @@ use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
     io::{self, ErrorKind},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::LazyLock,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    access_log::{AccessEntry, AccessKind, AccessLog},
     address::host_addr,
+    analytics::Analytics,
+    body_limit::{self, BodyTooLarge, LimitedBody},
     config,
+    connector::{self, ProxyProtocolConnector, TimedResolver},
+    dns::{DnsMetrics, Resolver},
+    error_page::{self, ErrorPages},
+    health::UpstreamHealth,
+    hop_by_hop,
     http1_client::HttpClient,
     ip_x::{local_ip, SocketAddrFormat},
-    reverse::{self, LocationConfig, Upstream},
+    latency::LatencyMetrics,
+    pool::PoolMetrics,
+    proxy_protocol::{self, ProxyProtocolVersion},
+    proxy_status::{self, ProxyError, PROXY_STATUS},
+    retry,
+    reverse::{self, LocationConfig, StaticLocationConfig, Upstream},
+    tunnel::{CloseReason, TunnelKind, TunnelMetrics, TunnelStats},
+    upgrade,
+    upstream_timeout::{TimeoutBody, TimeoutKind, UpstreamTimeout, UpstreamTimeouts},
     web_func, Config,
 };
-use {io_x::CounterIO, io_x::TimeoutIO, prom_label::LabelImpl};
+use {io_x::CounterIO, io_x::StatsIO, io_x::TimeoutIO, prom_label::LabelImpl};
 
 use axum::extract::Request;
 use axum_bootstrap::InterceptResult;
@@ -30,26 +46,25 @@ use hyper::{
     body::Bytes,
     header::{self, HeaderValue},
     http,
-    upgrade::Upgraded,
+    upgrade::{OnUpgrade, Upgraded},
     Method, Response, Version,
 };
 use hyper::{
     body::{Body, Incoming},
     header::HeaderName,
 };
+use hyper_rustls::ConfigBuilderExt;
 use hyper_util::client::legacy::{self, connect::HttpConnector};
 use hyper_util::rt::TokioExecutor;
 use hyper_util::rt::TokioIo;
 use log::{debug, info, warn};
 use percent_encoding::percent_decode_str;
-use prom_label::Label;
-use prometheus_client::{
-    encoding::EncodeLabelSet,
-    metrics::{counter::Counter, family::Family},
-    registry::Registry,
-};
+use prom_label::{BoundedFamily, Label};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::{encoding::EncodeLabelSet, metrics::counter::Counter, registry::Registry};
 use rand::Rng;
 use tokio::{net::TcpStream, pin};
+use tokio_rustls::rustls::ClientConfig;
 static LOCAL_IP: LazyLock<String> = LazyLock::new(|| local_ip().unwrap_or("0.0.0.0".to_string()));
 pub struct ProxyHandler {
     pub(crate) config: Config,
@@ -57,14 +72,34 @@ pub struct ProxyHandler {
     pub(crate) metrics: Metrics,
     #[cfg(target_os = "linux")]
     pub(crate) linux_monitor: crate::linux_monitor::NetMonitor,
-    http1_client: HttpClient<Incoming>,
-    reverse_client: legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, Incoming>,
+    pub(crate) access_log: AccessLog,
+    pub(crate) analytics: Analytics,
+    http1_client: HttpClient<BoxBody<Bytes, io::Error>>,
+    resolver: Resolver,
+    /// key为location的connect_ms和是否只使用HTTP/1.1（协议升级），建连超时设置在connector上，每种组合使用一个client
+    reverse_clients: HashMap<(u64, bool), ReverseClient>,
+    /// 配置了proxy_protocol的location每个请求新建client，复用TLS配置
+    upstream_tls_config: ClientConfig,
+    upstream_health: UpstreamHealth,
 }
 
+type ReverseClient =
+    legacy::Client<hyper_rustls::HttpsConnector<HttpConnector<TimedResolver>>, BoxBody<Bytes, io::Error>>;
+type ProxyProtocolClient =
+    legacy::Client<hyper_rustls::HttpsConnector<ProxyProtocolConnector>, BoxBody<Bytes, io::Error>>;
+
 pub(crate) struct Metrics {
-    pub(crate) http_req_counter: Family<LabelImpl<ReqLabels>, Counter>,
-    pub(crate) proxy_traffic: Family<LabelImpl<AccessLabel>, Counter>,
-    pub(crate) reverse_proxy_req: Family<LabelImpl<ReverseProxyReqLabel>, Counter>,
+    pub(crate) http_req_counter: BoundedFamily<LabelImpl<ReqLabels>, Counter>,
+    pub(crate) proxy_traffic: BoundedFamily<LabelImpl<AccessLabel>, Counter>,
+    pub(crate) reverse_proxy_req: BoundedFamily<LabelImpl<ReverseProxyReqLabel>, Counter>,
+    pub(crate) hotlink_blocked: BoundedFamily<LabelImpl<HotlinkLabel>, Counter>,
+    pub(crate) tunnel: TunnelMetrics,
+    pub(crate) latency: LatencyMetrics,
+    pub(crate) dns: DnsMetrics,
+    pub(crate) forward_pool: PoolMetrics,
+    /// label只有配置中的location，不需要限制series数量
+    pub(crate) reverse_proxy_timeout: Family<LabelImpl<ReverseProxyTimeoutLabel>, Counter>,
+    pub(crate) reverse_proxy_error: Family<LabelImpl<ReverseProxyErrorLabel>, Counter>,
     #[cfg(all(target_os = "linux", feature = "bpf"))]
     pub(crate) net_bytes: Family<LabelImpl<NetDirectionLabel>, Counter>,
     #[cfg(all(target_os = "linux", feature = "bpf"))]
@@ -94,23 +129,64 @@ impl ProxyHandler {
     #[allow(clippy::expect_used)]
     pub fn new(config: Config) -> Result<Self, crate::DynError> {
         let mut registry = Registry::default();
-        let metrics = register_metrics(&mut registry);
+        let metrics = register_metrics(&mut registry, config.metrics_max_series, config.metrics_idle_ttl);
 
-        let reverse_client = build_hyper_legacy_client();
-        let http1_client = HttpClient::<Incoming>::new();
+        let upstream_tls_config = ClientConfig::builder()
+            .try_with_platform_verifier()?
+            .with_no_client_auth();
+        let resolver = Resolver::new(config.dns.clone(), upstream_tls_config.clone(), metrics.dns.clone());
+        let mut reverse_clients = HashMap::new();
+        for timeouts in config
+            .reverse_proxy_config
+            .locations
+            .values()
+            .flatten()
+            .map(|location| &location.timeouts)
+            .chain([&UpstreamTimeouts::default()])
+        {
+            for http1_only in [false, true] {
+                reverse_clients
+                    .entry((timeouts.connect_ms, http1_only))
+                    .or_insert_with(|| {
+                        build_hyper_legacy_client(
+                            upstream_tls_config.clone(),
+                            resolver.clone(),
+                            metrics.latency.clone(),
+                            timeouts.connect(),
+                            http1_only,
+                        )
+                    });
+            }
+        }
+        let access_log = AccessLog::new(config.access_log_format, config.over_tls);
+        let http1_client = HttpClient::<BoxBody<Bytes, io::Error>>::new(
+            config.forward_pool.clone(),
+            metrics.forward_pool.clone(),
+            metrics.tunnel.clone(),
+            resolver.clone(),
+            metrics.latency.clone(),
+            access_log.clone(),
+        )?;
 
         #[cfg(target_os = "linux")]
         let monitor = crate::linux_monitor::NetMonitor::new()?;
         #[cfg(target_os = "linux")]
         monitor.start();
 
+        let analytics = Analytics::new(config.analytics_search_engines.clone(), config.analytics_max_keys);
+
         Ok(ProxyHandler {
             prom_registry: registry,
+            analytics,
             metrics,
             #[cfg(target_os = "linux")]
             linux_monitor: monitor,
-            reverse_client,
+            reverse_clients,
+            upstream_tls_config,
+            upstream_health: UpstreamHealth::default(),
             http1_client,
+            resolver,
+            access_log,
             config,
         })
     }
@@ -119,6 +195,13 @@ impl ProxyHandler {
     ) -> Result<InterceptResultAdapter, io::Error> {
         let config_basic_auth = &self.config.basic_auth;
         let never_ask_for_auth = self.config.never_ask_for_auth;
+        // 来自受信任代理的请求，client_socket_addr为X-Forwarded-For中真实的客户端
+        let peer_ip = client_socket_addr.ip();
+        let client_socket_addr = self
+            .config
+            .trusted_proxies
+            .real_client(req.headers(), client_socket_addr);
+        let mut access_entry = self.access_log.begin(&req, client_socket_addr);
 
         // 对于非CONNECT请求，检查是否需要反向代理或服务
         if Method::CONNECT != req.method() {
@@ -138,26 +221,83 @@ impl ProxyHandler {
                 .get(&origin_scheme_host_port.host)
                 .or(self.config.reverse_proxy_config.locations.get(config::DEFAULT_HOST));
 
-            if let Some(locations) = host_locations {
-                if let Some(location_config) = pick_location(req.uri().path(), locations) {
-                    return self
-                        .reverse_proxy(req, location_config, client_socket_addr, &origin_scheme_host_port)
+            let host_static_locations = self
+                .config
+                .reverse_proxy_config
+                .static_locations
+                .get(&origin_scheme_host_port.host)
+                .or(self
+                    .config
+                    .reverse_proxy_config
+                    .static_locations
+                    .get(config::DEFAULT_HOST));
+            let raw_path = req.uri().path();
+            let path = percent_decode_str(raw_path)
+                .decode_utf8()
+                .unwrap_or(Cow::from(raw_path));
+            #[cfg(feature = "otel")]
+            let match_start = std::time::SystemTime::now();
+            // 反向代理location匹配原始路径，转发时从原始路径中去掉location前缀；静态location匹配解码后的路径
+            let location_config = host_locations.and_then(|locations| pick_location(raw_path, locations));
+            if let Some(static_location) =
+                host_static_locations.and_then(|static_locations| pick_static_location(&path, static_locations))
+            {
+                // 反向代理的location更长时优先反向代理
+                let proxy_location_len = location_config.map_or(0, |location_config| location_config.location.len());
+                if static_location.location.len() >= proxy_location_len {
+                    let res = web_func::serve_static_location(self, static_location, &req, client_socket_addr, &path)
                         .await
-                        .map(InterceptResultAdapter::Return);
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                    let res = self.with_error_page(Some(&origin_scheme_host_port.host), res).await;
+                    return Ok(InterceptResultAdapter::Return(access_entry.finish_with(res)));
                 }
             }
 
+            if let Some(location_config) = location_config {
+                #[cfg(feature = "otel")]
+                if self.config.otlp_endpoint.is_some() {
+                    access_entry.set_trace(crate::otel::RequestTrace::start(
+                        &req,
+                        &location_config.location,
+                        match_start,
+                    ));
+                }
+                return self
+                    .reverse_proxy(
+                        req,
+                        location_config,
+                        client_socket_addr,
+                        peer_ip,
+                        &origin_scheme_host_port,
+                        access_entry,
+                    )
+                    .await
+                    .map(InterceptResultAdapter::Return);
+            }
+
             // 对于HTTP/2请求或URI中不包含host的请求，处理为普通服务请求
             if req.version() == Version::HTTP_2 || req.uri().host().is_none() {
+                // 上传需要拿到请求体，不经过serve_request
+                if let Some(webdav) = self.config.webdav.as_ref() {
+                    if webdav.matches(req.method(), &path) && (config_basic_auth.is_empty() || never_ask_for_auth) {
+                        let path = path.into_owned();
+                        let res = webdav.handle(req, client_socket_addr, &path).await;
+                        let res = self.with_error_page(Some(&origin_scheme_host_port.host), res).await;
+                        return Ok(InterceptResultAdapter::Return(access_entry.finish_with(res)));
+                    }
+                }
                 match self
                     .serve_request(&req, config_basic_auth, never_ask_for_auth, client_socket_addr)
                     .await
                 {
                     Ok(res) => {
                         if res.status() == http::StatusCode::NOT_FOUND {
+                            // 交给axum router处理，不记录访问日志。axum的fallback返回404错误页面
+                            access_entry.discard();
                             return Ok(InterceptResultAdapter::Continue(req));
                         } else {
-                            return Ok(InterceptResultAdapter::Return(res));
+                            let res = self.with_error_page(Some(&origin_scheme_host_port.host), res).await;
+                            return Ok(InterceptResultAdapter::Return(access_entry.finish_with(res)));
                         }
                     }
                     Err(err) => return Err(err),
@@ -181,14 +321,18 @@ impl ProxyHandler {
             return if never_ask_for_auth {
                 Err(io::Error::new(ErrorKind::PermissionDenied, "wrong basic auth, closing socket..."))
             } else {
-                Ok(InterceptResultAdapter::Return(build_authenticate_resp(true)))
+                let res = self.with_error_page(None, build_authenticate_resp(true)).await;
+                Ok(InterceptResultAdapter::Return(access_entry.finish_with(res)))
             };
         }
+        if !config_basic_auth.is_empty() {
+            access_entry.set_user(&username);
+        }
         if Method::CONNECT == req.method() {
-            self.tunnel_proxy(req, client_socket_addr, username)
+            self.tunnel_proxy(req, client_socket_addr, username, access_entry)
                 .map(InterceptResultAdapter::Return)
         } else {
-            self.simple_proxy(req, client_socket_addr, username)
+            self.simple_proxy(req, client_socket_addr, peer_ip, username, access_entry)
                 .await
                 .map(InterceptResultAdapter::Return)
         }
@@ -197,24 +341,52 @@ impl ProxyHandler {
     /// 代理普通请求
     /// HTTP/1.1 GET/POST/PUT/DELETE/HEAD
     async fn simple_proxy(
-        &self, mut req: Request<Incoming>, client_socket_addr: SocketAddr, username: String,
+        &self, mut req: Request<Incoming>, client_socket_addr: SocketAddr, peer_ip: IpAddr, username: String,
+        mut access_entry: AccessEntry,
     ) -> Result<Response<BoxBody<Bytes, io::Error>>, io::Error> {
         let access_label = self.build_access_label(&req, client_socket_addr, username)?;
+        access_entry.set_kind(AccessKind::Proxy);
+        access_entry.set_upstream(access_label.target.clone());
+        let max_body = self.config.max_request_body;
+        if let Some(res) = self.check_request(&req, None, max_body).await {
+            return Ok(access_entry.finish_with(res));
+        }
         mod_http1_proxy_req(&mut req)?;
-        match self
-            .http1_client
-            .send_request(req, &access_label, |stream: TcpStream, access_label: AccessLabel| {
-                CounterIO::new(stream, self.metrics.proxy_traffic.clone(), LabelImpl::new(access_label))
+        if self.config.forward_proxy_headers {
+            let version = req.version();
+            self.config
+                .trusted_proxies
+                .append_forwarded_for(req.headers_mut(), peer_ip, version);
+        }
+        let req = limit_body(req, max_body);
+        let req = access_entry.count_request(req);
+        let access_label = &access_label;
+        let result = self
+            .config
+            .forward_retry
+            .send(req, 0, |req, _| {
+                self.http1_client
+                    .send_request(req, access_label, |stream: TcpStream, access_label: AccessLabel| {
+                        CounterIO::new(stream, self.metrics.proxy_traffic.clone(), LabelImpl::new(access_label))
+                    })
             })
-            .await
-        {
-            Ok(resp) => Ok(resp.map(|body| {
-                body.map_err(|e| {
-                    let e = e;
-                    io::Error::new(ErrorKind::InvalidData, e)
-                })
-                .boxed()
-            })),
+            .await;
+        match result {
+            Ok(mut resp) => {
+                hop_by_hop::remove_hop_by_hop(resp.headers_mut(), false);
+                Ok(access_entry.finish_with(resp.map(|body| {
+                    body.map_err(|e| {
+                        let e = e;
+                        io::Error::new(ErrorKind::InvalidData, e)
+                    })
+                    .boxed()
+                })))
+            }
+            Err(e) if BodyTooLarge::find(&e) => {
+                warn!("[forward proxy] {}: {}", access_label, e);
+                let res = self.error_response(None, http::StatusCode::PAYLOAD_TOO_LARGE).await;
+                Ok(access_entry.finish_with(res))
+            }
             Err(e) => Err(e),
         }
     }
@@ -235,7 +407,7 @@ impl ProxyHandler {
     /// 代理CONNECT请求
     /// HTTP/1.1 CONNECT    
     fn tunnel_proxy(
-        &self, req: Request<Incoming>, client_socket_addr: SocketAddr, username: String,
+        &self, req: Request<Incoming>, client_socket_addr: SocketAddr, username: String, mut access_entry: AccessEntry,
     ) -> Result<Response<BoxBody<Bytes, io::Error>>, io::Error> {
         // Received an HTTP request like:
         // ```
@@ -250,8 +422,15 @@ impl ProxyHandler {
         // Note: only after client received an empty body with STATUS_OK can the
         // connection be upgraded, so we can't return a response inside
         // `on_upgrade` future.
+        access_entry.set_kind(AccessKind::Tunnel);
         if let Some(addr) = host_addr(req.uri()) {
             let proxy_traffic = self.metrics.proxy_traffic.clone();
+            let tunnel_metrics = self.metrics.tunnel.clone();
+            let latency = self.metrics.latency.clone();
+            let resolver = self.resolver.clone();
+            let connect_proxy_protocol = self.config.connect_proxy_protocol;
+            access_entry.set_upstream(addr.to_string());
+            access_entry.set_status(http::StatusCode::OK);
             tokio::task::spawn(async move {
                 match hyper::upgrade::on(req).await {
                     Ok(src_upgraded) => {
@@ -261,7 +440,18 @@ impl ProxyHandler {
                             username,
                         };
                         // Connect to remote server
-                        match TcpStream::connect(addr.to_string()).await {
+                        let connected =
+                            connector::connect(&addr.to_string(), TunnelKind::Connect.as_str(), &resolver, &latency)
+                                .await;
+                        let connected = match (connected, connect_proxy_protocol) {
+                            (Ok(mut stream), Some(version)) => {
+                                proxy_protocol::write_header(&mut stream, version, client_socket_addr)
+                                    .await
+                                    .map(|_| stream)
+                            }
+                            (connected, _) => connected,
+                        };
+                        match connected {
                             Ok(target_stream) => {
                                 // if the DST server did not respond the FIN(shutdown) from the SRC client, then you will see a pair of FIN-WAIT-2 and CLOSE_WAIT in the proxy server
                                 // which two socketAddrs are in the true path.
@@ -285,9 +475,25 @@ impl ProxyHandler {
                                 let access_tag = access_label.to_string();
                                 let dst_stream =
                                     CounterIO::new(target_stream, proxy_traffic, LabelImpl::new(access_label));
-                                if let Err(e) = tunnel(src_upgraded, dst_stream).await {
-                                    warn!("[tunnel io error] [{}]: [{}] {} ", access_tag, e.kind(), e);
+                                let (stats, result) = tunnel(src_upgraded, dst_stream).await;
+                                if let Err(e) = result {
+                                    if stats.close_reason == CloseReason::Idle {
+                                        info!("[tunnel idle] [{}]: {}", access_tag, e);
+                                    } else {
+                                        warn!("[tunnel io error] [{}]: [{}] {} ", access_tag, e.kind(), e);
+                                    }
                                 };
+                                debug!(
+                                    "[tunnel closed] [{}] up: {} down: {} duration: {:?} ttfb: {:?} reason: {}",
+                                    access_tag,
+                                    stats.from_client,
+                                    stats.from_server,
+                                    stats.duration,
+                                    stats.ttfb,
+                                    stats.close_reason.as_str()
+                                );
+                                tunnel_metrics.observe(TunnelKind::Connect, &stats);
+                                access_entry.set_tunnel_stats(&stats);
                             }
                             Err(e) => {
                                 warn!("[tunnel establish error] [{}]: [{}] {} ", access_label, e.kind(), e)
@@ -312,7 +518,7 @@ impl ProxyHandler {
             let mut resp = Response::new(full_body("CONNECT must be to a socket address"));
             *resp.status_mut() = http::StatusCode::BAD_REQUEST;
 
-            Ok(resp)
+            Ok(access_entry.finish_with(resp))
         }
     }
 
@@ -338,10 +544,35 @@ impl ProxyHandler {
     }
 
     async fn reverse_proxy(
-        &self, req: Request<hyper::body::Incoming>, location_config: &LocationConfig, client_socket_addr: SocketAddr,
-        origin_scheme_host_port: &SchemeHostPort,
+        &self, mut req: Request<hyper::body::Incoming>, location_config: &LocationConfig,
+        client_socket_addr: SocketAddr, peer_ip: IpAddr, origin_scheme_host_port: &SchemeHostPort,
+        mut access_entry: AccessEntry,
     ) -> Result<Response<BoxBody<Bytes, io::Error>>, io::Error> {
-        let upstream_req = build_upstream_req(req, location_config)?;
+        access_entry.set_kind(AccessKind::Reverse);
+        access_entry.set_upstream(location_config.upstream.url_base.clone());
+        let client_version = req.version();
+        let max_body = location_config.max_request_body.or(self.config.max_request_body);
+        if let Some(res) = self
+            .check_request(&req, Some(&origin_scheme_host_port.host), max_body)
+            .await
+        {
+            return Ok(access_entry.finish_with(res));
+        }
+        let client_upgrade = upgrade::is_upgrade(&req).then(|| hyper::upgrade::on(&mut req));
+        let mut upstream_req = limit_body(build_upstream_req(req, location_config)?, max_body);
+        let trusted_proxies = &self.config.trusted_proxies;
+        trusted_proxies.append_forwarded_for(upstream_req.headers_mut(), peer_ip, client_version);
+        trusted_proxies.set_forwarded_proto_host(
+            upstream_req.headers_mut(),
+            peer_ip,
+            &origin_scheme_host_port.scheme,
+            &origin_scheme_host_port.authority(),
+        );
+        #[cfg(feature = "otel")]
+        let upstream_trace = access_entry
+            .trace()
+            .map(|trace| trace.start_upstream(&mut upstream_req));
+        let upstream_req = access_entry.count_request(upstream_req);
         info!(
             "[reverse proxy] {:^35} => {}{}** ==> [{}] {:?} [{:?}]",
             SocketAddrFormat(&client_socket_addr).to_string(),
@@ -351,24 +582,98 @@ impl ProxyHandler {
             &upstream_req.uri(),
             upstream_req.version(),
         );
-        self.metrics
-            .reverse_proxy_req
-            .get_or_create(&LabelImpl::new(ReverseProxyReqLabel {
+        self.metrics.reverse_proxy_req.with_series(
+            &LabelImpl::new(ReverseProxyReqLabel {
                 client: client_socket_addr.ip().to_canonical().to_string(),
                 origin: origin_scheme_host_port.to_string() + location_config.location.as_str(),
                 upstream: location_config.upstream.url_base.clone(),
-            }))
-            .inc();
+            }),
+            |counter| counter.inc(),
+        );
         self.metrics
             .reverse_proxy_req
-            .get_or_create(&ALL_REVERSE_PROXY_REQ)
-            .inc();
+            .with_series(&ALL_REVERSE_PROXY_REQ, |counter| counter.inc());
+        let upstreams = self
+            .upstream_health
+            .healthy(location_config.upstreams(), &location_config.health);
+        if upstreams.is_empty() {
+            warn!("reverse_proxy error: no healthy upstream for location {}", location_config.location);
+            let res = self
+                .proxy_error_response(
+                    location_config,
+                    &origin_scheme_host_port.host,
+                    client_version,
+                    ProxyError::DestinationUnavailable,
+                    None,
+                )
+                .await;
+            return Ok(access_entry.finish_with(res));
+        }
+        let timeouts = &location_config.timeouts;
+        let reverse_client = self.reverse_client(timeouts, client_upgrade.is_some());
+        let proxy_protocol_client = location_config
+            .proxy_protocol
+            .map(|version| self.proxy_protocol_client(timeouts, client_upgrade.is_some(), version, client_socket_addr));
+        let proxy_protocol_client = &proxy_protocol_client;
+        let upstream_health = &self.upstream_health;
+        let start = Instant::now();
+        let responding = location_config
+            .retry
+            .send(upstream_req, upstreams.len() as u32 - 1, |mut req, attempt| {
+                // 重试时依次换到failover中的upstream
+                let upstream = upstreams[attempt as usize % upstreams.len()];
+                async move {
+                    if !std::ptr::eq(upstream, &location_config.upstream) {
+                        retarget_upstream_req(&mut req, location_config, upstream)?;
+                    }
+                    let responding = match proxy_protocol_client {
+                        Some(client) => client.request(req),
+                        None => reverse_client.request(req),
+                    };
+                    let result = responding.await.map(|resp| (resp, upstream)).map_err(|e| {
+                        match (e.is_connect(), is_timeout(&e)) {
+                            (true, true) => UpstreamTimeout::into_io_error(TimeoutKind::Connect),
+                            (false, true) => io::Error::new(ErrorKind::TimedOut, e),
+                            (true, false) => retry::not_sent(e),
+                            (false, false) => io::Error::other(e),
+                        }
+                    });
+                    upstream_health.report(upstream, result.is_ok(), &location_config.health);
+                    result
+                }
+            });
+        // 让DNS解析等过程可以通过Context::current()找到上游请求的span
+        #[cfg(feature = "otel")]
+        let responding = opentelemetry::trace::FutureExt::with_context(
+            responding,
+            upstream_trace.clone().unwrap_or_else(opentelemetry::Context::current),
+        );
+        let result = match timeouts.header_deadline() {
+            Some((duration, kind)) => tokio::time::timeout(duration, responding)
+                .await
+                .unwrap_or_else(|_| Err(UpstreamTimeout::into_io_error(kind))),
+            None => responding.await,
+        };
+        #[cfg(feature = "otel")]
+        if let Some(cx) = &upstream_trace {
+            crate::otel::end_upstream(cx, result.as_ref().map(|(resp, _)| resp.status()));
+        }
+        let upstream = match &result {
+            Ok((_, upstream)) => upstream,
+            Err(_) => &location_config.upstream,
+        };
+        self.metrics.latency.observe_reverse_response(
+            &location_config.location,
+            &upstream.url_base,
+            start.elapsed(),
+            result.is_ok(),
+        );
         let context = ReverseReqContext {
-            upstream: &location_config.upstream,
+            upstream,
             origin_scheme_host_port,
         };
-        match self.reverse_client.request(upstream_req).await {
-            Ok(mut resp) => {
+        match result {
+            Ok((mut resp, _)) => {
                 if resp.status().is_redirection() && resp.headers().contains_key(LOCATION) {
                     let headers = resp.headers_mut();
                     let redirect_location = headers
@@ -389,21 +694,212 @@ impl ProxyHandler {
                         info!("redirect to [{}], origin is [{:?}]", replacement, origin);
                     }
                 }
-                Ok(resp.map(|body| {
+                if let (Some(client_upgrade), http::StatusCode::SWITCHING_PROTOCOLS) = (client_upgrade, resp.status()) {
+                    return Ok(self.upgrade_proxy(client_upgrade, resp, location_config, access_entry));
+                }
+                hop_by_hop::remove_hop_by_hop(resp.headers_mut(), false);
+                let resp = resp.map(|body| {
                     body.map_err(|e| {
                         let e = e;
                         io::Error::new(ErrorKind::InvalidData, e)
                     })
                     .boxed()
-                }))
+                });
+                if !timeouts.limits_body() {
+                    return Ok(access_entry.finish_with(resp));
+                }
+                // 收到响应头之后的超时只能中断响应体
+                let reverse_proxy_timeout = self.metrics.reverse_proxy_timeout.clone();
+                let location = location_config.location.clone();
+                let start = tokio::time::Instant::from_std(start);
+                Ok(access_entry.finish_with(resp.map(|body| {
+                    TimeoutBody::new(body, timeouts, start, move |kind| {
+                        warn!("reverse_proxy body of location {}: {}", location, kind.message());
+                        observe_reverse_proxy_timeout(&reverse_proxy_timeout, &location, kind);
+                    })
+                    .boxed()
+                })))
+            }
+            Err(e) if BodyTooLarge::find(&e) => {
+                warn!("reverse_proxy error: {}", e);
+                let res = self
+                    .error_response(Some(&context.origin_scheme_host_port.host), http::StatusCode::PAYLOAD_TOO_LARGE)
+                    .await;
+                Ok(access_entry.finish_with(res))
             }
             Err(e) => {
-                warn!("reverse_proxy error: {:?}", e);
-                Err(io::Error::new(ErrorKind::InvalidData, e))
+                let error = ProxyError::classify(&e);
+                warn!("reverse_proxy error: {} {:?}", error.as_str(), e);
+                // 没有标记的超时来自重试的per_try_timeout_ms或者上游连接，都是在等待响应头
+                let timeout = UpstreamTimeout::find(&e).or_else(|| is_timeout(&e).then_some(TimeoutKind::Header));
+                if let Some(kind) = timeout {
+                    observe_reverse_proxy_timeout(&self.metrics.reverse_proxy_timeout, &location_config.location, kind);
+                }
+                let res = self
+                    .proxy_error_response(
+                        location_config,
+                        &context.origin_scheme_host_port.host,
+                        client_version,
+                        error,
+                        timeout.map(|kind| kind.message()),
+                    )
+                    .await;
+                Ok(access_entry.finish_with(res))
             }
         }
     }
 
+    /// 转发前检查请求：消息边界有歧义时返回400，Content-Length超过上限时返回413
+    async fn check_request(
+        &self, req: &Request<Incoming>, host: Option<&str>, max_body: Option<u64>,
+    ) -> Option<Response<BoxBody<Bytes, io::Error>>> {
+        if let Err(reason) = hop_by_hop::check_framing(req.headers(), req.version()) {
+            warn!("reject ambiguous request {} {}: {}", req.method(), req.uri(), reason);
+            return Some(
+                error_page::response_with_detail(&self.error_pages(host), http::StatusCode::BAD_REQUEST, Some(reason))
+                    .await,
+            );
+        }
+        if body_limit::exceeds(req.body(), max_body) {
+            warn!("reject request {} {}: body exceeds {:?} bytes", req.method(), req.uri(), max_body);
+            return Some(self.error_response(host, http::StatusCode::PAYLOAD_TOO_LARGE).await);
+        }
+        None
+    }
+
+    /// 上游同意升级协议：返回101响应，在后台等待两端完成升级后双向转发
+    fn upgrade_proxy(
+        &self, client_upgrade: OnUpgrade, mut resp: Response<Incoming>, location_config: &LocationConfig,
+        mut access_entry: AccessEntry,
+    ) -> Response<BoxBody<Bytes, io::Error>> {
+        let upstream_upgrade = hyper::upgrade::on(&mut resp);
+        // 升级后的连接没有响应体，空闲超时使用location的idle_ms
+        let idle_timeout = match location_config.timeouts.idle_ms {
+            0 => crate::IDLE_TIMEOUT,
+            idle_ms => Duration::from_millis(idle_ms),
+        };
+        let tunnel_metrics = self.metrics.tunnel.clone();
+        let location = location_config.location.clone();
+        access_entry.set_status(resp.status());
+        tokio::spawn(async move {
+            match upgrade::bridge(client_upgrade, upstream_upgrade, idle_timeout).await {
+                Ok((stats, result)) => {
+                    if let Err(e) = result {
+                        if stats.close_reason == CloseReason::Idle {
+                            info!("[upgrade idle] [{}]: {}", location, e);
+                        } else {
+                            warn!("[upgrade io error] [{}]: [{}] {} ", location, e.kind(), e);
+                        }
+                    }
+                    debug!(
+                        "[upgrade closed] [{}] up: {} down: {} duration: {:?} reason: {}",
+                        location,
+                        stats.from_client,
+                        stats.from_server,
+                        stats.duration,
+                        stats.close_reason.as_str()
+                    );
+                    tunnel_metrics.observe(TunnelKind::Upgrade, &stats);
+                    access_entry.set_tunnel_stats(&stats);
+                }
+                Err(e) => warn!("[upgrade error] [{}]: {}", location, e),
+            }
+        });
+        resp.map(|_| empty_body())
+    }
+
+    /// 上游失败时的响应：状态码按错误类型区分，带上Proxy-Status和Via，body为错误页面
+    async fn proxy_error_response(
+        &self, location_config: &LocationConfig, host: &str, client_version: Version, error: ProxyError,
+        detail: Option<&str>,
+    ) -> Response<BoxBody<Bytes, io::Error>> {
+        let status = error.status();
+        self.metrics
+            .reverse_proxy_error
+            .get_or_create(&LabelImpl::new(ReverseProxyErrorLabel {
+                location: location_config.location.clone(),
+                status: status.as_u16(),
+                cause: error.as_str(),
+            }))
+            .inc();
+        let detail = detail.unwrap_or(error.message());
+        let mut res = error_page::response_with_detail(&self.error_pages(Some(host)), status, Some(detail)).await;
+        let headers = res.headers_mut();
+        headers.insert(PROXY_STATUS, error.proxy_status(detail));
+        headers.insert(header::VIA, proxy_status::via(client_version));
+        res
+    }
+
+    fn reverse_client(&self, timeouts: &UpstreamTimeouts, http1_only: bool) -> &ReverseClient {
+        match self.reverse_clients.get(&(timeouts.connect_ms, http1_only)) {
+            Some(client) => client,
+            None => &self.reverse_clients[&(0, http1_only)],
+        }
+    }
+
+    /// 头部中的客户端地址每个请求不同，client和连接都不复用
+    fn proxy_protocol_client(
+        &self, timeouts: &UpstreamTimeouts, http1_only: bool, version: ProxyProtocolVersion, client: SocketAddr,
+    ) -> ProxyProtocolClient {
+        let connector = ProxyProtocolConnector::new(
+            reverse_http_connector(self.resolver.clone(), self.metrics.latency.clone(), timeouts.connect()),
+            version,
+            client,
+        );
+        legacy::Client::builder(TokioExecutor::new())
+            .pool_max_idle_per_host(0)
+            .build(https_connector(self.upstream_tls_config.clone(), http1_only, connector))
+    }
+
+    /// 错误页面的查找顺序：Host的配置、default_host的配置、全局配置（--error-page）
+    fn error_pages(&self, host: Option<&str>) -> Vec<&ErrorPages> {
+        let host_error_pages = &self.config.reverse_proxy_config.error_pages;
+        host.and_then(|host| host_error_pages.get(host))
+            .or(host_error_pages.get(config::DEFAULT_HOST))
+            .into_iter()
+            .chain(std::iter::once(&self.config.error_pages))
+            .collect()
+    }
+
+    /// 错误页面的响应，没有配置时使用内置页面
+    pub(crate) async fn error_response(
+        &self, host: Option<&str>, status: http::StatusCode,
+    ) -> Response<BoxBody<Bytes, io::Error>> {
+        error_page::response(&self.error_pages(host), status).await
+    }
+
+    /// 本地生成的错误响应：有配置的错误页面时替换body；没有配置时，有内置页面的状态码使用内置页面
+    async fn with_error_page(
+        &self, host: Option<&str>, res: Response<BoxBody<Bytes, io::Error>>,
+    ) -> Response<BoxBody<Bytes, io::Error>> {
+        let status = res.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return res;
+        }
+        let body = match error_page::load(&self.error_pages(host), status).await {
+            Some(body) => body,
+            None if error_page::EMBEDDED_STATUSES.contains(&status) => error_page::embedded(status),
+            None => return res,
+        };
+        let (mut parts, _) = res.into_parts();
+        for header in [
+            http::header::CONTENT_LENGTH,
+            http::header::CONTENT_ENCODING,
+            http::header::ETAG,
+            http::header::LAST_MODIFIED,
+        ] {
+            parts.headers.remove(header);
+        }
+        // 416的Content-Range（bytes */len）告诉客户端资源的长度，需要保留
+        if status != http::StatusCode::RANGE_NOT_SATISFIABLE {
+            parts.headers.remove(http::header::CONTENT_RANGE);
+        }
+        parts
+            .headers
+            .insert(http::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        Response::from_parts(parts, full_body(body))
+    }
+
     #[cfg(all(target_os = "linux", feature = "bpf"))]
     pub(crate) fn snapshot_metrics(&self) {
         use crate::ebpf;
@@ -434,9 +930,8 @@ impl ProxyHandler {
 }
 
 fn mod_http1_proxy_req(req: &mut Request<Incoming>) -> io::Result<()> {
-    // 删除代理特有的请求头
-    req.headers_mut().remove(http::header::PROXY_AUTHORIZATION.to_string());
-    req.headers_mut().remove("Proxy-Connection");
+    // 删除逐跳和代理特有的请求头
+    hop_by_hop::remove_hop_by_hop(req.headers_mut(), false);
     // set host header
     let uri = req.uri().clone();
     let hostname = uri
@@ -453,8 +948,7 @@ fn mod_http1_proxy_req(req: &mut Request<Incoming>) -> io::Result<()> {
     if Some(host_header.clone()) != origin {
         info!("change host header: {:?} -> {:?}", origin, host_header);
     }
-    // change absoulte uri to relative uri
-    origin_form(req.uri_mut())?;
+    // 保留absolute uri，由HttpClient根据协商出的协议转换
     Ok(())
 }
 
@@ -466,21 +960,14 @@ fn build_upstream_req(req: Request<Incoming>, location_config: &LocationConfig)
     };
     let url = location_config.upstream.url_base.clone() + &path_and_query[location_config.location.len()..];
 
-    let mut builder = Request::builder().method(method).uri(url).version(
-        if !location_config.upstream.url_base.starts_with("https:") {
-            match location_config.upstream.version {
-                reverse::Version::H1 => Version::HTTP_11,
-                reverse::Version::H2 => Version::HTTP_2,
-                reverse::Version::Auto => Version::HTTP_11,
-            }
-        } else {
-            match location_config.upstream.version {
-                reverse::Version::H1 => Version::HTTP_11,
-                reverse::Version::H2 => Version::HTTP_2,
-                reverse::Version::Auto => req.version(),
-            }
-        },
-    );
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(url)
+        .version(match upgrade::is_upgrade(&req) {
+            // 协议升级只有HTTP/1.1支持
+            true => Version::HTTP_11,
+            false => upstream_version(&location_config.upstream, req.version()),
+        });
     let header_map = match builder.headers_mut() {
         Some(header_map) => header_map,
         None => {
@@ -497,17 +984,75 @@ fn build_upstream_req(req: Request<Incoming>, location_config: &LocationConfig)
             info!("skip host header: {:?}", ele.1);
         }
     }
+    hop_by_hop::remove_hop_by_hop(header_map, upgrade::is_upgrade(&req));
     builder
         .body(req.into_body())
         .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
 }
 
+/// 有上限时，请求体在转发过程中超过上限会中断
+fn limit_body<B>(req: Request<B>, max_body: Option<u64>) -> Request<BoxBody<Bytes, io::Error>>
+where
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    match max_body {
+        Some(limit) => req.map(|body| LimitedBody::new(body, limit).boxed()),
+        None => req.map(|body| body.map_err(|e| io::Error::new(ErrorKind::InvalidData, e)).boxed()),
+    }
+}
+
+fn upstream_version(upstream: &Upstream, client_version: Version) -> Version {
+    if !upstream.url_base.starts_with("https:") {
+        match upstream.version {
+            reverse::Version::H1 => Version::HTTP_11,
+            reverse::Version::H2 => Version::HTTP_2,
+            reverse::Version::Auto => Version::HTTP_11,
+        }
+    } else {
+        match upstream.version {
+            reverse::Version::H1 => Version::HTTP_11,
+            reverse::Version::H2 => Version::HTTP_2,
+            reverse::Version::Auto => client_version,
+        }
+    }
+}
+
+/// 重试时把上游请求改为发往另一个upstream
+fn retarget_upstream_req<B>(
+    req: &mut Request<B>, location_config: &LocationConfig, upstream: &Upstream,
+) -> io::Result<()> {
+    let uri = req.uri().to_string();
+    let suffix = uri
+        .strip_prefix(location_config.upstream.url_base.as_str())
+        .unwrap_or_else(|| req.uri().path_and_query().map(|p| p.as_str()).unwrap_or_default());
+    let uri = (upstream.url_base.clone() + suffix)
+        .parse::<Uri>()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let client_version = req.version();
+    *req.uri_mut() = uri;
+    if !upgrade::is_upgrade(req) {
+        *req.version_mut() = upstream_version(upstream, client_version);
+    }
+    Ok(())
+}
+
 struct SchemeHostPort {
     scheme: String,
     host: String,
     port: Option<u16>,
 }
 
+impl SchemeHostPort {
+    /// host和非默认端口，用于X-Forwarded-Host
+    fn authority(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}:{}", self.host, port),
+            None => self.host.clone(),
+        }
+    }
+}
+
 impl Display for SchemeHostPort {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self.port {
@@ -637,20 +1182,70 @@ fn pick_location<'b>(path: &str, locations: &'b [LocationConfig]) -> Option<&'b
     locations.iter().find(|&ele| path.starts_with(&ele.location))
 }
 
-fn build_hyper_legacy_client() -> legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, Incoming> {
-    let pool_idle_timeout = Duration::from_secs(90);
-    // 创建一个 HttpConnector
-    let mut http_connector = HttpConnector::new();
+/// 上游请求是否因为超时失败
+fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+fn observe_reverse_proxy_timeout(
+    counter: &Family<LabelImpl<ReverseProxyTimeoutLabel>, Counter>, location: &str, kind: TimeoutKind,
+) {
+    counter
+        .get_or_create(&LabelImpl::new(ReverseProxyTimeoutLabel {
+            location: location.to_owned(),
+            timeout: kind.as_str(),
+        }))
+        .inc();
+}
+
+fn pick_static_location<'b>(
+    path: &str, static_locations: &'b [StaticLocationConfig],
+) -> Option<&'b StaticLocationConfig> {
+    static_locations.iter().find(|&ele| ele.relative_path(path).is_some())
+}
+
+const REVERSE_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// 创建一个 HttpConnector，使用记录DNS解析耗时的resolver
+fn reverse_http_connector(
+    resolver: Resolver, latency: LatencyMetrics, connect_timeout: Option<Duration>,
+) -> HttpConnector<TimedResolver> {
+    let happy_eyeballs_delay = resolver.happy_eyeballs_delay();
+    let mut http_connector = HttpConnector::new_with_resolver(TimedResolver::new(resolver, latency));
+    http_connector.set_happy_eyeballs_timeout(Some(happy_eyeballs_delay));
     http_connector.enforce_http(false);
-    http_connector.set_keepalive(Some(pool_idle_timeout));
-
-    let https_connector = HttpsConnectorBuilder::new()
-        .with_platform_verifier()
-        .https_or_http()
-        .enable_all_versions()
-        .wrap_connector(http_connector);
-    // 创建一个 HttpsConnector，使用 rustls 作为后端
-    let client: legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, Incoming> =
+    http_connector.set_keepalive(Some(REVERSE_POOL_IDLE_TIMEOUT));
+    http_connector.set_connect_timeout(connect_timeout);
+    http_connector
+}
+
+/// 使用 rustls 作为后端的 HttpsConnector
+fn https_connector<C>(tls_config: ClientConfig, http1_only: bool, connector: C) -> hyper_rustls::HttpsConnector<C> {
+    let https_connector = HttpsConnectorBuilder::new().with_tls_config(tls_config).https_or_http();
+    // 协议升级的请求不能通过ALPN协商到h2
+    match http1_only {
+        true => https_connector.enable_http1().wrap_connector(connector),
+        false => https_connector.enable_all_versions().wrap_connector(connector),
+    }
+}
+
+fn build_hyper_legacy_client(
+    tls_config: ClientConfig, resolver: Resolver, latency: LatencyMetrics, connect_timeout: Option<Duration>,
+    http1_only: bool,
+) -> ReverseClient {
+    let pool_idle_timeout = REVERSE_POOL_IDLE_TIMEOUT;
+    let https_connector =
+        https_connector(tls_config, http1_only, reverse_http_connector(resolver, latency, connect_timeout));
+    let client: legacy::Client<hyper_rustls::HttpsConnector<HttpConnector<TimedResolver>>, BoxBody<Bytes, io::Error>> =
         legacy::Client::builder(TokioExecutor::new())
             .pool_idle_timeout(pool_idle_timeout)
             .pool_max_idle_per_host(5)
@@ -659,13 +1254,62 @@ fn build_hyper_legacy_client() -> legacy::Client<hyper_rustls::HttpsConnector<Ht
     client
 }
 
-fn register_metrics(registry: &mut Registry) -> Metrics {
-    let http_req_counter = Family::<LabelImpl<ReqLabels>, Counter>::default();
+fn register_metrics(registry: &mut Registry, max_series: usize, idle_ttl: Duration) -> Metrics {
+    let http_req_counter = BoundedFamily::<LabelImpl<ReqLabels>, Counter>::new(
+        LabelImpl::new(ReqLabels {
+            referer: OVERFLOW_LABEL_VALUE.to_string(),
+            path: OVERFLOW_LABEL_VALUE.to_string(),
+        }),
+        max_series,
+        idle_ttl,
+    );
+    http_req_counter.pin(crate::web_func::ALL_HTTP_REQ.clone());
     registry.register("req_from_out", "Number of HTTP requests received", http_req_counter.clone());
-    let reverse_proxy_req = Family::<LabelImpl<ReverseProxyReqLabel>, Counter>::default();
+    let reverse_proxy_req = BoundedFamily::<LabelImpl<ReverseProxyReqLabel>, Counter>::new(
+        LabelImpl::new(ReverseProxyReqLabel {
+            client: OVERFLOW_LABEL_VALUE.to_string(),
+            origin: OVERFLOW_LABEL_VALUE.to_string(),
+            upstream: OVERFLOW_LABEL_VALUE.to_string(),
+        }),
+        max_series,
+        idle_ttl,
+    );
+    reverse_proxy_req.pin(ALL_REVERSE_PROXY_REQ.clone());
     registry.register("reverse_proxy_req", "Number of reverse proxy requests", reverse_proxy_req.clone());
-    let proxy_traffic = Family::<LabelImpl<AccessLabel>, Counter>::default();
+    let proxy_traffic = BoundedFamily::<LabelImpl<AccessLabel>, Counter>::new(
+        LabelImpl::new(AccessLabel {
+            client: OVERFLOW_LABEL_VALUE.to_string(),
+            target: OVERFLOW_LABEL_VALUE.to_string(),
+            username: OVERFLOW_LABEL_VALUE.to_string(),
+        }),
+        max_series,
+        idle_ttl,
+    );
     registry.register("proxy_traffic", "num proxy_traffic", proxy_traffic.clone());
+    let hotlink_blocked = BoundedFamily::<LabelImpl<HotlinkLabel>, Counter>::new(
+        LabelImpl::new(HotlinkLabel {
+            referer_host: OVERFLOW_LABEL_VALUE.to_string(),
+        }),
+        max_series,
+        idle_ttl,
+    );
+    registry.register("hotlink_blocked", "Number of requests blocked by hotlink protection", hotlink_blocked.clone());
+    let tunnel = TunnelMetrics::register(registry);
+    let latency = LatencyMetrics::register(registry);
+    let dns = DnsMetrics::register(registry);
+    let forward_pool = PoolMetrics::register(registry);
+    let reverse_proxy_timeout = Family::<LabelImpl<ReverseProxyTimeoutLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_timeout",
+        "Number of reverse proxy requests aborted by timeouts",
+        reverse_proxy_timeout.clone(),
+    );
+    let reverse_proxy_error = Family::<LabelImpl<ReverseProxyErrorLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_error",
+        "Number of reverse proxy requests answered with 502, 503 or 504",
+        reverse_proxy_error.clone(),
+    );
     #[cfg(all(target_os = "linux", feature = "bpf"))]
     let net_bytes = Family::<LabelImpl<NetDirectionLabel>, Counter>::default();
     #[cfg(all(target_os = "linux", feature = "bpf"))]
@@ -675,13 +1319,22 @@ fn register_metrics(registry: &mut Registry) -> Metrics {
     #[cfg(all(target_os = "linux", feature = "bpf"))]
     registry.register("cgroup_bytes", "num this cgroup's net traffic in bytes", cgroup_bytes.clone());
 
-    register_metric_cleaner(proxy_traffic.clone(), "proxy_traffic".to_owned(), 24);
-    // register_metric_cleaner(http_req_counter.clone(), 7 * 24);
+    register_metric_evictor(proxy_traffic.clone(), "proxy_traffic");
+    register_metric_evictor(reverse_proxy_req.clone(), "reverse_proxy_req");
+    register_metric_evictor(http_req_counter.clone(), "req_from_out");
+    register_metric_evictor(hotlink_blocked.clone(), "hotlink_blocked");
 
     Metrics {
         http_req_counter,
         proxy_traffic,
         reverse_proxy_req,
+        hotlink_blocked,
+        tunnel,
+        latency,
+        dns,
+        forward_pool,
+        reverse_proxy_timeout,
+        reverse_proxy_error,
         #[cfg(all(target_os = "linux", feature = "bpf"))]
         net_bytes,
         #[cfg(all(target_os = "linux", feature = "bpf"))]
@@ -689,19 +1342,27 @@ fn register_metrics(registry: &mut Registry) -> Metrics {
     }
 }
 
-// 每两小时清空一次，否则一直累积，光是exporter的流量就很大，观察到每天需要3.7GB。不用担心rate函数不准，promql查询会自动处理reset（数据突降）的数据。
-// 不过，虽然能够处理reset，但increase会用最后一个出现的值-第一个出现的值。在我们清空的实现下，reset后第一个出现的值肯定不是0，所以increase的算出来的值会稍少（少第一次出现的值）
-// 因此对于准确性要求较高的http_req_counter，这里的清空间隔就放大一点
-fn register_metric_cleaner<T: Label + Send + Sync>(counter: Family<T, Counter>, name: String, interval_in_hour: u64) {
+/// label组合超过上限后计入的series的label值
+const OVERFLOW_LABEL_VALUE: &str = "other";
+
+// label中带有客户端IP等取值不受控的字段，不处理的话series一直累积，光是exporter的流量就很大，观察到每天需要3.7GB。
+// 以前是定时清空整个Family，所有series同时reset，increase会少算reset后第一次出现的值。
+// 现在逐个淘汰空闲超过ttl的series，活跃的series不受影响；同时由BoundedFamily限制series的数量
+fn register_metric_evictor<T: Label + Send + Sync>(family: BoundedFamily<T, Counter>, name: &'static str) {
     tokio::spawn(async move {
+        let mut interval = tokio::time::interval(METRIC_EVICT_INTERVAL);
         loop {
-            tokio::time::sleep(Duration::from_secs(interval_in_hour * 60 * 60)).await;
-            info!("cleaning prometheus metric labels for {}", name);
-            counter.clear();
+            interval.tick().await;
+            let evicted = family.evict_idle();
+            if evicted > 0 {
+                info!("evicted {} idle series of {}, {} left", evicted, name, family.len());
+            }
         }
     });
 }
 
+const METRIC_EVICT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 pub(crate) fn check_auth(
     config_basic_auth: &HashMap<String, String>, req: &Request<impl Body>, client_socket_addr: &SocketAddr,
     header_name: HeaderName,
@@ -736,33 +1397,23 @@ pub(crate) fn check_auth(
     (username, authed)
 }
 
-fn origin_form(uri: &mut Uri) -> io::Result<()> {
-    let path = match uri.path_and_query() {
-        Some(path) if path.as_str() != "/" => {
-            let mut parts = ::http::uri::Parts::default();
-            parts.path_and_query = Some(path.clone());
-            Uri::from_parts(parts).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
-        }
-        _none_or_just_slash => {
-            debug_assert!(Uri::default() == "/");
-            Uri::default()
-        }
-    };
-    *uri = path;
-    Ok(())
-}
-
 // Create a TCP connection to host:port, build a tunnel between the connection and
 // the upgraded connection
-async fn tunnel(upgraded: Upgraded, target_io: CounterIO<TcpStream, LabelImpl<AccessLabel>>) -> io::Result<()> {
+// 即使隧道因为错误关闭，也会返回已经传输的字节数
+async fn tunnel(
+    upgraded: Upgraded, target_io: CounterIO<TcpStream, LabelImpl<AccessLabel>>,
+) -> (TunnelStats, io::Result<()>) {
     let mut upgraded = TokioIo::new(upgraded);
-    let timed_target_io = TimeoutIO::new(target_io, crate::IDLE_TIMEOUT);
+    let timed_target_io = StatsIO::new(TimeoutIO::new(target_io, crate::IDLE_TIMEOUT));
+    let io_stats = timed_target_io.stats();
     pin!(timed_target_io);
     // https://github.com/sfackler/tokio-io-timeout/issues/12
     // timed_target_io.as_mut() // 一定要as_mut()，否则会move所有权
     // ._set_timeout_pinned(Duration::from_secs(crate::IDLE_SECONDS));
-    let (_from_client, _from_server) = tokio::io::copy_bidirectional(&mut upgraded, &mut timed_target_io).await?;
-    Ok(())
+    let result = tokio::io::copy_bidirectional(&mut upgraded, &mut timed_target_io)
+        .await
+        .map(|_| ());
+    (TunnelStats::new(&io_stats, CloseReason::from_io_result(&result)), result)
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
@@ -787,6 +1438,26 @@ pub struct ReverseProxyReqLabel {
     pub upstream: String,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct ReverseProxyTimeoutLabel {
+    location: String,
+    /// connect、header、total、idle
+    timeout: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct ReverseProxyErrorLabel {
+    location: String,
+    status: u16,
+    /// Proxy-Status中的错误类型，例如connection_refused
+    cause: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, PartialOrd, Ord)]
+pub struct HotlinkLabel {
+    pub referer_host: String,
+}
+
 static ALL_REVERSE_PROXY_REQ: LazyLock<prom_label::LabelImpl<ReverseProxyReqLabel>> = LazyLock::new(|| {
     LabelImpl::new(ReverseProxyReqLabel {
         client: "all".to_string(),
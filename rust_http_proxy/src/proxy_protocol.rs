@@ -0,0 +1,255 @@
+//! PROXY protocol v1/v2（HAProxy定义），在TCP连接开头传递原始的客户端地址
+//!
+//! 监听端口上只解析来自受信任地址的头部；发往上游和CONNECT目标时在建连后先写入头部
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// v2头部的签名
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// v1头部的最大长度，包括结尾的CRLF
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ProxyProtocolVersion {
+    /// 文本格式
+    V1,
+    /// 二进制格式
+    V2,
+}
+
+/// 头部中的地址。LOCAL命令、UNKNOWN和不支持的地址族没有地址，此时使用连接本身的地址
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ProxyHeader {
+    pub(crate) source: SocketAddr,
+    pub(crate) destination: SocketAddr,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PROXY protocol header: {}", msg))
+}
+
+/// 从连接中读取头部，只读取头部本身的字节，之后的数据留给HTTP
+pub(crate) async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<ProxyHeader>> {
+    // v1最短的头部 "PROXY UNKNOWN\r\n" 也有15字节，先读12字节不会读到头部之后的数据
+    let mut buf = vec![0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut buf).await?;
+    if buf == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut addresses = vec![0; len];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(fixed[0], fixed[1], &addresses);
+    }
+    if !buf.starts_with(b"PROXY ") {
+        return Err(invalid("missing signature"));
+    }
+    while !buf.ends_with(b"\r\n") {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        buf.push(stream.read_u8().await?);
+    }
+    parse_v1(&buf[..buf.len() - 2])
+}
+
+/// 解析去掉CRLF的v1头部，例如 `PROXY TCP4 1.1.1.1 2.2.2.2 51234 443`
+fn parse_v1(line: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ascii"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let ip = |ip: &str| {
+                ip.parse::<IpAddr>()
+                    .ok()
+                    .filter(|ip| ip.is_ipv4() == (*family == "TCP4"))
+                    .ok_or_else(|| invalid("wrong address"))
+            };
+            let port = |port: &str| port.parse::<u16>().map_err(|_| invalid("wrong port"));
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            }))
+        }
+        _ => Err(invalid("wrong v1 fields")),
+    }
+}
+
+/// 解析v2头部签名之后的部分，TLV扩展被忽略
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("wrong v2 version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL：负载均衡自己的连接（例如健康检查），使用连接的地址
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("wrong v2 command")),
+    }
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    match family >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let ip = |bytes: &[u8]| IpAddr::from(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]));
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(&addresses[0..4]), port(&addresses[8..10])),
+                destination: SocketAddr::new(ip(&addresses[4..8]), port(&addresses[10..12])),
+            }))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let ip = |bytes: &[u8]| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+                IpAddr::from(Ipv6Addr::from(octets))
+            };
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(&addresses[0..16]), port(&addresses[32..34])),
+                destination: SocketAddr::new(ip(&addresses[16..32]), port(&addresses[34..36])),
+            }))
+        }
+        1 | 2 => Err(invalid("v2 addresses too short")),
+        // AF_UNSPEC、AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+/// 生成头部。源地址和目标地址的地址族不同时，IPv4地址转换为IPv4-mapped IPv6地址
+pub(crate) fn encode(version: ProxyProtocolVersion, header: &ProxyHeader) -> Vec<u8> {
+    let (source, destination) = match (header.source.ip(), header.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => (IpAddr::V4(source), IpAddr::V4(destination)),
+        (source, destination) => (IpAddr::V6(to_ipv6(source)), IpAddr::V6(to_ipv6(destination))),
+    };
+    let (source_port, destination_port) = (header.source.port(), header.destination.port());
+    match version {
+        ProxyProtocolVersion::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source,
+            destination,
+            source_port,
+            destination_port
+        )
+        .into_bytes(),
+        ProxyProtocolVersion::V2 => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            // 版本2，PROXY命令
+            buf.push(0x21);
+            let addresses = match (source, destination) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    buf.push(0x11);
+                    [source.octets().to_vec(), destination.octets().to_vec()].concat()
+                }
+                (source, destination) => {
+                    buf.push(0x21);
+                    [
+                        to_ipv6(source).octets().to_vec(),
+                        to_ipv6(destination).octets().to_vec(),
+                    ]
+                    .concat()
+                }
+            };
+            buf.extend_from_slice(&(addresses.len() as u16 + 4).to_be_bytes());
+            buf.extend_from_slice(&addresses);
+            buf.extend_from_slice(&source_port.to_be_bytes());
+            buf.extend_from_slice(&destination_port.to_be_bytes());
+            buf
+        }
+    }
+}
+
+/// 建连后写入头部，目标地址为连接的对端（上游或者CONNECT的目标）
+pub(crate) async fn write_header(
+    stream: &mut TcpStream, version: ProxyProtocolVersion, source: SocketAddr,
+) -> io::Result<()> {
+    let header = ProxyHeader {
+        source,
+        destination: stream.peer_addr()?,
+    };
+    stream.write_all(&encode(version, &header)).await
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(bytes: &[u8]) -> io::Result<(Option<ProxyHeader>, Vec<u8>)> {
+        let mut stream = bytes;
+        let header = read_header(&mut stream).await?;
+        Ok((header, stream.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_read_header() -> io::Result<()> {
+        let (header, rest) = read(b"PROXY TCP4 1.1.1.1 2.2.2.2 51234 443\r\nGET / HTTP/1.1\r\n").await?;
+        assert_eq!(
+            header,
+            Some(ProxyHeader {
+                source: SocketAddr::from(([1, 1, 1, 1], 51234)),
+                destination: SocketAddr::from(([2, 2, 2, 2], 443)),
+            })
+        );
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n").await?;
+        assert_eq!(header.map(|header| header.source.to_string()), Some("[2001:db8::1]:51234".to_owned()));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await?.0, None);
+        assert!(read(b"PROXY TCP4 2001:db8::1 2.2.2.2 1 2\r\n").await.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.is_err());
+        assert!(read(&[b"PROXY ".as_slice(), &[b'1'; 200]].concat()).await.is_err());
+
+        // v2，带有一个TLV
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[
+            0x21, 0x11, 0, 15, 1, 1, 1, 1, 2, 2, 2, 2, 0xc8, 0x22, 1, 0xbb, 0x04, 0, 0,
+        ]);
+        let (header, rest) = read(&[v2.as_slice(), b"GET"].concat()).await?;
+        assert_eq!(header.map(|header| header.source), Some(SocketAddr::from(([1, 1, 1, 1], 51234))));
+        assert_eq!(rest, b"GET");
+        // LOCAL命令
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read(&local).await?.0, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode() -> io::Result<()> {
+        let v4 = ProxyHeader {
+            source: SocketAddr::from(([1, 1, 1, 1], 51234)),
+            destination: SocketAddr::from(([2, 2, 2, 2], 443)),
+        };
+        assert_eq!(encode(ProxyProtocolVersion::V1, &v4), b"PROXY TCP4 1.1.1.1 2.2.2.2 51234 443\r\n");
+        let mixed = ProxyHeader {
+            destination: "[2001:db8::2]:443".parse().map_err(io::Error::other)?,
+            ..v4
+        };
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for header in [v4, mixed] {
+                let (parsed, rest) = read(&encode(version, &header)).await?;
+                assert!(rest.is_empty());
+                let parsed = parsed.ok_or_else(|| io::Error::other("no address"))?;
+                assert_eq!(parsed.source.ip().to_canonical(), header.source.ip());
+                assert_eq!(parsed.destination, header.destination);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! 反向代理上游失败的分类，错误类型使用RFC 9209 Proxy-Status中定义的名称
+//!
+//! 连接被拒绝、TLS失败等返回502，超时返回504，没有健康的upstream返回503
+
+use std::{error::Error, io};
+
+use http::{HeaderName, HeaderValue, StatusCode};
+use tokio_rustls::rustls;
+
+use crate::upstream_timeout::{TimeoutKind, UpstreamTimeout};
+
+/// Via和Proxy-Status中标识本代理的名称
+pub(crate) const PROXY_NAME: &str = "rust_http_proxy";
+
+pub(crate) const PROXY_STATUS: HeaderName = HeaderName::from_static("proxy-status");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ProxyError {
+    DnsError,
+    ConnectionRefused,
+    ConnectionTimeout,
+    ConnectionTerminated,
+    TlsCertificateError,
+    TlsAlertReceived,
+    TlsProtocolError,
+    HttpResponseTimeout,
+    DestinationUnavailable,
+    HttpProtocolError,
+}
+
+impl ProxyError {
+    /// 根据上游请求的错误分类
+    pub(crate) fn classify(e: &io::Error) -> Self {
+        match UpstreamTimeout::find(e) {
+            Some(TimeoutKind::Connect) => return ProxyError::ConnectionTimeout,
+            Some(_) => return ProxyError::HttpResponseTimeout,
+            None => {}
+        }
+        let mut classified = None;
+        let mut source: Option<&(dyn Error + 'static)> = Some(e);
+        while let Some(err) = source {
+            if let Some(tls) = err.downcast_ref::<rustls::Error>() {
+                return match tls {
+                    rustls::Error::InvalidCertificate(_) => ProxyError::TlsCertificateError,
+                    rustls::Error::AlertReceived(_) => ProxyError::TlsAlertReceived,
+                    _ => ProxyError::TlsProtocolError,
+                };
+            }
+            if err.to_string() == "dns error" {
+                return ProxyError::DnsError;
+            }
+            if let Some(io_err) = err.downcast_ref::<io::Error>() {
+                // 外层的io::Error优先，例如重试时标记的TimedOut
+                classified = classified.or(match io_err.kind() {
+                    io::ErrorKind::ConnectionRefused => Some(ProxyError::ConnectionRefused),
+                    io::ErrorKind::TimedOut => Some(ProxyError::HttpResponseTimeout),
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof => Some(ProxyError::ConnectionTerminated),
+                    _ => None,
+                });
+                // io::Error的source()会跳过其中包装的错误
+                if let Some(inner) = io_err.get_ref() {
+                    source = Some(inner);
+                    continue;
+                }
+            }
+            source = err.source();
+        }
+        classified.unwrap_or(ProxyError::HttpProtocolError)
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ProxyError::DnsError => "dns_error",
+            ProxyError::ConnectionRefused => "connection_refused",
+            ProxyError::ConnectionTimeout => "connection_timeout",
+            ProxyError::ConnectionTerminated => "connection_terminated",
+            ProxyError::TlsCertificateError => "tls_certificate_error",
+            ProxyError::TlsAlertReceived => "tls_alert_received",
+            ProxyError::TlsProtocolError => "tls_protocol_error",
+            ProxyError::HttpResponseTimeout => "http_response_timeout",
+            ProxyError::DestinationUnavailable => "destination_unavailable",
+            ProxyError::HttpProtocolError => "http_protocol_error",
+        }
+    }
+
+    /// 错误页面和Proxy-Status中的说明
+    pub(crate) fn message(&self) -> &'static str {
+        match self {
+            ProxyError::DnsError => "upstream dns error",
+            ProxyError::ConnectionRefused => "upstream connection refused",
+            ProxyError::ConnectionTimeout => "upstream connect timeout",
+            ProxyError::ConnectionTerminated => "upstream connection terminated",
+            ProxyError::TlsCertificateError => "upstream tls certificate error",
+            ProxyError::TlsAlertReceived => "upstream tls alert received",
+            ProxyError::TlsProtocolError => "upstream tls protocol error",
+            ProxyError::HttpResponseTimeout => "upstream response timeout",
+            ProxyError::DestinationUnavailable => "no healthy upstream",
+            ProxyError::HttpProtocolError => "upstream protocol error",
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::ConnectionTimeout | ProxyError::HttpResponseTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::DestinationUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Proxy-Status响应头，例如 `rust_http_proxy; error=connection_refused; details="..."`
+    pub(crate) fn proxy_status(&self, details: &str) -> HeaderValue {
+        let details = details
+            .replace(['"', '\\'], "")
+            .replace(|c: char| !c.is_ascii() || c.is_ascii_control(), "");
+        HeaderValue::from_str(&format!("{}; error={}; details=\"{}\"", PROXY_NAME, self.as_str(), details))
+            .unwrap_or_else(|_| HeaderValue::from_static(PROXY_NAME))
+    }
+}
+
+/// 代理自己生成的响应的Via头
+pub(crate) fn via(version: http::Version) -> HeaderValue {
+    let protocol = match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    HeaderValue::from_str(&format!("{} {}", protocol, PROXY_NAME))
+        .unwrap_or_else(|_| HeaderValue::from_static(PROXY_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let refused = io::Error::other(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert_eq!(ProxyError::classify(&refused), ProxyError::ConnectionRefused);
+        assert_eq!(ProxyError::classify(&refused).status(), StatusCode::BAD_GATEWAY);
+        let tls = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Expired),
+        );
+        assert_eq!(ProxyError::classify(&io::Error::other(tls)), ProxyError::TlsCertificateError);
+        let connect = UpstreamTimeout::into_io_error(TimeoutKind::Connect);
+        assert_eq!(ProxyError::classify(&connect), ProxyError::ConnectionTimeout);
+        assert_eq!(ProxyError::classify(&connect).status(), StatusCode::GATEWAY_TIMEOUT);
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(ProxyError::classify(&reset), ProxyError::ConnectionTerminated);
+        assert_eq!(ProxyError::classify(&io::Error::other("h2 protocol")), ProxyError::HttpProtocolError);
+        assert_eq!(ProxyError::DestinationUnavailable.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(
+            ProxyError::ConnectionRefused.proxy_status("tcp \"connect\" error"),
+            "rust_http_proxy; error=connection_refused; details=\"tcp connect error\""
+        );
+        assert_eq!(via(http::Version::HTTP_11), "1.1 rust_http_proxy");
+        assert_eq!(via(http::Version::HTTP_2), "2 rust_http_proxy");
+    }
+}
@@ -0,0 +1,252 @@
+//! 静态文件的Range请求（RFC 7233）：多range、If-Range、合并重叠的range，多个range时返回multipart/byteranges
+
+use std::{future::ready, io, path::PathBuf, time::SystemTime};
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use http::{header, HeaderMap};
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use httpdate::{fmt_http_date, parse_http_date};
+use hyper::body::{Bytes, Frame};
+use rand::Rng;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+/// 单个请求最多允许的range数量，超过时忽略Range请求头，返回完整内容
+pub(crate) const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeOutcome {
+    /// 没有Range、Range语法错误、range过多或If-Range不匹配时，返回完整内容
+    Full,
+    /// 可以满足的range，已排序并合并，闭区间
+    Partial(Vec<(u64, u64)>),
+    /// 所有range都超出文件范围，返回416
+    Unsatisfiable,
+}
+
+pub(crate) fn evaluate(headers: &HeaderMap, etag: &str, last_modified: SystemTime, file_len: u64) -> RangeOutcome {
+    let range = match headers.get(header::RANGE).and_then(|value| value.to_str().ok()) {
+        Some(range) => range,
+        None => return RangeOutcome::Full,
+    };
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        let matched = if_range
+            .to_str()
+            .is_ok_and(|if_range| if_range_matches(if_range, etag, last_modified));
+        if !matched {
+            return RangeOutcome::Full;
+        }
+    }
+    match parse_ranges(range, file_len) {
+        None => RangeOutcome::Full,
+        Some(ranges) if ranges.is_empty() => RangeOutcome::Unsatisfiable,
+        Some(ranges) => RangeOutcome::Partial(coalesce(ranges)),
+    }
+}
+
+/// If-Range为ETag时使用强比较，为日期时与Last-Modified精确相等
+fn if_range_matches(if_range: &str, etag: &str, last_modified: SystemTime) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with("W/") {
+        false
+    } else if if_range.starts_with('"') {
+        !etag.starts_with("W/") && if_range == etag
+    } else {
+        parse_http_date(if_range).is_ok_and(|date| fmt_http_date(date) == fmt_http_date(last_modified))
+    }
+}
+
+/// 语法错误或range数量超过MAX_RANGES时返回None；返回的Vec只包含可以满足的range
+fn parse_ranges(range: &str, file_len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = range.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let specs = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect::<Vec<_>>();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        if first.is_empty() {
+            // suffix-length，例如bytes=-100，超过文件长度时返回整个文件
+            let suffix = parse_digits(last)?;
+            if suffix == 0 || file_len == 0 {
+                continue;
+            }
+            ranges.push((file_len.saturating_sub(suffix), file_len - 1));
+        } else {
+            // start-end或start-，end超过文件长度时截断
+            let start = parse_digits(first)?;
+            let end = if last.is_empty() { u64::MAX } else { parse_digits(last)? };
+            if end < start {
+                return None;
+            }
+            if start >= file_len {
+                continue;
+            }
+            ranges.push((start, end.min(file_len - 1)));
+        }
+    }
+    Some(ranges)
+}
+
+fn parse_digits(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// 排序并合并重叠或相邻的range
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// multipart/byteranges响应
+pub(crate) struct Multipart {
+    pub(crate) content_type: String,
+    pub(crate) content_length: u64,
+    pub(crate) body: BoxBody<Bytes, io::Error>,
+}
+
+/// 依次读取文件的各个range，边读边发送，不在内存中缓存
+pub(crate) fn multipart(path: PathBuf, ranges: &[(u64, u64)], content_type: &str, file_len: u64) -> Multipart {
+    let boundary = format!("{:016x}{:016x}", rand::rng().random::<u64>(), rand::rng().random::<u64>());
+    let closing = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+    let mut content_length = closing.len() as u64;
+    let parts = ranges
+        .iter()
+        .map(|&(start, end)| {
+            let part_header = Bytes::from(format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, content_type, start, end, file_len
+            ));
+            content_length += part_header.len() as u64 + (end - start + 1);
+            (part_header, start, end)
+        })
+        .collect::<Vec<_>>();
+    let stream = stream::iter(parts)
+        .flat_map(move |(part_header, start, end)| {
+            let path = path.clone();
+            let part_body = stream::once(async move {
+                let mut file = File::open(path).await?;
+                file.seek(io::SeekFrom::Start(start)).await?;
+                Ok::<_, io::Error>(ReaderStream::new(file.take(end - start + 1)))
+            })
+            .try_flatten();
+            stream::once(ready(Ok(part_header))).chain(part_body)
+        })
+        .chain(stream::once(ready(Ok(closing))));
+    Multipart {
+        content_type: format!("multipart/byteranges; boundary={}", boundary),
+        content_length,
+        body: BodyExt::boxed(StreamBody::new(stream.map_ok(Frame::data))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use std::time::Duration;
+
+    fn headers(range: &'static str, if_range: Option<&'static str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static(range));
+        if let Some(if_range) = if_range {
+            headers.insert(header::IF_RANGE, HeaderValue::from_static(if_range));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_ranges("bytes=0-99", 1000), Some(vec![(0, 99)]));
+        assert_eq!(parse_ranges("bytes=900-", 1000), Some(vec![(900, 999)]));
+        assert_eq!(parse_ranges("bytes=-100", 1000), Some(vec![(900, 999)]));
+        // 超出文件长度的end和suffix被截断
+        assert_eq!(parse_ranges("bytes=900-2000", 1000), Some(vec![(900, 999)]));
+        assert_eq!(parse_ranges("bytes=-2000", 1000), Some(vec![(0, 999)]));
+        assert_eq!(parse_ranges("bytes=0-100, 200-", 1000), Some(vec![(0, 100), (200, 999)]));
+        // 不可满足的range被忽略
+        assert_eq!(parse_ranges("bytes=1000-,0-0", 1000), Some(vec![(0, 0)]));
+        assert_eq!(parse_ranges("bytes=1000-", 1000), Some(vec![]));
+        // 语法错误
+        assert_eq!(parse_ranges("bytes=100-50", 1000), None);
+        assert_eq!(parse_ranges("bytes=a-b", 1000), None);
+        assert_eq!(parse_ranges("bytes=+1-2", 1000), None);
+        assert_eq!(parse_ranges("items=0-1", 1000), None);
+        let too_many = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+            .collect::<Vec<_>>();
+        assert_eq!(parse_ranges(&format!("bytes={}", too_many.join(",")), 1000), None);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        assert_eq!(coalesce(vec![(200, 300), (0, 100), (50, 150)]), vec![(0, 150), (200, 300)]);
+        assert_eq!(coalesce(vec![(0, 99), (100, 199)]), vec![(0, 199)]);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let last_modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let etag = "\"abc-10\"";
+        assert_eq!(
+            evaluate(&headers("bytes=0-1", Some("\"abc-10\"")), etag, last_modified, 10),
+            RangeOutcome::Partial(vec![(0, 1)])
+        );
+        assert_eq!(evaluate(&headers("bytes=0-1", Some("\"other\"")), etag, last_modified, 10), RangeOutcome::Full);
+        assert_eq!(evaluate(&headers("bytes=0-1", Some("W/\"abc-10\"")), etag, last_modified, 10), RangeOutcome::Full);
+        assert_eq!(
+            evaluate(&headers("bytes=0-1", Some("Tue, 14 Nov 2023 22:13:20 GMT")), etag, last_modified, 10),
+            RangeOutcome::Partial(vec![(0, 1)])
+        );
+        assert_eq!(
+            evaluate(&headers("bytes=0-1", Some("Tue, 14 Nov 2023 22:13:21 GMT")), etag, last_modified, 10),
+            RangeOutcome::Full
+        );
+        assert_eq!(evaluate(&headers("bytes=10-", None), etag, last_modified, 10), RangeOutcome::Unsatisfiable);
+    }
+
+    #[tokio::test]
+    async fn test_multipart() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("range.txt");
+        tokio::fs::write(&path, b"0123456789abcdef").await?;
+        let multipart = multipart(path.clone(), &[(0, 1), (10, 15)], "text/plain", 16);
+        let boundary = multipart
+            .content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap_or_default()
+            .to_owned();
+        let body = multipart.body.collect().await?.to_bytes();
+        assert_eq!(body.len() as u64, multipart.content_length);
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/16\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 10-15/16\r\n\r\nabcdef\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8_lossy(&body), expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! 幂等请求的重试：上游连接失败、重置或者超时时，换新连接或者下一个upstream重试
+//!
+//! 请求体只有在完整缓存下来（不超过max_replay_body）时才会重放，否则只尝试一次
+//!
+//! 连接上游失败时请求还没有发出，不受attempts和请求方法的限制，总是换到下一个upstream
+
+use std::{future::Future, io, time::Duration};
+
+use http::{Method, Request};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::{Body, Bytes};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::full_body;
+use crate::upstream_timeout::{TimeoutKind, UpstreamTimeout};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd)]
+#[serde(default)]
+pub(crate) struct RetryPolicy {
+    /// 总的尝试次数，1表示不重试
+    pub(crate) attempts: u32,
+    /// 第一次重试前等待的毫秒数，之后每次翻倍
+    pub(crate) backoff_ms: u64,
+    /// 每次尝试等待响应头的毫秒数，0表示不限制
+    pub(crate) per_try_timeout_ms: u64,
+    /// 可以缓存下来重放的请求体大小上限
+    pub(crate) max_replay_body: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff_ms: 100,
+            per_try_timeout_ms: 0,
+            max_replay_body: 64 * 1024,
+        }
+    }
+}
+
+/// 重试不会改变服务端状态的方法
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE)
+}
+
+/// 标记连接上游失败的错误，请求还没有发出
+pub(crate) fn not_sent(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, e)
+}
+
+fn is_not_sent(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotConnected || UpstreamTimeout::find(e) == Some(TimeoutKind::Connect)
+}
+
+impl RetryPolicy {
+    /// 发送请求，send的第二个参数是第几次尝试（从0开始），可以用来选择upstream
+    ///
+    /// failover是除第一个upstream之外的upstream数量，连接失败（send返回not_sent或者连接超时）时最多再尝试failover次
+    pub(crate) async fn send<T, F, Fut>(
+        &self, req: Request<BoxBody<Bytes, io::Error>>, failover: u32, mut send: F,
+    ) -> io::Result<T>
+    where
+        F: FnMut(Request<BoxBody<Bytes, io::Error>>, u32) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let idempotent = is_idempotent(req.method());
+        let replayable = (self.attempts > 1 && idempotent || failover > 0)
+            && req
+                .body()
+                .size_hint()
+                .upper()
+                .is_some_and(|upper| upper <= self.max_replay_body);
+        if !replayable {
+            return self.send_once(req, &mut send, 0).await;
+        }
+        let (parts, body) = req.into_parts();
+        let body = body.collect().await?.to_bytes();
+        let mut backoff = Duration::from_millis(self.backoff_ms);
+        let mut attempt = 0;
+        loop {
+            let mut req = Request::new(full_body(body.clone()));
+            *req.method_mut() = parts.method.clone();
+            *req.uri_mut() = parts.uri.clone();
+            *req.version_mut() = parts.version;
+            *req.headers_mut() = parts.headers.clone();
+            match self.send_once(req, &mut send, attempt).await {
+                // 连接失败时立即换到下一个upstream
+                Err(e) if is_not_sent(&e) && attempt < failover => {
+                    warn!("[failover] {} {} attempt {} failed: {}", parts.method, parts.uri, attempt + 1, e);
+                    attempt += 1;
+                }
+                Err(e) if idempotent && attempt + 1 < self.attempts => {
+                    warn!("[retry] {} {} attempt {} failed: {}", parts.method, parts.uri, attempt + 1, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_once<T, F, Fut>(
+        &self, req: Request<BoxBody<Bytes, io::Error>>, send: &mut F, attempt: u32,
+    ) -> io::Result<T>
+    where
+        F: FnMut(Request<BoxBody<Bytes, io::Error>>, u32) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        if self.per_try_timeout_ms == 0 {
+            return send(req, attempt).await;
+        }
+        tokio::time::timeout(Duration::from_millis(self.per_try_timeout_ms), send(req, attempt))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "upstream per-try timeout")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::proxy::empty_body;
+
+    fn request(method: Method, body: BoxBody<Bytes, io::Error>) -> Request<BoxBody<Bytes, io::Error>> {
+        let mut req = Request::new(body);
+        *req.method_mut() = method;
+        req
+    }
+
+    /// 前fail_times次失败，记录每次收到的请求体
+    async fn send_with_failures(
+        policy: &RetryPolicy, req: Request<BoxBody<Bytes, io::Error>>, fail_times: u32,
+    ) -> (io::Result<u32>, Vec<Bytes>) {
+        let bodies = Mutex::new(vec![]);
+        let result = policy
+            .send(req, 0, |req, attempt| {
+                let bodies = &bodies;
+                async move {
+                    let body = req.into_body().collect().await?.to_bytes();
+                    bodies.lock().map_err(|_| io::Error::other("poisoned"))?.push(body);
+                    if attempt < fail_times {
+                        Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+        (result, bodies.into_inner().unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff_ms: 1,
+            ..Default::default()
+        };
+        let (result, bodies) = send_with_failures(&policy, request(Method::PUT, full_body("abc")), 2).await;
+        assert_eq!(result.ok(), Some(2));
+        assert_eq!(bodies, vec![Bytes::from("abc"); 3]);
+
+        let (result, bodies) = send_with_failures(&policy, request(Method::GET, empty_body()), 5).await;
+        assert!(result.is_err());
+        assert_eq!(bodies.len(), 3);
+
+        // 非幂等的方法只尝试一次
+        let (result, bodies) = send_with_failures(&policy, request(Method::POST, full_body("abc")), 1).await;
+        assert!(result.is_err());
+        assert_eq!(bodies.len(), 1);
+
+        // 请求体太大时不缓存，只尝试一次
+        let small = RetryPolicy {
+            max_replay_body: 2,
+            ..policy.clone()
+        };
+        let (result, bodies) = send_with_failures(&small, request(Method::PUT, full_body("abc")), 1).await;
+        assert!(result.is_err());
+        assert_eq!(bodies.len(), 1);
+
+        // 默认不重试，需要显式配置attempts
+        let (result, bodies) = send_with_failures(&RetryPolicy::default(), request(Method::GET, empty_body()), 1).await;
+        assert!(result.is_err());
+        assert_eq!(bodies.len(), 1);
+
+        // 单次超时也会重试
+        let timeout = RetryPolicy {
+            per_try_timeout_ms: 10,
+            ..policy
+        };
+        let result = timeout
+            .send(request(Method::GET, empty_body()), 0, |_, attempt| async move {
+                if attempt == 0 {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+                Ok(attempt)
+            })
+            .await;
+        assert_eq!(result.ok(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_failover_on_connect_failure() {
+        // 默认的attempts为1，非幂等的请求在连接失败时也会换到下一个upstream
+        let policy = RetryPolicy::default();
+        let send = |error: fn() -> io::Error| {
+            policy.send(request(Method::POST, full_body("abc")), 1, move |req, attempt| async move {
+                let body = req.into_body().collect().await?.to_bytes();
+                match attempt {
+                    0 => Err(error()),
+                    _ => Ok(body),
+                }
+            })
+        };
+        assert_eq!(send(|| not_sent("connection refused")).await.ok(), Some(Bytes::from("abc")));
+        assert_eq!(send(|| UpstreamTimeout::into_io_error(TimeoutKind::Connect)).await.ok(), Some(Bytes::from("abc")));
+        // 请求可能已经发出
+        assert!(send(|| io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            .await
+            .is_err());
+    }
+}
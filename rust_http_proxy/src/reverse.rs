@@ -1,10 +1,62 @@
+use std::{collections::HashMap, time::SystemTime};
+
 use serde::{Deserialize, Serialize};
 
+use crate::autoindex::AutoIndexConfig;
+use crate::cache_rule::{CacheHeaders, CacheRuleConfig, CacheRules};
+use crate::health::HealthConfig;
+use crate::proxy_protocol::ProxyProtocolVersion;
+use crate::retry::RetryPolicy;
+use crate::upstream_timeout::UpstreamTimeouts;
+
+/// 配置文件中的一个location，有upstream的是反向代理，有root的是静态文件，有error_pages的是该Host的错误页面
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum LocationEntry {
+    Proxy(LocationConfig),
+    ErrorPages(ErrorPagesConfig),
+    Static(StaticLocationConfig),
+}
+
+/// Host的错误页面，例如 `{error_pages: {404: /404.html, 502: /50x.html}, root: /srv/errors}`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ErrorPagesConfig {
+    pub(crate) error_pages: HashMap<u16, String>,
+    /// 错误页面所在的目录，默认为web_content_path
+    #[serde(default)]
+    pub(crate) root: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 pub(crate) struct LocationConfig {
     #[serde(default = "root")]
     pub(crate) location: String,
     pub(crate) upstream: Upstream,
+    /// upstream失败时依次重试的upstream
+    #[serde(default)]
+    pub(crate) failover: Vec<Upstream>,
+    /// 幂等请求的重试策略
+    #[serde(default)]
+    pub(crate) retry: RetryPolicy,
+    #[serde(default)]
+    pub(crate) timeouts: UpstreamTimeouts,
+    /// 有failover时，跳过连续失败的upstream
+    #[serde(default)]
+    pub(crate) health: HealthConfig,
+    /// 请求体大小上限，覆盖--max-request-body
+    #[serde(default)]
+    pub(crate) max_request_body: Option<u64>,
+    /// 连接上游后先发送PROXY protocol头部，上游连接不复用
+    #[serde(default)]
+    pub(crate) proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+impl LocationConfig {
+    /// upstream和failover
+    pub(crate) fn upstreams(&self) -> Vec<&Upstream> {
+        std::iter::once(&self.upstream).chain(&self.failover).collect()
+    }
 }
 
 impl std::cmp::PartialOrd for LocationConfig {
@@ -19,6 +71,124 @@ impl std::cmp::Ord for LocationConfig {
     }
 }
 
+/// 静态文件location，例如 `{location: /docs, root: /srv/docs}`，请求路径去掉location前缀后拼接到root
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StaticLocationConfig {
+    #[serde(default = "root")]
+    pub(crate) location: String,
+    pub(crate) root: String,
+    /// 目录的默认文件，依次尝试
+    #[serde(default = "default_index")]
+    pub(crate) index: Vec<String>,
+    /// 请求的文件不存在时依次尝试的文件（相对于root），例如单页应用的 /index.html
+    #[serde(default)]
+    pub(crate) try_files: Vec<String>,
+    /// 单页应用的history API回退：浏览器导航请求的路径不存在时返回该文件（相对于root），例如 /index.html
+    #[serde(default)]
+    pub(crate) spa_fallback: Option<String>,
+    /// 文件响应的Cache-Control，cache_rules都不匹配时使用
+    #[serde(default)]
+    pub(crate) cache_control: Option<String>,
+    /// 按路径glob或者扩展名设置Cache-Control和Expires，第一条匹配的生效
+    #[serde(default)]
+    pub(crate) cache_rules: Vec<CacheRuleConfig>,
+    #[serde(skip)]
+    pub(crate) compiled_cache_rules: CacheRules,
+    #[serde(default)]
+    pub(crate) autoindex: bool,
+    #[serde(default)]
+    pub(crate) autoindex_show_hidden: bool,
+    #[serde(skip)]
+    pub(crate) autoindex_config: AutoIndexConfig,
+}
+
+impl StaticLocationConfig {
+    /// 全局的web_content_path，autoindex由命令行参数按路径前缀开启
+    pub(crate) fn web_content(
+        web_content_path: String, autoindex_config: AutoIndexConfig, spa_fallback: Option<String>,
+        cache_rules: Vec<CacheRuleConfig>,
+    ) -> Result<Self, String> {
+        Ok(StaticLocationConfig {
+            location: root(),
+            root: web_content_path,
+            index: default_index(),
+            try_files: vec![],
+            spa_fallback,
+            cache_control: None,
+            compiled_cache_rules: CacheRules::new(&cache_rules)?,
+            cache_rules,
+            autoindex: false,
+            autoindex_show_hidden: false,
+            autoindex_config,
+        })
+    }
+
+    /// 校验并补全配置
+    pub(crate) fn normalize(&mut self) -> Result<(), String> {
+        if !self.location.starts_with('/') {
+            return Err("location should start with '/'".to_owned());
+        }
+        if self.root.is_empty() {
+            return Err(format!("root of static location {} is empty", self.location));
+        }
+        if let Some(try_file) = self
+            .try_files
+            .iter()
+            .chain(self.spa_fallback.iter())
+            .find(|f| !f.starts_with('/') || f.contains("/.."))
+        {
+            return Err(format!(
+                "try_files and spa_fallback should start with '/' and not contain '/..': {}",
+                try_file
+            ));
+        }
+        self.root = self.root.trim_end_matches('/').to_owned();
+        self.compiled_cache_rules = CacheRules::new(&self.cache_rules)?;
+        if self.autoindex {
+            self.autoindex_config = AutoIndexConfig::new(vec!["/".to_owned()], self.autoindex_show_hidden);
+        }
+        Ok(())
+    }
+
+    /// 文件响应的缓存头，url_path为location下的请求路径，file_name为实际返回的文件名
+    pub(crate) fn cache_headers(&self, url_path: &str, file_name: &str, now: SystemTime) -> Option<CacheHeaders<'_>> {
+        self.compiled_cache_rules.find(url_path, file_name, now).or_else(|| {
+            self.cache_control.as_deref().map(|cache_control| CacheHeaders {
+                cache_control,
+                expires: None,
+            })
+        })
+    }
+
+    /// 请求路径在root下的相对路径，以/开头或者为空。只在路径段的边界匹配location，
+    /// 例如location为/docs时不匹配/docs-private，否则返回None
+    pub(crate) fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(self.location.trim_end_matches('/'))
+            .filter(|relative| relative.is_empty() || relative.starts_with('/'))
+    }
+}
+
+impl std::cmp::PartialEq for StaticLocationConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.location == other.location
+    }
+}
+
+impl std::cmp::Eq for StaticLocationConfig {}
+
+impl std::cmp::PartialOrd for StaticLocationConfig {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::cmp::Ord for StaticLocationConfig {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.location.cmp(&other.location).reverse() // 越长越优先
+    }
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq, PartialOrd)]
 pub(crate) struct Upstream {
     pub(crate) url_base: String, // https://google.com
@@ -35,6 +205,10 @@ fn root() -> String {
     "/".to_owned()
 }
 
+fn default_index() -> Vec<String> {
+    vec!["index.html".to_owned()]
+}
+
 #[derive(PartialEq, PartialOrd, Copy, Clone, Eq, Ord, Hash, Serialize, Deserialize)]
 pub(crate) enum Version {
     #[serde(rename = "H1")]
@@ -44,3 +218,89 @@ pub(crate) enum Version {
     #[serde(rename = "AUTO")]
     Auto,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_location_entries() -> Result<(), serde_yaml::Error> {
+        let yaml = r#"
+example.com:
+  - location: /api
+    upstream:
+      url_base: http://127.0.0.1:8080
+    failover:
+      - url_base: http://127.0.0.1:8081
+    retry:
+      attempts: 3
+    timeouts:
+      connect_ms: 1000
+    health:
+      max_fails: 3
+    max_request_body: 1048576
+    proxy_protocol: v2
+  - upstream:
+      url_base: http://127.0.0.1:8080
+  - location: /docs
+    root: /srv/docs
+    autoindex: true
+  - error_pages:
+      404: /404.html
+      502: /50x.html
+    root: /srv/errors
+"#;
+        let entries: HashMap<String, Vec<LocationEntry>> = serde_yaml::from_str(yaml)?;
+        let entries = entries.get("example.com").map(Vec::as_slice).unwrap_or_default();
+        match &entries[0] {
+            LocationEntry::Proxy(location) => {
+                assert_eq!(location.upstreams().len(), 2);
+                assert_eq!(location.retry.attempts, 3);
+                assert_eq!(location.retry.backoff_ms, RetryPolicy::default().backoff_ms);
+                assert_eq!(location.health.max_fails, 3);
+                assert_eq!(location.timeouts.connect(), Some(std::time::Duration::from_secs(1)));
+                assert_eq!(location.max_request_body, Some(1048576));
+                assert_eq!(location.proxy_protocol, Some(ProxyProtocolVersion::V2));
+            }
+            _ => panic!("should be proxy location"),
+        }
+        // 没有配置的选项使用默认值
+        match &entries[1] {
+            LocationEntry::Proxy(location) => {
+                assert_eq!(location.location, "/");
+                assert!(location.failover.is_empty());
+                assert_eq!(location.retry, RetryPolicy::default());
+                assert_eq!(location.timeouts, UpstreamTimeouts::default());
+                assert_eq!(location.health, HealthConfig::default());
+                assert_eq!(location.max_request_body, None);
+                assert_eq!(location.proxy_protocol, None);
+            }
+            _ => panic!("should be proxy location"),
+        }
+        match &entries[2] {
+            LocationEntry::Static(location) => {
+                assert_eq!(location.root, "/srv/docs");
+                assert_eq!(location.index, vec!["index.html".to_owned()]);
+                assert!(location.autoindex);
+            }
+            _ => panic!("should be static location"),
+        }
+        match &entries[3] {
+            LocationEntry::ErrorPages(config) => {
+                assert_eq!(config.error_pages.get(&502).map(String::as_str), Some("/50x.html"));
+                assert_eq!(config.root.as_deref(), Some("/srv/errors"));
+            }
+            _ => panic!("should be error pages"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_unknown_fields() {
+        // 拼错的字段不能被忽略，否则会被当成另一种location
+        let parse = |yaml: &str| serde_yaml::from_str::<LocationEntry>(yaml).is_err();
+        assert!(parse("location: /docs\nroot: /srv/docs\nautoindx: true\n"));
+        assert!(parse("location: /errors\nerror_pages: {404: /404.html}\n"));
+        assert!(!parse("location: /docs\nroot: /srv/docs\nautoindex: true\n"));
+    }
+}
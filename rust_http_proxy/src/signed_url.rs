@@ -0,0 +1,243 @@
+//! 带过期时间的签名下载链接：受保护前缀下的文件需要带有`expires`、`sig`参数（可选绑定客户端IP的`ip`参数）
+//!
+//! 签名为HMAC-SHA256(secret, "{path}\n{expires}\n{ip}")的base64url编码，path为percent decode之后的请求路径
+
+use std::{
+    fmt::{self, Display, Formatter},
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 路径中需要编码的字符，保留/
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// 校验失败的原因
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SignError {
+    Missing,
+    Expired,
+    IpMismatch,
+    BadSignature,
+}
+
+impl Display for SignError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SignError::Missing => "missing expires or sig",
+            SignError::Expired => "link expired",
+            SignError::IpMismatch => "client ip mismatch",
+            SignError::BadSignature => "bad signature",
+        })
+    }
+}
+
+/// prefixes为空时不启用
+#[derive(Default)]
+pub(crate) struct SignedUrls {
+    secret: Vec<u8>,
+    prefixes: Vec<String>,
+}
+
+impl SignedUrls {
+    pub(crate) fn new(secret: Option<String>, prefixes: Vec<String>) -> Result<Self, String> {
+        let secret = match secret {
+            Some(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ if prefixes.is_empty() => vec![],
+            _ => return Err("--signed-url-secret is required when --signed-url-prefix is set".to_owned()),
+        };
+        if let Some(prefix) = prefixes.iter().find(|prefix| !prefix.starts_with('/')) {
+            return Err(format!("signed url prefix should start with '/': {}", prefix));
+        }
+        Ok(SignedUrls { secret, prefixes })
+    }
+
+    pub(crate) fn prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
+
+    pub(crate) fn is_protected(&self, path: &str) -> bool {
+        let path = normalize(path);
+        self.prefixes.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    /// 生成`expires=..&sig=..`形式的query，绑定ip时带有`ip=..`
+    pub(crate) fn sign(&self, path: &str, expires: SystemTime, ip: Option<IpAddr>) -> String {
+        let expires = unix_secs(expires);
+        let ip = ip.map(|ip| ip.to_canonical().to_string());
+        let sig = self.signature(&normalize(path), expires, ip.as_deref().unwrap_or_default());
+        match ip {
+            Some(ip) => format!("expires={}&ip={}&sig={}", expires, ip, sig),
+            None => format!("expires={}&sig={}", expires, sig),
+        }
+    }
+
+    /// 带签名参数的相对URL，path会被percent encode
+    pub(crate) fn signed_url(&self, path: &str, expires: SystemTime, ip: Option<IpAddr>) -> String {
+        format!("{}?{}", utf8_percent_encode(path, PATH), self.sign(path, expires, ip))
+    }
+
+    pub(crate) fn verify(
+        &self, path: &str, query: Option<&str>, client_ip: IpAddr, now: SystemTime,
+    ) -> Result<(), SignError> {
+        let (mut expires, mut sig, mut ip) = (None, None, None);
+        for (name, value) in query
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+        {
+            let value = percent_decode_str(value).decode_utf8_lossy().into_owned();
+            match name {
+                "expires" => expires = Some(value),
+                "sig" => sig = Some(value),
+                "ip" => ip = Some(value),
+                _ => {}
+            }
+        }
+        let (Some(expires), Some(sig)) = (expires, sig) else {
+            return Err(SignError::Missing);
+        };
+        let expires_secs = expires.parse::<u64>().map_err(|_| SignError::BadSignature)?;
+        let sig = URL_SAFE_NO_PAD
+            .decode(sig.as_bytes())
+            .map_err(|_| SignError::BadSignature)?;
+        let mut mac = self.mac();
+        mac.update(message(&normalize(path), expires_secs, ip.as_deref().unwrap_or_default()).as_bytes());
+        // 先校验签名，避免通过过期和IP的错误区分出签名是否正确
+        mac.verify_slice(&sig).map_err(|_| SignError::BadSignature)?;
+        if unix_secs(now) > expires_secs {
+            return Err(SignError::Expired);
+        }
+        match ip {
+            Some(ip) if ip != client_ip.to_canonical().to_string() => Err(SignError::IpMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        // HMAC接受任意长度的key
+        match HmacSha256::new_from_slice(&self.secret) {
+            Ok(mac) => mac,
+            Err(_) => unreachable!("hmac accepts keys of any length"),
+        }
+    }
+
+    fn signature(&self, path: &str, expires: u64, ip: &str) -> String {
+        let mut mac = self.mac();
+        mac.update(message(path, expires, ip).as_bytes());
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+}
+
+/// 去掉重复的/和.，否则//downloads/a.iso可以绕过前缀/downloads/的检查
+fn normalize(path: &str) -> String {
+    let mut normalized = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .fold(String::with_capacity(path.len()), |mut normalized, segment| {
+            normalized.push('/');
+            normalized.push_str(segment);
+            normalized
+        });
+    if normalized.is_empty() || path.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+fn message(path: &str, expires: u64, ip: &str) -> String {
+    format!("{}\n{}\n{}", path, expires, ip)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// 解析有效期，例如 3600、90s、30m、12h、7d
+pub(crate) fn parse_ttl(ttl: &str) -> Result<Duration, String> {
+    let ttl = ttl.trim();
+    let (number, unit) = match ttl.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => ttl.split_at(index),
+        None => (ttl, "s"),
+    };
+    let wrong_ttl = || format!("wrong ttl {}, should be like 3600, 30m, 12h or 7d", ttl);
+    let number = number.parse::<u64>().map_err(|_| wrong_ttl())?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("wrong ttl unit of {}, should be one of s, m, h, d", ttl)),
+    };
+    number.checked_mul(unit).map(Duration::from_secs).ok_or_else(wrong_ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() -> Result<(), String> {
+        let signed = SignedUrls::new(Some("secret".to_owned()), vec!["/downloads/".to_owned()])?;
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let expires = now + parse_ttl("1h")?;
+        let client: IpAddr = [1, 2, 3, 4].into();
+        let other: IpAddr = [5, 6, 7, 8].into();
+        assert!(signed.is_protected("/downloads/a b.iso"));
+        assert!(!signed.is_protected("/index.html"));
+        assert!(signed.is_protected("//downloads/a.iso"));
+        assert!(signed.is_protected("/./downloads//a.iso"));
+
+        let url = signed.signed_url("/downloads/a b.iso", expires, None);
+        assert!(url.starts_with("/downloads/a%20b.iso?expires=1700003600&sig="));
+        let query = signed.sign("/downloads/a b.iso", expires, None);
+        assert_eq!(signed.verify("/downloads/a b.iso", Some(&query), client, now), Ok(()));
+        assert_eq!(signed.verify("/downloads/c.iso", Some(&query), client, now), Err(SignError::BadSignature));
+        assert_eq!(
+            signed.verify("/downloads/a b.iso", Some(&query), client, expires + Duration::from_secs(1)),
+            Err(SignError::Expired)
+        );
+        let tampered = query.replace("expires=1700003600", "expires=1800000000");
+        assert_eq!(signed.verify("/downloads/a b.iso", Some(&tampered), client, now), Err(SignError::BadSignature));
+        assert_eq!(signed.verify("/downloads/a b.iso", None, client, now), Err(SignError::Missing));
+
+        let query = signed.sign("/downloads/a b.iso", expires, Some(client));
+        assert_eq!(signed.verify("/downloads/a b.iso", Some(&query), client, now), Ok(()));
+        assert_eq!(signed.verify("/downloads/a b.iso", Some(&query), other, now), Err(SignError::IpMismatch));
+        // 去掉ip参数后签名不匹配
+        let unbound = query.replace("&ip=1.2.3.4", "");
+        assert_eq!(signed.verify("/downloads/a b.iso", Some(&unbound), other, now), Err(SignError::BadSignature));
+
+        assert!(SignedUrls::new(None, vec!["/downloads/".to_owned()]).is_err());
+        assert!(SignedUrls::new(Some("secret".to_owned()), vec!["downloads".to_owned()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("3600"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_ttl("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_ttl("7d"), Ok(Duration::from_secs(7 * 24 * 3600)));
+        assert!(parse_ttl("1w").is_err());
+        assert!(parse_ttl("h").is_err());
+        assert!(parse_ttl(&format!("{}d", u64::MAX / 2)).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! 隧道（CONNECT）和正向代理连接关闭时的统计
+
+use std::{error::Error, io, time::Duration};
+
+use io_x::IoStats;
+use prom_label::LabelImpl;
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{
+        family::Family,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+/// 连接关闭的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CloseReason {
+    /// 正常关闭
+    Eof,
+    /// 空闲超时（TimeoutIO）
+    Idle,
+    /// 被对端reset
+    Reset,
+    /// 其他错误
+    Error,
+}
+
+impl CloseReason {
+    pub(crate) fn from_io_result<T>(result: &io::Result<T>) -> Self {
+        match result {
+            Ok(_) => CloseReason::Eof,
+            Err(e) => Self::from_io_error(e),
+        }
+    }
+
+    pub(crate) fn from_io_error(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => CloseReason::Idle,
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => {
+                CloseReason::Reset
+            }
+            _ => CloseReason::Error,
+        }
+    }
+
+    /// hyper的连接错误，取出其中的io::Error来判断
+    pub(crate) fn from_hyper_result(result: &Result<(), hyper::Error>) -> Self {
+        match result {
+            Ok(_) => CloseReason::Eof,
+            Err(err) => match err.source().and_then(|source| source.downcast_ref::<io::Error>()) {
+                Some(io_err) => Self::from_io_error(io_err),
+                None => CloseReason::Error,
+            },
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Eof => "eof",
+            CloseReason::Idle => "idle",
+            CloseReason::Reset => "reset",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
+/// 隧道的类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TunnelKind {
+    /// CONNECT隧道
+    Connect,
+    /// 正向代理普通请求使用的上游连接
+    Forward,
+    /// 反向代理协议升级（WebSocket、h2c）后的连接
+    Upgrade,
+}
+
+impl TunnelKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            TunnelKind::Connect => "connect",
+            TunnelKind::Forward => "forward",
+            TunnelKind::Upgrade => "upgrade",
+        }
+    }
+}
+
+/// 隧道关闭时的统计
+#[derive(Clone, Debug)]
+pub(crate) struct TunnelStats {
+    pub(crate) from_client: u64,
+    pub(crate) from_server: u64,
+    pub(crate) duration: Duration,
+    /// 从隧道建立到收到server第一个字节的耗时
+    pub(crate) ttfb: Option<Duration>,
+    pub(crate) close_reason: CloseReason,
+}
+
+impl TunnelStats {
+    /// 根据上游连接的读写统计生成，读即是from_server，写即是from_client
+    pub(crate) fn new(upstream_stats: &IoStats, close_reason: CloseReason) -> Self {
+        TunnelStats {
+            from_client: upstream_stats.written_bytes(),
+            from_server: upstream_stats.read_bytes(),
+            duration: upstream_stats.elapsed(),
+            ttfb: upstream_stats.time_to_first_read(),
+            close_reason,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct TunnelMetrics {
+    duration: Family<LabelImpl<TunnelLabel>, Histogram>,
+    bytes: Family<LabelImpl<TunnelBytesLabel>, Histogram>,
+}
+
+impl TunnelMetrics {
+    pub(crate) fn register(registry: &mut Registry) -> Self {
+        let duration = Family::<LabelImpl<TunnelLabel>, Histogram>::new_with_constructor(|| {
+            Histogram::new(exponential_buckets(0.01, 4.0, 10))
+        });
+        registry.register("tunnel_duration_seconds", "Duration of proxy tunnels", duration.clone());
+        let bytes = Family::<LabelImpl<TunnelBytesLabel>, Histogram>::new_with_constructor(|| {
+            Histogram::new(exponential_buckets(256.0, 4.0, 12))
+        });
+        registry.register("tunnel_bytes", "Bytes transferred by proxy tunnels", bytes.clone());
+        TunnelMetrics { duration, bytes }
+    }
+
+    pub(crate) fn observe(&self, kind: TunnelKind, stats: &TunnelStats) {
+        self.duration
+            .get_or_create(&LabelImpl::new(TunnelLabel {
+                kind: kind.as_str(),
+                close_reason: stats.close_reason.as_str(),
+            }))
+            .observe(stats.duration.as_secs_f64());
+        for (direction, bytes) in [("from_client", stats.from_client), ("from_server", stats.from_server)] {
+            self.bytes
+                .get_or_create(&LabelImpl::new(TunnelBytesLabel {
+                    kind: kind.as_str(),
+                    direction,
+                }))
+                .observe(bytes as f64);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct TunnelLabel {
+    pub kind: &'static str,
+    pub close_reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct TunnelBytesLabel {
+    pub kind: &'static str,
+    pub direction: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason() {
+        let idle = io::Error::new(io::ErrorKind::TimedOut, "read idle for 600s");
+        assert_eq!(CloseReason::from_io_error(&idle), CloseReason::Idle);
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert_eq!(CloseReason::from_io_error(&reset), CloseReason::Reset);
+        let other = io::Error::other("other");
+        assert_eq!(CloseReason::from_io_result::<()>(&Err(other)), CloseReason::Error);
+        assert_eq!(CloseReason::from_io_result(&Ok(())), CloseReason::Eof);
+    }
+}
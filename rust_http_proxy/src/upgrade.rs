@@ -0,0 +1,166 @@
+//! 反向代理的协议升级（WebSocket、h2c）：上游返回101后，在客户端和上游升级后的连接之间双向转发
+//!
+//! 只支持HTTP/1.1的Upgrade机制，上游请求固定使用HTTP/1.1
+
+use std::{io, time::Duration};
+
+use http::{header, Request, Version};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use io_x::{StatsIO, TimeoutIO};
+
+use crate::tunnel::{CloseReason, TunnelStats};
+
+/// 请求是否要求升级协议：Connection中包含upgrade，并且有Upgrade头
+pub(crate) fn is_upgrade<B>(req: &Request<B>) -> bool {
+    req.version() == Version::HTTP_11
+        && req.headers().contains_key(header::UPGRADE)
+        && req
+            .headers()
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// 等待两端完成升级，然后双向转发直到任意一端关闭或者空闲超时
+///
+/// 升级失败时返回Err，此时还没有传输数据
+pub(crate) async fn bridge(
+    client: OnUpgrade, upstream: OnUpgrade, idle_timeout: Duration,
+) -> Result<(TunnelStats, io::Result<()>), hyper::Error> {
+    let (client, upstream) = tokio::try_join!(client, upstream)?;
+    let mut client = TokioIo::new(client);
+    let mut upstream = Box::pin(StatsIO::new(TimeoutIO::new(TokioIo::new(upstream), idle_timeout)));
+    let io_stats = upstream.stats();
+    let result = tokio::io::copy_bidirectional(&mut client, &mut upstream)
+        .await
+        .map(|_| ());
+    Ok((TunnelStats::new(&io_stats, CloseReason::from_io_result(&result)), result))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http::{Response, StatusCode};
+    use http_body_util::Empty;
+    use hyper::{body::Bytes, server::conn::http1, service::service_fn};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    fn switching_protocols() -> Response<Empty<Bytes>> {
+        let mut resp = Response::new(Empty::new());
+        *resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        resp.headers_mut()
+            .insert(header::CONNECTION, header::HeaderValue::from_static("upgrade"));
+        resp.headers_mut()
+            .insert(header::UPGRADE, header::HeaderValue::from_static("echo"));
+        resp
+    }
+
+    /// 升级后原样返回收到的数据
+    async fn echo_upstream(listener: TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept().await?;
+        let service = service_fn(|mut req: Request<hyper::body::Incoming>| async move {
+            let on_upgrade = hyper::upgrade::on(&mut req);
+            tokio::spawn(async move {
+                if let Ok(upgraded) = on_upgrade.await {
+                    let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                }
+            });
+            Ok::<_, Infallible>(switching_protocols())
+        });
+        http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .with_upgrades()
+            .await
+            .map_err(io::Error::other)
+    }
+
+    /// 把升级请求转发给upstream，用bridge连接两端
+    async fn proxy(listener: TcpListener, upstream: String) -> io::Result<()> {
+        let (stream, _) = listener.accept().await?;
+        let service = service_fn(move |mut req: Request<hyper::body::Incoming>| {
+            let upstream = upstream.clone();
+            async move {
+                let client_upgrade = hyper::upgrade::on(&mut req);
+                let (mut sender, conn) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(TcpStream::connect(upstream).await?))
+                        .await
+                        .map_err(io::Error::other)?;
+                tokio::spawn(conn.with_upgrades());
+                let (parts, _) = req.into_parts();
+                let mut resp = sender
+                    .send_request(Request::from_parts(parts, Empty::<Bytes>::new()))
+                    .await
+                    .map_err(io::Error::other)?;
+                let upstream_upgrade = hyper::upgrade::on(&mut resp);
+                tokio::spawn(bridge(client_upgrade, upstream_upgrade, Duration::from_secs(10)));
+                Ok::<_, io::Error>(switching_protocols())
+            }
+        });
+        http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .with_upgrades()
+            .await
+            .map_err(io::Error::other)
+    }
+
+    #[tokio::test]
+    async fn test_bridge() -> io::Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?.to_string();
+        tokio::spawn(echo_upstream(upstream));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        tokio::spawn(proxy(listener, upstream_addr));
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n")
+            .await?;
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await?);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        client.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    fn request(version: Version, headers: &[(&'static str, &'static str)]) -> Request<()> {
+        let mut req = Request::new(());
+        *req.version_mut() = version;
+        for (name, value) in headers {
+            req.headers_mut()
+                .append(header::HeaderName::from_static(name), header::HeaderValue::from_static(value));
+        }
+        req
+    }
+
+    #[test]
+    fn test_is_upgrade() {
+        let websocket = [("connection", "keep-alive, Upgrade"), ("upgrade", "websocket")];
+        assert!(is_upgrade(&request(Version::HTTP_11, &websocket)));
+        assert!(!is_upgrade(&request(Version::HTTP_2, &websocket)));
+        assert!(is_upgrade(&request(
+            Version::HTTP_11,
+            &[
+                ("connection", "Upgrade, HTTP2-Settings"),
+                ("upgrade", "h2c"),
+                ("http2-settings", "AAMAAABkAAQAAP__")
+            ]
+        )));
+        assert!(!is_upgrade(&request(Version::HTTP_11, &[("upgrade", "websocket")])));
+        assert!(!is_upgrade(&request(Version::HTTP_11, &[("connection", "upgrade")])));
+    }
+}
@@ -0,0 +1,272 @@
+//! 反向代理的超时：建连、等待响应头、总时长、响应体空闲
+//!
+//! 收到响应头之前超时返回504，之后超时只能中断响应体
+
+use std::{
+    fmt::{self, Display, Formatter},
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, Sleep};
+
+/// location的超时配置，单位为毫秒，0表示不限制
+#[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd)]
+#[serde(default)]
+pub(crate) struct UpstreamTimeouts {
+    /// 与上游建立TCP连接
+    pub(crate) connect_ms: u64,
+    /// 从发出请求到收到响应头，包含所有重试
+    pub(crate) header_ms: u64,
+    /// 从发出请求到响应体传输完成
+    pub(crate) total_ms: u64,
+    /// 响应体两次读取之间的最长间隔
+    pub(crate) idle_ms: u64,
+}
+
+fn millis(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+impl UpstreamTimeouts {
+    pub(crate) fn connect(&self) -> Option<Duration> {
+        millis(self.connect_ms)
+    }
+
+    /// 等待响应头的时长，同时受total限制，返回先触发的那个
+    pub(crate) fn header_deadline(&self) -> Option<(Duration, TimeoutKind)> {
+        match (millis(self.header_ms), millis(self.total_ms)) {
+            (Some(header), Some(total)) if total < header => Some((total, TimeoutKind::Total)),
+            (Some(header), _) => Some((header, TimeoutKind::Header)),
+            (None, Some(total)) => Some((total, TimeoutKind::Total)),
+            (None, None) => None,
+        }
+    }
+
+    /// 是否需要限制响应体
+    pub(crate) fn limits_body(&self) -> bool {
+        self.total_ms > 0 || self.idle_ms > 0
+    }
+}
+
+/// 触发的超时
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TimeoutKind {
+    Connect,
+    Header,
+    Total,
+    Idle,
+}
+
+impl TimeoutKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            TimeoutKind::Connect => "connect",
+            TimeoutKind::Header => "header",
+            TimeoutKind::Total => "total",
+            TimeoutKind::Idle => "idle",
+        }
+    }
+
+    /// 错误页面中的说明
+    pub(crate) fn message(&self) -> &'static str {
+        match self {
+            TimeoutKind::Connect => "upstream connect timeout",
+            TimeoutKind::Header => "upstream response header timeout",
+            TimeoutKind::Total => "upstream total timeout",
+            TimeoutKind::Idle => "upstream idle timeout",
+        }
+    }
+}
+
+/// 放在io::Error中，用于区分是哪个超时
+#[derive(Debug)]
+pub(crate) struct UpstreamTimeout(pub(crate) TimeoutKind);
+
+impl Display for UpstreamTimeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.message())
+    }
+}
+
+impl std::error::Error for UpstreamTimeout {}
+
+impl UpstreamTimeout {
+    pub(crate) fn into_io_error(kind: TimeoutKind) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, UpstreamTimeout(kind))
+    }
+
+    pub(crate) fn find(e: &io::Error) -> Option<TimeoutKind> {
+        e.get_ref()?.downcast_ref::<UpstreamTimeout>().map(|timeout| timeout.0)
+    }
+}
+
+type OnTimeout = Box<dyn FnOnce(TimeoutKind) + Send + Sync>;
+
+pin_project! {
+    /// 限制响应体的总时长和空闲时长，超时后返回错误并调用on_timeout
+    pub(crate) struct TimeoutBody<B> {
+        #[pin]
+        inner: B,
+        total: Option<Pin<Box<Sleep>>>,
+        idle: Option<(Duration, Pin<Box<Sleep>>)>,
+        on_timeout: Option<OnTimeout>,
+    }
+}
+
+impl<B> TimeoutBody<B> {
+    /// deadline为请求开始时间加上total
+    pub(crate) fn new(
+        inner: B, timeouts: &UpstreamTimeouts, start: Instant,
+        on_timeout: impl FnOnce(TimeoutKind) + Send + Sync + 'static,
+    ) -> Self {
+        TimeoutBody {
+            inner,
+            total: millis(timeouts.total_ms).map(|total| Box::pin(tokio::time::sleep_until(start + total))),
+            idle: millis(timeouts.idle_ms).map(|idle| (idle, Box::pin(tokio::time::sleep(idle)))),
+            on_timeout: Some(Box::new(on_timeout)),
+        }
+    }
+}
+
+impl<B> Body for TimeoutBody<B>
+where
+    B: Body<Data = Bytes, Error = io::Error>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = self.project();
+        if let Poll::Ready(polled) = this.inner.poll_frame(cx) {
+            if let Some((idle, sleep)) = this.idle {
+                sleep.as_mut().reset(Instant::now() + *idle);
+            }
+            return Poll::Ready(polled);
+        }
+        let fired = if this
+            .total
+            .as_mut()
+            .is_some_and(|total| total.as_mut().poll(cx).is_ready())
+        {
+            Some(TimeoutKind::Total)
+        } else if this
+            .idle
+            .as_mut()
+            .is_some_and(|(_, idle)| idle.as_mut().poll(cx).is_ready())
+        {
+            Some(TimeoutKind::Idle)
+        } else {
+            None
+        };
+        match fired {
+            Some(kind) => {
+                if let Some(on_timeout) = this.on_timeout.take() {
+                    on_timeout(kind);
+                }
+                Poll::Ready(Some(Err(UpstreamTimeout::into_io_error(kind))))
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    /// 永远不返回数据的body
+    struct PendingBody;
+
+    impl Body for PendingBody {
+        type Data = Bytes;
+        type Error = io::Error;
+
+        fn poll_frame(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_header_deadline() {
+        let timeouts = UpstreamTimeouts {
+            header_ms: 1000,
+            total_ms: 500,
+            ..Default::default()
+        };
+        assert_eq!(timeouts.header_deadline(), Some((Duration::from_millis(500), TimeoutKind::Total)));
+        let timeouts = UpstreamTimeouts {
+            header_ms: 1000,
+            total_ms: 5000,
+            ..Default::default()
+        };
+        assert_eq!(timeouts.header_deadline(), Some((Duration::from_secs(1), TimeoutKind::Header)));
+        assert_eq!(UpstreamTimeouts::default().header_deadline(), None);
+        assert!(!UpstreamTimeouts::default().limits_body());
+        let e = UpstreamTimeout::into_io_error(TimeoutKind::Connect);
+        assert_eq!(UpstreamTimeout::find(&e), Some(TimeoutKind::Connect));
+        assert_eq!(UpstreamTimeout::find(&io::Error::from(io::ErrorKind::TimedOut)), None);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_body() {
+        for (timeouts, expected) in [
+            (
+                UpstreamTimeouts {
+                    idle_ms: 100,
+                    ..Default::default()
+                },
+                TimeoutKind::Idle,
+            ),
+            (
+                UpstreamTimeouts {
+                    idle_ms: 1000,
+                    total_ms: 200,
+                    ..Default::default()
+                },
+                TimeoutKind::Total,
+            ),
+        ] {
+            let fired = Arc::new(Mutex::new(None));
+            let fired_clone = fired.clone();
+            let body = TimeoutBody::new(PendingBody, &timeouts, Instant::now(), move |kind| {
+                if let Ok(mut fired) = fired_clone.lock() {
+                    *fired = Some(kind);
+                }
+            });
+            let result = body.collect().await;
+            assert_eq!(result.err().as_ref().and_then(UpstreamTimeout::find), Some(expected));
+            assert_eq!(fired.lock().ok().and_then(|fired| *fired), Some(expected));
+        }
+        // 有数据时不超时
+        let body = TimeoutBody::new(
+            http_body_util::Full::new(Bytes::from("ok")).map_err(|never| match never {}),
+            &UpstreamTimeouts {
+                idle_ms: 10,
+                ..Default::default()
+            },
+            Instant::now(),
+            |_| {},
+        );
+        let collected = body.collect().await.map(|body| body.to_bytes());
+        assert_eq!(collected.ok(), Some(Bytes::from("ok")));
+    }
+}
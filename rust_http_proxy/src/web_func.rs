@@ -1,11 +1,19 @@
+use crate::analytics::{Analytics, PageView};
+use crate::autoindex;
+use crate::cache_rule::{self, CacheHeaders};
+use crate::config::Config;
+use crate::encoding::{self, AcceptEncoding, Encoding, Representation};
+use crate::hotlink::HotlinkAction;
 use crate::ip_x::SocketAddrFormat;
 use crate::proxy::build_authenticate_resp;
 use crate::proxy::check_auth;
 use crate::proxy::empty_body;
 use crate::proxy::full_body;
+use crate::proxy::HotlinkLabel;
 use crate::proxy::ProxyHandler;
 use crate::proxy::ReqLabels;
-use async_compression::tokio::bufread::GzipEncoder;
+use crate::range::{self, RangeOutcome};
+use crate::reverse::StaticLocationConfig;
 use futures_util::TryStreamExt;
 use http::response::Builder;
 use http::{Error, HeaderValue};
@@ -17,20 +25,18 @@ use hyper::header::{CONTENT_ENCODING, REFERER};
 use hyper::{http, Method, Request, Response, StatusCode};
 use log::{info, warn};
 use mime_guess::from_path;
-use prom_label::LabelImpl;
+use prom_label::{BoundedFamily, LabelImpl};
 use prometheus_client::encoding::text::encode;
 use prometheus_client::metrics::counter::Counter;
-use prometheus_client::metrics::family::Family;
 use prometheus_client::registry::Registry;
-use regex::Regex;
+use std::fs::Metadata;
 use std::io;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::pin;
 use std::sync::LazyLock;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use tokio::fs::{metadata, File};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
 
 pub(crate) static GZIP: &str = "gzip";
@@ -39,26 +45,14 @@ pub(crate) const SERVER_NAME: &str = "The Bad Server";
 pub async fn serve_http_request(
     proxy_handler: &ProxyHandler, req: &Request<impl Body>, client_socket_addr: SocketAddr, path: &str,
 ) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
-    let web_content_path = &proxy_handler.config.web_content_path;
+    let web_content = &proxy_handler.config.web_content;
     let referer_keywords_to_self = &proxy_handler.config.referer_keywords_to_self;
     let referer_header = req.headers().get(REFERER).map_or("", |h| h.to_str().unwrap_or(""));
-    if (path.ends_with(".png") || path.ends_with(".jpeg") || path.ends_with(".jpg"))
-        && !referer_keywords_to_self.is_empty()
-        && !referer_header.is_empty()
-    {
-        // 如果referer_header不包含任何一个refers，则返回404
-        if !referer_keywords_to_self
-            .iter()
-            .any(|refer| referer_header.contains(refer))
-        {
-            warn!(
-                "{} wrong Referer Header \"{}\" from [{}]",
-                path,
-                referer_header,
-                SocketAddrFormat(&client_socket_addr)
-            );
-            return not_found();
-        }
+    if let Some(res) = check_signed_url(proxy_handler, req, path, client_socket_addr) {
+        return res;
+    }
+    if let Some(res) = check_hotlink(proxy_handler, path, referer_header, client_socket_addr) {
+        return res;
     }
     #[cfg(target_os = "linux")]
     let hostname = req
@@ -69,7 +63,8 @@ pub async fn serve_http_request(
         .headers()
         .get(http::header::ACCEPT_ENCODING)
         .map_or("", |h| h.to_str().unwrap_or(""));
-    let can_gzip = accept_encoding.contains(GZIP);
+    let accept_encoding = AcceptEncoding::parse(accept_encoding);
+    let can_gzip = accept_encoding.accepts(Encoding::Gzip);
     #[allow(clippy::needless_return)]
     return match (req.method(), path) {
         (_, "/ip") => serve_ip(client_socket_addr),
@@ -89,6 +84,14 @@ pub async fn serve_http_request(
             proxy_handler.snapshot_metrics();
             serve_metrics(&proxy_handler.prom_registry, can_gzip).await
         }
+        (_, "/stats") => {
+            if let (_, false) =
+                check_auth(&proxy_handler.config.basic_auth, req, &client_socket_addr, hyper::header::AUTHORIZATION)
+            {
+                return Ok(build_authenticate_resp(false));
+            }
+            serve_stats(&proxy_handler.analytics, req.uri().query())
+        }
         (&Method::GET, path) => {
             let is_outer_view_html = (path.ends_with('/') || path.ends_with(".html"))
                 && !referer_header.is_empty() // 存在Referer Header
@@ -109,59 +112,217 @@ pub async fn serve_http_request(
                     "".to_string()
                 },
             );
-            let r = serve_path(web_content_path, path, req, can_gzip, true).await;
+            let start = Instant::now();
+            let r = serve_path(web_content, path, req, &accept_encoding, true, &proxy_handler.config).await;
+            observe_serve_latency(proxy_handler, &r, start);
             let is_shell = path.ends_with(".sh");
             incr_counter_if_need(
                 &r,
                 is_outer_view_html,
                 is_shell,
                 &proxy_handler.metrics.http_req_counter,
+                &proxy_handler.analytics,
                 referer_header,
                 path,
             );
+            record_page_view(proxy_handler, req, path, &r, client_socket_addr);
+            r
+        }
+        (&Method::HEAD, path) => {
+            let start = Instant::now();
+            let r = serve_path(web_content, path, req, &accept_encoding, false, &proxy_handler.config).await;
+            observe_serve_latency(proxy_handler, &r, start);
             r
         }
-        (&Method::HEAD, path) => serve_path(web_content_path, path, req, false, false).await,
         _ => not_found(),
     };
 }
 
+/// 配置文件中按Host配置的静态文件location，path为percent decode之后的路径
+pub(crate) async fn serve_static_location(
+    proxy_handler: &ProxyHandler, site: &StaticLocationConfig, req: &Request<impl Body>,
+    client_socket_addr: SocketAddr, path: &str,
+) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    let need_body = match *req.method() {
+        Method::GET => true,
+        Method::HEAD => false,
+        _ => {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(http::header::ALLOW, "GET, HEAD")
+                .header(http::header::SERVER, SERVER_NAME)
+                .body(empty_body())
+        }
+    };
+    let accept_encoding = req
+        .headers()
+        .get(http::header::ACCEPT_ENCODING)
+        .map_or("", |h| h.to_str().unwrap_or(""));
+    let accept_encoding = AcceptEncoding::parse(accept_encoding);
+    let referer_header = req.headers().get(REFERER).map_or("", |h| h.to_str().unwrap_or(""));
+    if let Some(res) = check_signed_url(proxy_handler, req, path, client_socket_addr) {
+        return res;
+    }
+    if let Some(res) = check_hotlink(proxy_handler, path, referer_header, client_socket_addr) {
+        return res;
+    }
+    let start = Instant::now();
+    let Some(relative_path) = site.relative_path(path) else {
+        return not_found();
+    };
+    let r = serve_path(site, relative_path, req, &accept_encoding, need_body, &proxy_handler.config).await;
+    observe_serve_latency(proxy_handler, &r, start);
+    if need_body {
+        record_page_view(proxy_handler, req, path, &r, client_socket_addr);
+    }
+    r
+}
+
+/// 成功返回的html页面计入访问统计
+fn record_page_view(
+    proxy_handler: &ProxyHandler, req: &Request<impl Body>, path: &str,
+    r: &Result<Response<BoxBody<Bytes, io::Error>>, Error>, client_socket_addr: SocketAddr,
+) {
+    let Ok(res) = r else {
+        return;
+    };
+    if !res.status().is_success() && res.status() != StatusCode::NOT_MODIFIED {
+        return;
+    }
+    let is_html = res
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(path.ends_with('/') || path.ends_with(".html"), |content_type| content_type.starts_with("text/html"));
+    if !is_html {
+        return;
+    }
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .map_or("", |h: &HeaderValue| h.to_str().unwrap_or(""))
+    };
+    let host = req
+        .uri()
+        .authority()
+        .map_or_else(|| header(http::header::HOST), |authority| authority.as_str());
+    proxy_handler.analytics.record(
+        PageView {
+            path,
+            host,
+            referer: header(REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            client_ip: client_socket_addr.ip(),
+        },
+        SystemTime::now(),
+    );
+}
+
+/// 受保护前缀下的文件需要有效的签名参数，校验失败时返回403
+fn check_signed_url(
+    proxy_handler: &ProxyHandler, req: &Request<impl Body>, path: &str, client_socket_addr: SocketAddr,
+) -> Option<Result<Response<BoxBody<Bytes, io::Error>>, Error>> {
+    let signed_urls = &proxy_handler.config.signed_urls;
+    if !signed_urls.is_protected(path) {
+        return None;
+    }
+    let err = signed_urls
+        .verify(path, req.uri().query(), client_socket_addr.ip(), SystemTime::now())
+        .err()?;
+    warn!("{} signed url rejected: {} from [{}]", path, err, SocketAddrFormat(&client_socket_addr));
+    Some(
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header(http::header::SERVER, SERVER_NAME)
+            .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(empty_body()),
+    )
+}
+
+/// 防盗链检查，被拦截时返回响应并计数
+fn check_hotlink(
+    proxy_handler: &ProxyHandler, path: &str, referer_header: &str, client_socket_addr: SocketAddr,
+) -> Option<Result<Response<BoxBody<Bytes, io::Error>>, Error>> {
+    let hotlink = &proxy_handler.config.hotlink;
+    let referer_host = hotlink.check(path, referer_header)?;
+    warn!("{} hotlink blocked, Referer \"{}\" from [{}]", path, referer_header, SocketAddrFormat(&client_socket_addr));
+    proxy_handler
+        .metrics
+        .hotlink_blocked
+        .with_series(&LabelImpl::new(HotlinkLabel { referer_host }), |counter| counter.inc());
+    Some(match &hotlink.action {
+        HotlinkAction::Status(status) => Response::builder()
+            .status(*status)
+            .header(http::header::SERVER, SERVER_NAME)
+            .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(empty_body()),
+        HotlinkAction::Redirect(target) => Response::builder()
+            .status(StatusCode::FOUND)
+            .header(http::header::LOCATION, target.as_str())
+            .header(http::header::SERVER, SERVER_NAME)
+            .header(http::header::CACHE_CONTROL, "no-store")
+            .body(empty_body()),
+    })
+}
+
+fn observe_serve_latency(
+    proxy_handler: &ProxyHandler, r: &Result<Response<BoxBody<Bytes, io::Error>>, Error>, start: Instant,
+) {
+    if let Ok(res) = r {
+        proxy_handler
+            .metrics
+            .latency
+            .observe_static_serve(res.status(), start.elapsed());
+    }
+}
+
 fn incr_counter_if_need(
     r: &Result<Response<BoxBody<Bytes, io::Error>>, Error>, is_outer_view_html: bool, _is_shell: bool,
-    http_req_counter: &Family<LabelImpl<ReqLabels>, Counter>, referer_header: &str, path: &str,
+    http_req_counter: &BoundedFamily<LabelImpl<ReqLabels>, Counter>, analytics: &Analytics, referer_header: &str,
+    path: &str,
 ) {
     if let Ok(ref res) = *r {
         if is_outer_view_html && (res.status().is_success() || res.status().is_redirection()) {
-            http_req_counter
-                .get_or_create(&LabelImpl::new(ReqLabels {
-                    referer: extract_search_engine_from_referer(referer_header).unwrap_or("parse_failed".to_string()),
+            http_req_counter.with_series(
+                &LabelImpl::new(ReqLabels {
+                    referer: analytics.referer_source(referer_header),
                     path: path.to_string(),
-                }))
-                .inc();
-            http_req_counter.get_or_create(&ALL_HTTP_REQ).inc();
+                }),
+                |counter| counter.inc(),
+            );
+            http_req_counter.with_series(&ALL_HTTP_REQ, |counter| counter.inc());
         }
     }
 }
 
-static ALL_HTTP_REQ: LazyLock<prom_label::LabelImpl<ReqLabels>> = LazyLock::new(|| {
+pub(crate) static ALL_HTTP_REQ: LazyLock<prom_label::LabelImpl<ReqLabels>> = LazyLock::new(|| {
     LabelImpl::new(ReqLabels {
         referer: "all".to_string(),
         path: "all".to_string(),
     })
 });
 
-fn extract_search_engine_from_referer(referer: &str) -> Result<String, regex::Error> {
-    if let Some(caps) = Regex::new("^https?://(.+?)(/|$)")?.captures(referer) {
-        let address = caps.get(1).map_or(referer, |g| g.as_str());
-        if let Some(caps) =
-            Regex::new("(google|baidu|bing|yandex|v2ex|github|stackoverflow|duckduckgo)")?.captures(address)
-        {
-            Ok(caps.get(1).map_or(address, |g| g.as_str()).to_string())
-        } else {
-            Ok(address.to_owned())
+fn serve_stats(analytics: &Analytics, query: Option<&str>) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    let snapshot = analytics.snapshot();
+    if query.is_some_and(|query| query.split('&').any(|pair| pair == "format=json")) {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::SERVER, SERVER_NAME)
+            .header(http::header::CACHE_CONTROL, "no-store")
+            .body(full_body(snapshot.render_json()));
+    }
+    match snapshot.render_html() {
+        Ok(html) => Response::builder()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(http::header::SERVER, SERVER_NAME)
+            .header(http::header::CACHE_CONTROL, "no-store")
+            .body(full_body(html)),
+        Err(e) => {
+            warn!("render stats error: {}", e);
+            Ok(build_500_resp())
         }
-    } else {
-        Ok(referer.to_string())
     }
 }
 
@@ -194,8 +355,91 @@ async fn serve_metrics(prom_registry: &Registry, can_gzip: bool) -> Result<Respo
     }
 }
 
+/// 在root下查找url_path对应的文件
+enum Lookup {
+    File(PathBuf, Metadata),
+    /// 目录的url不以/结尾，并且需要列出目录
+    RedirectToDir,
+    AutoIndex(PathBuf),
+    NotFound,
+}
+
+async fn lookup(site: &StaticLocationConfig, url_path: &str) -> Lookup {
+    let base = format!("{}{}", site.root, url_path);
+    if !url_path.ends_with('/') {
+        match metadata(&base).await {
+            Ok(meta) if meta.is_file() => return Lookup::File(PathBuf::from(base), meta),
+            Ok(meta) if meta.is_dir() => {
+                if let Some((path, meta)) = find_index(site, &format!("{}/", base)).await {
+                    return Lookup::File(path, meta);
+                }
+                // 目录下没有index，重定向到以/结尾的路径，使目录列表中的相对链接正确
+                if site.autoindex_config.enabled_for(&format!("{}/", url_path)) {
+                    return Lookup::RedirectToDir;
+                }
+            }
+            _ => {}
+        }
+        return Lookup::NotFound;
+    }
+    if let Some((path, meta)) = find_index(site, &base).await {
+        return Lookup::File(path, meta);
+    }
+    if site.autoindex_config.enabled_for(url_path) {
+        return Lookup::AutoIndex(PathBuf::from(base));
+    }
+    Lookup::NotFound
+}
+
+async fn find_index(site: &StaticLocationConfig, dir: &str) -> Option<(PathBuf, Metadata)> {
+    for index in &site.index {
+        let path = PathBuf::from(format!("{}{}", dir, index));
+        if let Ok(meta) = metadata(&path).await {
+            if meta.is_file() {
+                return Some((path, meta));
+            }
+        }
+    }
+    None
+}
+
+/// 文件不存在时依次尝试try_files
+async fn find_try_files(site: &StaticLocationConfig) -> Option<(PathBuf, Metadata)> {
+    for try_file in &site.try_files {
+        let path = PathBuf::from(format!("{}{}", site.root, try_file));
+        if let Ok(meta) = metadata(&path).await {
+            if meta.is_file() {
+                return Some((path, meta));
+            }
+        }
+    }
+    None
+}
+
+/// 单页应用的history API回退，只针对浏览器的导航请求：Accept包含text/html，并且最后一段路径没有扩展名
+async fn find_spa_fallback(
+    site: &StaticLocationConfig, req: &Request<impl Body>, url_path: &str,
+) -> Option<(PathBuf, Metadata)> {
+    let spa_fallback = site.spa_fallback.as_ref()?;
+    let accept_html = req
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    let has_extension = url_path.rsplit('/').next().unwrap_or_default().contains('.');
+    if !accept_html || has_extension {
+        return None;
+    }
+    let path = PathBuf::from(format!("{}{}", site.root, spa_fallback));
+    match metadata(&path).await {
+        Ok(meta) if meta.is_file() => Some((path, meta)),
+        _ => None,
+    }
+}
+
 async fn serve_path(
-    web_content_path: &String, url_path: &str, req: &Request<impl Body>, can_gzip: bool, need_body: bool,
+    site: &StaticLocationConfig, url_path: &str, req: &Request<impl Body>, accept_encoding: &AcceptEncoding,
+    need_body: bool, config: &Config,
 ) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
     if String::from(url_path).contains("/..") {
         return not_found();
@@ -204,41 +448,31 @@ async fn serve_path(
     if String::from(url_path).starts_with("/.git/") {
         return not_found();
     }
-    let path = if String::from(url_path).ends_with('/') {
-        format!("{}{}index.html", web_content_path, url_path)
-    } else {
-        format!("{}{}", web_content_path, url_path)
-    };
-    let mut path = PathBuf::from(path);
-    let meta = match metadata(&path).await {
-        Ok(meta) => {
-            if meta.is_file() {
-                meta
-            } else {
-                path = PathBuf::from(format!("{}{}/index.html", web_content_path, url_path));
-                match metadata(&path).await {
-                    Ok(m) => m,
-                    Err(_) => return not_found(),
-                }
-            }
+    let (path, meta) = match lookup(site, url_path).await {
+        Lookup::File(path, meta) => (path, meta),
+        Lookup::RedirectToDir => return redirect_to_dir(req),
+        Lookup::AutoIndex(dir) => {
+            let dir_path = format!("{}{}", site.location.trim_end_matches('/'), url_path);
+            return autoindex::serve_dir(&dir, &dir_path, req.uri().query(), &site.autoindex_config, need_body).await;
         }
-        Err(_) => {
+        Lookup::NotFound => {
             if url_path == "/favicon.ico" {
                 return serve_favico(req, need_body);
             };
-            return not_found();
+            match find_try_files(site).await {
+                Some((path, meta)) => (path, meta),
+                None => match find_spa_fallback(site, req, url_path).await {
+                    Some((path, meta)) => (path, meta),
+                    None => return not_found(),
+                },
+            }
         }
     };
-
-    let last_modified: SystemTime = match meta.modified() {
-        Ok(time) => time,
-        Err(_) => return not_found(),
-    };
-    let file_len = meta.len();
-    let file_etag = cal_file_etag(last_modified, file_len);
-    if let Some(response) = return_304_if_not_modified(req, &file_etag, last_modified) {
-        return response;
-    }
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let cache_headers = site.cache_headers(url_path, &file_name, SystemTime::now());
     let mime_type = from_path(&path).first_or_octet_stream();
     let content_type = mime_type.as_ref();
     let content_type = if !content_type.to_ascii_lowercase().contains("charset") && !content_type.contains("wasm") {
@@ -246,95 +480,108 @@ async fn serve_path(
     } else {
         String::from(content_type)
     };
+    let content_type = content_type.as_str();
+    // 选择预压缩文件、动态压缩或者原文件，之后的ETag、Last-Modified和Range都针对选中的表示
+    let compressible = encoding::is_compressible(content_type, &config.compressible_types);
+    let Representation {
+        path,
+        meta,
+        encoding,
+        on_the_fly,
+    } = encoding::select(path, meta, accept_encoding, compressible).await;
+
+    let last_modified: SystemTime = match meta.modified() {
+        Ok(time) => time,
+        Err(_) => return not_found(),
+    };
+    let file_len = meta.len();
+    let file_etag = encoding.etag(&cal_file_etag(last_modified, file_len));
+    // 304需要带上200响应中的ETag、Last-Modified、Cache-Control、Expires和Vary
+    if cache_rule::is_not_modified(req.headers(), &file_etag, last_modified) {
+        return with_cache_headers(not_modified_builder(last_modified, &file_etag), &cache_headers)
+            .header(http::header::VARY, "Accept-Encoding")
+            .body(empty_body());
+    }
     let mut builder = Response::builder()
-        .header(http::header::CONTENT_TYPE, content_type.as_str())
+        .header(http::header::CONTENT_TYPE, content_type)
         .header(http::header::LAST_MODIFIED, fmt_http_date(last_modified))
-        .header(http::header::ETAG, file_etag)
-        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::ETAG, file_etag.as_str())
+        .header(http::header::VARY, "Accept-Encoding")
         .header(http::header::SERVER, SERVER_NAME);
-
-    // 判断客户端是否支持gzip
-    let content_type = content_type.as_str();
-    let need_gzip = can_gzip
-        && (content_type.starts_with("text/html")
-            || content_type.starts_with("text/css")
-            || content_type.starts_with("application/javascript")
-            || content_type.starts_with("application/json")
-            || content_type.starts_with("text/xml")
-            || content_type.starts_with("application/xml")
-            || content_type.starts_with("text/plain")
-            || content_type.starts_with("text/markdown"));
-    if need_gzip {
-        builder = builder.header(CONTENT_ENCODING, GZIP)
+    builder = with_cache_headers(builder, &cache_headers);
+    if encoding != Encoding::Identity {
+        builder = builder.header(CONTENT_ENCODING, encoding.as_str());
+    }
+    // 动态压缩时长度未知，不支持Range
+    let range_outcome = if on_the_fly {
+        RangeOutcome::Full
+    } else {
+        builder = builder.header(http::header::ACCEPT_RANGES, "bytes");
+        range::evaluate(req.headers(), &file_etag, last_modified, file_len)
     };
     if !need_body {
         return builder.body(empty_body());
     }
 
-    let mut file = match File::open(path).await {
-        Ok(file) => file,
-        Err(_) => return not_found(),
-    };
-
-    let (start, end, builder) = match parse_range(req.headers().get(http::header::RANGE), file_len, builder) {
-        Ok((start, end, builder)) => (start, end, builder),
-        Err(e) => {
+    let ranges = match range_outcome {
+        RangeOutcome::Full => {
+            return match File::open(path).await {
+                Ok(file) => final_build(on_the_fly.then_some(encoding), file, builder),
+                Err(_) => not_found(),
+            };
+        }
+        RangeOutcome::Unsatisfiable => {
             return Response::builder()
                 .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(http::header::CONTENT_RANGE, format!("bytes */{}", file_len))
                 .header(http::header::SERVER, SERVER_NAME)
-                .body(full_body(e.to_string()));
+                .body(empty_body());
         }
+        RangeOutcome::Partial(ranges) => ranges,
     };
-
-    if start != 0 {
+    let builder = builder.status(StatusCode::PARTIAL_CONTENT);
+    if let [(start, end)] = ranges[..] {
+        let mut file = match File::open(path).await {
+            Ok(file) => file,
+            Err(_) => return not_found(),
+        };
         if let Err(e) = file.seek(io::SeekFrom::Start(start)).await {
             warn!("seek file error: {}", e);
             return Ok(build_500_resp());
         };
-    }
-    if end != file_len - 1 {
-        final_build(need_gzip, file.take(end - start + 1), builder)
+        let builder = builder
+            .header(http::header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file_len))
+            .header(http::header::CONTENT_LENGTH, end - start + 1);
+        final_build(None, file.take(end - start + 1), builder)
     } else {
-        final_build(need_gzip, file, builder)
-    }
-}
-
-fn return_304_if_not_modified(
-    req: &Request<impl Body>, file_etag: &str, last_modified: SystemTime,
-) -> Option<Result<Response<BoxBody<Bytes, io::Error>>, Error>> {
-    match req.headers().get(http::header::IF_NONE_MATCH) {
-        Some(if_none_match) => {
-            // use if-none-match
-            let if_none_match = if_none_match.to_str().ok()?;
-            if if_none_match == file_etag {
-                Some(not_modified(last_modified, file_etag))
-            } else {
-                None
-            }
-        }
-        None => {
-            // fallback to if-modified-since
-            let if_modified_since = req.headers().get(http::header::IF_MODIFIED_SINCE)?;
-            let if_modified_since = if_modified_since.to_str().ok()?;
-            if if_modified_since == fmt_http_date(last_modified.to_owned()).as_str() {
-                Some(not_modified(last_modified, file_etag))
-            } else {
-                None
-            }
+        let multipart = range::multipart(path, &ranges, content_type, file_len);
+        let mut builder = builder.header(http::header::CONTENT_LENGTH, multipart.content_length);
+        if let Some(headers) = builder.headers_mut() {
+            headers.insert(http::header::CONTENT_TYPE, HeaderValue::from_str(&multipart.content_type)?);
         }
+        builder.body(multipart.body)
     }
 }
 
-fn not_modified(last_modified: SystemTime, file_etag: &str) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+fn not_modified_builder(last_modified: SystemTime, file_etag: &str) -> Builder {
     Response::builder()
         .status(StatusCode::NOT_MODIFIED)
         .header(http::header::ETAG, file_etag)
         .header(http::header::LAST_MODIFIED, fmt_http_date(last_modified))
         .header(http::header::SERVER, SERVER_NAME)
-        .body(empty_body())
 }
 
-fn cal_file_etag(last_modified: SystemTime, file_len: u64) -> String {
+fn with_cache_headers(mut builder: Builder, cache_headers: &Option<CacheHeaders>) -> Builder {
+    if let Some(cache_headers) = cache_headers {
+        builder = builder.header(http::header::CACHE_CONTROL, cache_headers.cache_control);
+        if let Some(expires) = cache_headers.expires {
+            builder = builder.header(http::header::EXPIRES, fmt_http_date(expires));
+        }
+    }
+    builder
+}
+
+pub(crate) fn cal_file_etag(last_modified: SystemTime, file_len: u64) -> String {
     let last_modified_secs = last_modified
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap_or_default()
@@ -342,102 +589,24 @@ fn cal_file_etag(last_modified: SystemTime, file_len: u64) -> String {
     format!("\"{:x}-{:x}\"", last_modified_secs, file_len)
 }
 
+/// encode不为None时动态压缩
 fn final_build<T>(
-    need_gzip: bool, async_read: T, builder: Builder,
+    encode: Option<Encoding>, async_read: T, builder: Builder,
 ) -> Result<Response<BoxBody<Bytes, io::Error>>, Error>
 where
     T: AsyncRead + Send + Sync + Unpin + 'static,
 {
-    let stream_body = StreamBody::new(build_reader_stream(async_read, need_gzip).map_ok(Frame::data));
+    let dyn_async_read = encode.unwrap_or(Encoding::Identity).encode(async_read);
+    let stream_body = StreamBody::new(ReaderStream::new(dyn_async_read).map_ok(Frame::data));
     builder.body(stream_body.boxed())
 }
 
-fn build_reader_stream<T>(
-    async_read: T, need_gzip: bool,
-) -> ReaderStream<pin::Pin<Box<dyn AsyncRead + Send + Sync + Unpin>>>
-where
-    T: AsyncRead + Send + Sync + Unpin + 'static,
-{
-    let dyn_async_read: pin::Pin<Box<dyn AsyncRead + Send + Sync + Unpin>> = if need_gzip {
-        let buf_stream = BufReader::new(async_read);
-        let encoder = GzipEncoder::with_quality(buf_stream, async_compression::Level::Best);
-        Box::pin(encoder)
-    } else {
-        Box::pin(async_read)
-    };
-    ReaderStream::new(dyn_async_read)
-}
-
-fn parse_range(
-    range_header: Option<&HeaderValue>, file_size: u64, mut builder: Builder,
-) -> io::Result<(u64, u64, Builder)> {
-    let mut start = 0;
-    let mut end = file_size - 1;
-    if let Some(range_value) = range_header {
-        let range_value = range_value
-            .to_str()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        // 仅支持单个range，不支持多个range
-        let re = Regex::new(r"^bytes=(\d*)-(\d*)$").map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        // 使用正则表达式匹配字符串并捕获组
-        let caps = re.captures(range_value);
-        match caps {
-            Some(caps) => {
-                // 捕获组可以通过索引访问，索引0是整个匹配，索引1开始是捕获的组
-                let left = caps.get(1).map_or("", |m| m.as_str());
-                let right = caps.get(2).map_or("", |m| m.as_str());
-
-                if left.is_empty() {
-                    if !right.is_empty() {
-                        // suffix-length格式，例如bytes=-100
-                        let right = right
-                            .parse::<u64>()
-                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-                        if right < file_size {
-                            start = file_size - right;
-                        } else {
-                            let msg = "suffix-length bigger than file size";
-                            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
-                        }
-                    }
-                } else {
-                    // start-end格式，例如bytes=100-200或bytes=100-
-                    start = left
-                        .parse::<u64>()
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-                    if !right.is_empty() {
-                        end = right
-                            .parse::<u64>()
-                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-                    }
-                }
-                builder = builder
-                    .header(http::header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file_size))
-                    .status(http::StatusCode::PARTIAL_CONTENT);
-            }
-            None => {
-                let msg = "invalid range";
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
-            }
-        }
-    }
-    if end < start {
-        let msg = "end must be greater than or equal to start";
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
-    }
-    if end >= file_size {
-        let msg = "end must be less than file length";
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
-    }
-    Ok((start, end, builder))
-}
-
 fn serve_favico(req: &Request<impl Body>, need_body: bool) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
     let last_modified = BOOTUP_TIME.to_owned();
     let file_len = FAV_ICO.len() as u64;
     let file_etag = cal_file_etag(last_modified, file_len);
-    if let Some(response) = return_304_if_not_modified(req, &file_etag, last_modified) {
-        return response;
+    if cache_rule::is_not_modified(req.headers(), &file_etag, last_modified) {
+        return not_modified_builder(last_modified, &file_etag).body(empty_body());
     }
     Response::builder()
         .status(StatusCode::OK)
@@ -459,6 +628,18 @@ fn serve_ip(client_socket_addr: SocketAddr) -> Result<Response<BoxBody<Bytes, io
         .body(full_body(client_socket_addr.ip().to_canonical().to_string()))
 }
 
+fn redirect_to_dir(req: &Request<impl Body>) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    let location = match req.uri().query() {
+        Some(query) => format!("{}/?{}", req.uri().path(), query),
+        None => format!("{}/", req.uri().path()),
+    };
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(http::header::SERVER, SERVER_NAME)
+        .header(http::header::LOCATION, location)
+        .body(empty_body())
+}
+
 fn not_found() -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -499,53 +680,71 @@ fn decompress_string(input: &[u8]) -> io::Result<String> {
 mod tests {
     use super::*;
     #[test]
-    fn test_extract_domain_from_url() {
-        assert_eq!(
-            extract_search_engine_from_referer("https://www.baidu.com/").unwrap_or("default".to_string()),
-            "baidu"
-        );
-        assert_eq!(
-            extract_search_engine_from_referer("https://www.baidu.com").unwrap_or("default".to_string()),
-            "baidu"
-        );
-        assert_eq!(
-            extract_search_engine_from_referer("http://www.baidu.com/").unwrap_or("default".to_string()),
-            "baidu"
-        );
-        assert_eq!(
-            extract_search_engine_from_referer("sadasdasdsadas").unwrap_or("default".to_string()),
-            "sadasdasdsadas"
-        );
-        assert_eq!(
-            extract_search_engine_from_referer("http://huaiwen.com/baidu.com/bing.com")
-                .unwrap_or("default".to_string()),
-            "huaiwen.com"
+    fn test_gzip_compress_string() -> io::Result<()> {
+        let original_string = "Hello, Rust! This is a test string for Gzip compression.";
+        let compressed_data = compress_string(original_string)?;
+        let decompressed_string = decompress_string(&compressed_data)?;
+
+        assert_eq!(original_string, decompressed_string);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_static_location() -> Result<(), crate::DynError> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path();
+        tokio::fs::create_dir_all(root.join("assets")).await?;
+        tokio::fs::write(root.join("index.html"), b"<html></html>").await?;
+        tokio::fs::write(root.join("assets/app.js"), b"1").await?;
+        let yaml = format!(
+            "location: /app/\nroot: {}/\nindex: [main.html, index.html]\ntry_files: [/index.html]\n",
+            root.display()
         );
+        let mut site: StaticLocationConfig = serde_yaml::from_str(&yaml)?;
+        site.normalize()?;
+
+        let path = |lookup: Lookup| match lookup {
+            Lookup::File(path, _) => path.strip_prefix(root).map(PathBuf::from).unwrap_or_default(),
+            _ => PathBuf::new(),
+        };
         assert_eq!(
-            extract_search_engine_from_referer("http://huaiwenbaidu.com/baidu.com/bing.com")
-                .unwrap_or("default".to_string()),
-            "baidu"
+            path(lookup(&site, site.relative_path("/app/assets/app.js").unwrap_or_default()).await),
+            PathBuf::from("assets/app.js")
         );
         assert_eq!(
-            extract_search_engine_from_referer("https://www.google.com.hk/").unwrap_or("default".to_string()),
-            "google"
+            path(lookup(&site, site.relative_path("/app/").unwrap_or_default()).await),
+            PathBuf::from("index.html")
         );
         assert_eq!(
-            extract_search_engine_from_referer(
-                "https://www.bing.com/search?q=google%E6%9C%8D%E5%8A%A1%E4%B8%8B%E8%BD%BD+anzhuo11&qs=ds&form=QBRE"
-            )
-            .unwrap_or("default".to_string()),
-            "bing"
+            path(lookup(&site, site.relative_path("/app").unwrap_or_default()).await),
+            PathBuf::from("index.html")
         );
+        assert!(matches!(lookup(&site, "/assets/").await, Lookup::NotFound));
+        // 单页应用的路由不存在对应的文件，回退到try_files
+        assert!(matches!(lookup(&site, "/users/1").await, Lookup::NotFound));
+        assert_eq!(find_try_files(&site).await.map(|(path, _)| path), Some(root.join("index.html")));
+        Ok(())
     }
 
-    #[test]
-    fn test_gzip_compress_string() -> io::Result<()> {
-        let original_string = "Hello, Rust! This is a test string for Gzip compression.";
-        let compressed_data = compress_string(original_string)?;
-        let decompressed_string = decompress_string(&compressed_data)?;
+    #[tokio::test]
+    async fn test_static_location_sibling_prefix() -> Result<(), crate::DynError> {
+        let tmp = tempfile::tempdir()?;
+        let base = tmp.path();
+        tokio::fs::create_dir_all(base.join("docs")).await?;
+        tokio::fs::create_dir_all(base.join("docs-private")).await?;
+        tokio::fs::write(base.join("docs/index.html"), b"<html></html>").await?;
+        tokio::fs::write(base.join("docs-private/secret"), b"secret").await?;
+        let yaml = format!("location: /docs\nroot: {}\n", base.join("docs").display());
+        let mut site: StaticLocationConfig = serde_yaml::from_str(&yaml)?;
+        site.normalize()?;
 
-        assert_eq!(original_string, decompressed_string);
+        // /docs-private不在/docs下，不能拼接成root-private/secret
+        assert_eq!(site.relative_path("/docs-private/secret"), None);
+        assert_eq!(site.relative_path("/docsx"), None);
+        assert_eq!(site.relative_path("/docs"), Some(""));
+        assert_eq!(site.relative_path("/docs/index.html"), Some("/index.html"));
+        let relative_path = site.relative_path("/docs/index.html").unwrap_or_default();
+        assert!(matches!(lookup(&site, relative_path).await, Lookup::File(..)));
         Ok(())
     }
 }
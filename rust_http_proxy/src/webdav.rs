@@ -0,0 +1,548 @@
+//! 静态目录下的上传：PUT、DELETE、MKCOL，以及WebDAV class 1的子集（PROPFIND、COPY、MOVE），可以作为网络磁盘挂载
+//!
+//! 只处理--webdav-prefix下的写请求和PROPFIND，需要--webdav-user鉴权；GET和HEAD仍由静态文件服务处理
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use httpdate::fmt_http_date;
+use hyper::body::{Body, Bytes};
+use log::{info, warn};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::Rng;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use crate::{
+    ip_x::SocketAddrFormat,
+    proxy::{build_authenticate_resp, check_auth, empty_body, full_body},
+    web_func::{cal_file_etag, SERVER_NAME},
+};
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, PROPFIND, COPY, MOVE";
+
+/// href中每一段需要编码的字符
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+/// 上传过程中的临时文件后缀，PROPFIND不列出
+const TEMP_SUFFIX: &str = ".upload";
+
+pub(crate) struct WebDavConfig {
+    /// 以/开头和结尾
+    prefix: String,
+    root: PathBuf,
+    /// 与--user相同，key为Authorization header的值
+    users: HashMap<String, String>,
+    max_size: u64,
+}
+
+impl WebDavConfig {
+    pub(crate) fn new(
+        prefix: String, root: &str, users: HashMap<String, String>, max_size: u64,
+    ) -> Result<Self, String> {
+        if !prefix.starts_with('/') || prefix.split('/').any(|segment| segment == "..") {
+            return Err(format!("webdav prefix should start with '/' and not contain '..': {}", prefix));
+        }
+        if users.is_empty() {
+            return Err("--webdav-user is required when --webdav-prefix is set".to_owned());
+        }
+        let prefix = if prefix.ends_with('/') {
+            prefix
+        } else {
+            format!("{}/", prefix)
+        };
+        Ok(WebDavConfig {
+            prefix,
+            root: PathBuf::from(root),
+            users,
+            max_size,
+        })
+    }
+
+    pub(crate) fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// 是否由WebDAV处理。path为percent decode之后的路径
+    pub(crate) fn matches(&self, method: &Method, path: &str) -> bool {
+        let in_prefix = path.starts_with(&self.prefix) || path == self.prefix.trim_end_matches('/');
+        in_prefix
+            && (matches!(*method, Method::PUT | Method::DELETE | Method::OPTIONS)
+                || ["MKCOL", "PROPFIND", "COPY", "MOVE"].contains(&method.as_str()))
+    }
+
+    pub(crate) async fn handle<B>(
+        &self, req: Request<B>, client_socket_addr: SocketAddr, path: &str,
+    ) -> Response<BoxBody<Bytes, io::Error>>
+    where
+        B: Body<Data = Bytes> + Unpin,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let (username, authed) = check_auth(&self.users, &req, &client_socket_addr, http::header::AUTHORIZATION);
+        if !authed {
+            return build_authenticate_resp(false);
+        }
+        info!("webdav {} {} by {} from [{}]", req.method(), path, username, SocketAddrFormat(&client_socket_addr));
+        let method = req.method().clone();
+        let result = match self.resolve(path).await {
+            Err(status) => Ok(status_response(status)),
+            Ok(target) => match method.as_str() {
+                "OPTIONS" => Ok(options()),
+                "PUT" => self.put(req, &target).await,
+                "DELETE" => self.delete(path, &target).await,
+                "MKCOL" => mkcol(req, &target).await,
+                "PROPFIND" => self.propfind(&req, path, &target).await,
+                "COPY" | "MOVE" => self.copy_or_move(&req, path, &target, method == "MOVE").await,
+                _ => Ok(status_response(StatusCode::METHOD_NOT_ALLOWED)),
+            },
+        };
+        match result {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("webdav {} {} failed: {}", method, path, e);
+                status_response(match e.kind() {
+                    ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                    ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                })
+            }
+        }
+    }
+
+    /// 请求路径对应的文件路径，不允许..，也不允许通过符号链接离开root
+    async fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let mut target = self.root.clone();
+        for segment in path.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+            if segment == ".." || segment.contains(['\\', '\0']) {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            target.push(segment);
+        }
+        let root = fs::canonicalize(&self.root)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        // 找到最近的已存在的祖先，检查其真实路径
+        for ancestor in target.ancestors() {
+            if let Ok(real) = fs::canonicalize(ancestor).await {
+                return if real.starts_with(&root) {
+                    Ok(target)
+                } else {
+                    Err(StatusCode::FORBIDDEN)
+                };
+            }
+        }
+        Err(StatusCode::FORBIDDEN)
+    }
+
+    fn is_prefix_root(&self, path: &str) -> bool {
+        path.trim_end_matches('/') == self.prefix.trim_end_matches('/')
+    }
+
+    /// 先写入同目录下的临时文件，完成后rename，读者不会看到写了一半的文件
+    async fn put<B>(&self, req: Request<B>, target: &Path) -> io::Result<Response<BoxBody<Bytes, io::Error>>>
+    where
+        B: Body<Data = Bytes> + Unpin,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let content_length = req
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if content_length.is_some_and(|len| len > self.max_size) {
+            return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        let (Some(parent), Some(file_name)) = (target.parent(), target.file_name()) else {
+            return Ok(status_response(StatusCode::FORBIDDEN));
+        };
+        if !is_dir(parent).await {
+            return Ok(status_response(StatusCode::CONFLICT));
+        }
+        let existed = match fs::metadata(target).await {
+            Ok(meta) if meta.is_dir() => return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED)),
+            Ok(_) => true,
+            Err(_) => false,
+        };
+        let temp = temp_path(parent, file_name.to_string_lossy().as_ref());
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&temp).await?;
+        let mut body = req.into_body();
+        let mut written = 0u64;
+        let result = async {
+            while let Some(frame) = body.frame().await {
+                let frame = frame.map_err(|e| io::Error::other(e.into()))?;
+                if let Ok(data) = frame.into_data() {
+                    written += data.len() as u64;
+                    if written > self.max_size {
+                        return Ok(false);
+                    }
+                    file.write_all(&data).await?;
+                }
+            }
+            file.sync_all().await?;
+            Ok::<_, io::Error>(true)
+        }
+        .await;
+        drop(file);
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = fs::remove_file(&temp).await;
+                return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&temp).await;
+                return Err(e);
+            }
+        }
+        if let Err(e) = fs::rename(&temp, target).await {
+            let _ = fs::remove_file(&temp).await;
+            return Err(e);
+        }
+        Ok(status_response(if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        }))
+    }
+
+    async fn delete(&self, path: &str, target: &Path) -> io::Result<Response<BoxBody<Bytes, io::Error>>> {
+        if self.is_prefix_root(path) {
+            return Ok(status_response(StatusCode::FORBIDDEN));
+        }
+        let meta = fs::symlink_metadata(target).await?;
+        if meta.is_dir() {
+            fs::remove_dir_all(target).await?;
+        } else {
+            fs::remove_file(target).await?;
+        }
+        Ok(status_response(StatusCode::NO_CONTENT))
+    }
+
+    async fn propfind<B>(
+        &self, req: &Request<B>, path: &str, target: &Path,
+    ) -> io::Result<Response<BoxBody<Bytes, io::Error>>> {
+        let depth = match req.headers().get("Depth").and_then(|value| value.to_str().ok()) {
+            Some("0") => 0,
+            Some("1") => 1,
+            // 不支持infinity，RFC 4918 9.1
+            _ => return Ok(xml_response(
+                StatusCode::FORBIDDEN,
+                r#"<?xml version="1.0" encoding="utf-8"?><D:error xmlns:D="DAV:"><D:propfind-finite-depth/></D:error>"#
+                    .to_owned(),
+            )),
+        };
+        let meta = fs::metadata(target).await?;
+        let mut href = path.to_owned();
+        if meta.is_dir() && !href.ends_with('/') {
+            href.push('/');
+        }
+        let mut body = String::from(r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">"#);
+        write_response(&mut body, &href, &meta);
+        if meta.is_dir() && depth == 1 {
+            let mut entries = fs::read_dir(target).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && name.ends_with(TEMP_SUFFIX) {
+                    continue;
+                }
+                let Ok(meta) = fs::metadata(entry.path()).await else {
+                    continue;
+                };
+                let mut child = format!("{}{}", href, name);
+                if meta.is_dir() {
+                    child.push('/');
+                }
+                write_response(&mut body, &child, &meta);
+            }
+        }
+        body.push_str("</D:multistatus>");
+        Ok(xml_response(StatusCode::MULTI_STATUS, body))
+    }
+
+    async fn copy_or_move<B>(
+        &self, req: &Request<B>, path: &str, source: &Path, is_move: bool,
+    ) -> io::Result<Response<BoxBody<Bytes, io::Error>>> {
+        if is_move && self.is_prefix_root(path) {
+            return Ok(status_response(StatusCode::FORBIDDEN));
+        }
+        let Some(destination) = req
+            .headers()
+            .get("Destination")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<http::Uri>().ok())
+        else {
+            return Ok(status_response(StatusCode::BAD_REQUEST));
+        };
+        let destination = percent_decode_str(destination.path()).decode_utf8_lossy().into_owned();
+        if !self.matches(&Method::PUT, &destination) || self.is_prefix_root(&destination) {
+            return Ok(status_response(StatusCode::FORBIDDEN));
+        }
+        let dest = match self.resolve(&destination).await {
+            Ok(dest) => dest,
+            Err(status) => return Ok(status_response(status)),
+        };
+        let source_meta = fs::metadata(source).await?;
+        // 不能复制或移动到自身及其子目录
+        if dest.starts_with(source) {
+            return Ok(status_response(StatusCode::FORBIDDEN));
+        }
+        let overwrite = !req
+            .headers()
+            .get("Overwrite")
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"F"));
+        let existed = match fs::symlink_metadata(&dest).await {
+            Ok(_) if !overwrite => return Ok(status_response(StatusCode::PRECONDITION_FAILED)),
+            Ok(meta) => {
+                if meta.is_dir() {
+                    fs::remove_dir_all(&dest).await?;
+                } else {
+                    fs::remove_file(&dest).await?;
+                }
+                true
+            }
+            Err(_) => false,
+        };
+        if !parent_is_dir(&dest).await {
+            return Ok(status_response(StatusCode::CONFLICT));
+        }
+        if is_move {
+            fs::rename(source, &dest).await?;
+        } else if source_meta.is_dir() {
+            let shallow = req.headers().get("Depth").is_some_and(|value| value == "0");
+            copy_dir(source, &dest, shallow).await?;
+        } else {
+            copy_file(source, &dest).await?;
+        }
+        Ok(status_response(if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        }))
+    }
+}
+
+async fn mkcol<B>(req: Request<B>, target: &Path) -> io::Result<Response<BoxBody<Bytes, io::Error>>>
+where
+    B: Body,
+{
+    // MKCOL不支持请求体，RFC 4918 9.3
+    if req.body().size_hint().lower() > 0 {
+        return Ok(status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    if fs::symlink_metadata(target).await.is_ok() {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    if !parent_is_dir(target).await {
+        return Ok(status_response(StatusCode::CONFLICT));
+    }
+    fs::create_dir(target).await?;
+    Ok(status_response(StatusCode::CREATED))
+}
+
+fn options() -> Response<BoxBody<Bytes, io::Error>> {
+    let mut res = status_response(StatusCode::OK);
+    res.headers_mut().insert("DAV", HeaderValue::from_static("1"));
+    res.headers_mut()
+        .insert(http::header::ALLOW, HeaderValue::from_static(ALLOW));
+    res
+}
+
+async fn is_dir(path: &Path) -> bool {
+    fs::metadata(path).await.is_ok_and(|meta| meta.is_dir())
+}
+
+async fn parent_is_dir(path: &Path) -> bool {
+    match path.parent() {
+        Some(parent) => is_dir(parent).await,
+        None => false,
+    }
+}
+
+fn temp_path(parent: &Path, file_name: &str) -> PathBuf {
+    parent.join(format!(".{}.{:016x}{}", file_name, rand::rng().random::<u64>(), TEMP_SUFFIX))
+}
+
+/// 同样先复制到临时文件再rename
+async fn copy_file(source: &Path, dest: &Path) -> io::Result<()> {
+    let (Some(parent), Some(file_name)) = (dest.parent(), dest.file_name()) else {
+        return Err(io::Error::new(ErrorKind::PermissionDenied, "invalid destination"));
+    };
+    let temp = temp_path(parent, file_name.to_string_lossy().as_ref());
+    if let Err(e) = fs::copy(source, &temp).await {
+        let _ = fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&temp, dest).await {
+        let _ = fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// shallow为true时（Depth: 0）只创建目录本身
+async fn copy_dir(source: &Path, dest: &Path, shallow: bool) -> io::Result<()> {
+    let mut pending = vec![(source.to_path_buf(), dest.to_path_buf())];
+    while let Some((source, dest)) = pending.pop() {
+        fs::create_dir(&dest).await?;
+        if shallow {
+            break;
+        }
+        let mut entries = fs::read_dir(&source).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            let target = dest.join(entry.file_name());
+            if file_type.is_dir() {
+                pending.push((entry.path(), target));
+            } else if file_type.is_file() {
+                fs::copy(entry.path(), target).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_response(body: &mut String, href: &str, meta: &std::fs::Metadata) {
+    let href = href
+        .split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    let name = href
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+        .unwrap_or_default();
+    let _ = write!(body, "<D:response><D:href>{}</D:href><D:propstat><D:prop>", escape(&href));
+    let _ = write!(body, "<D:displayname>{}</D:displayname>", escape(&name));
+    if let Ok(modified) = meta.modified() {
+        let _ = write!(body, "<D:getlastmodified>{}</D:getlastmodified>", fmt_http_date(modified));
+        if meta.is_file() {
+            let _ = write!(body, "<D:getetag>{}</D:getetag>", escape(&cal_file_etag(modified, meta.len())));
+        }
+    }
+    if meta.is_dir() {
+        body.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let _ = write!(
+            body,
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>{}</D:getcontenttype>",
+            meta.len(),
+            mime_guess::from_path(&name).first_or_octet_stream()
+        );
+    }
+    body.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>");
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_response(status: StatusCode, body: String) -> Response<BoxBody<Bytes, io::Error>> {
+    let mut res = Response::new(full_body(body));
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(http::header::CONTENT_TYPE, HeaderValue::from_static("application/xml; charset=utf-8"));
+    res.headers_mut()
+        .insert(http::header::SERVER, HeaderValue::from_static(SERVER_NAME));
+    res
+}
+
+fn status_response(status: StatusCode) -> Response<BoxBody<Bytes, io::Error>> {
+    let mut res = Response::new(empty_body());
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(http::header::SERVER, HeaderValue::from_static(SERVER_NAME));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose, Engine};
+    use http_body_util::Full;
+
+    fn request(method: &str, path: &str, body: &'static str) -> Result<Request<Full<Bytes>>, http::Error> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(http::header::AUTHORIZATION, format!("Basic {}", general_purpose::STANDARD.encode("dav:secret")))
+            .body(Full::new(Bytes::from_static(body.as_bytes())))
+    }
+
+    #[tokio::test]
+    async fn test_webdav() -> Result<(), crate::DynError> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path();
+        fs::create_dir_all(root.join("dav")).await?;
+        let users =
+            HashMap::from([(format!("Basic {}", general_purpose::STANDARD.encode("dav:secret")), "dav".to_owned())]);
+        let webdav = WebDavConfig::new("/dav".to_owned(), &root.to_string_lossy(), users, 8)?;
+        let client: SocketAddr = ([127, 0, 0, 1], 10000).into();
+        let call = |req: Request<Full<Bytes>>| {
+            let path = req.uri().path().to_owned();
+            let webdav = &webdav;
+            async move { webdav.handle(req, client, &path).await.status() }
+        };
+
+        assert!(webdav.matches(&Method::PUT, "/dav/a.txt"));
+        assert!(!webdav.matches(&Method::GET, "/dav/a.txt"));
+        assert!(!webdav.matches(&Method::PUT, "/other/a.txt"));
+
+        let unauthed = Request::builder()
+            .method("PUT")
+            .uri("/dav/a.txt")
+            .body(Full::new(Bytes::new()))?;
+        assert_eq!(call(unauthed).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(request("PUT", "/dav/a.txt", "hello")?).await, StatusCode::CREATED);
+        assert_eq!(fs::read(root.join("dav/a.txt")).await?, b"hello");
+        assert_eq!(call(request("PUT", "/dav/a.txt", "world")?).await, StatusCode::NO_CONTENT);
+        assert_eq!(call(request("PUT", "/dav/big.txt", "123456789")?).await, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(fs::metadata(root.join("dav/big.txt")).await.is_err());
+        assert_eq!(call(request("PUT", "/dav/x/a.txt", "hello")?).await, StatusCode::CONFLICT);
+        assert_eq!(call(request("PUT", "/dav/../a.txt", "hello")?).await, StatusCode::FORBIDDEN);
+
+        assert_eq!(call(request("MKCOL", "/dav/dir", "")?).await, StatusCode::CREATED);
+        assert_eq!(call(request("MKCOL", "/dav/dir", "")?).await, StatusCode::METHOD_NOT_ALLOWED);
+        let copy = Request::builder()
+            .method("COPY")
+            .uri("/dav/a.txt")
+            .header(http::header::AUTHORIZATION, format!("Basic {}", general_purpose::STANDARD.encode("dav:secret")))
+            .header("Destination", "http://localhost/dav/dir/b%20c.txt")
+            .body(Full::new(Bytes::new()))?;
+        assert_eq!(call(copy).await, StatusCode::CREATED);
+        assert_eq!(fs::read(root.join("dav/dir/b c.txt")).await?, b"world");
+
+        let mut propfind = request("PROPFIND", "/dav/dir", "")?;
+        propfind.headers_mut().insert("Depth", HeaderValue::from_static("1"));
+        let res = webdav.handle(propfind, client, "/dav/dir").await;
+        assert_eq!(res.status(), StatusCode::MULTI_STATUS);
+        let body = String::from_utf8(res.into_body().collect().await?.to_bytes().to_vec())?;
+        assert!(body.contains("<D:href>/dav/dir/</D:href>"));
+        assert!(body.contains("<D:href>/dav/dir/b%20c.txt</D:href>"));
+        assert_eq!(call(request("PROPFIND", "/dav/dir", "")?).await, StatusCode::FORBIDDEN);
+
+        let mut move_req = request("MOVE", "/dav/dir", "")?;
+        move_req
+            .headers_mut()
+            .insert("Destination", HeaderValue::from_static("/dav/moved"));
+        assert_eq!(call(move_req).await, StatusCode::CREATED);
+        assert!(is_dir(&root.join("dav/moved")).await);
+        assert_eq!(call(request("DELETE", "/dav/moved", "")?).await, StatusCode::NO_CONTENT);
+        assert_eq!(call(request("DELETE", "/dav/moved", "")?).await, StatusCode::NOT_FOUND);
+        assert_eq!(call(request("DELETE", "/dav/", "")?).await, StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}